@@ -0,0 +1,498 @@
+//! Derive macros for `shunlib`, re-exported by it with the `derive` feature.
+
+use std::collections::HashSet;
+
+use proc_macro::TokenStream;
+use proc_macro2::TokenStream as TokenStream2;
+use quote::quote;
+use syn::spanned::Spanned;
+use syn::{parse_macro_input, Data, DeriveInput, Error, Expr, Fields, Ident, LitStr, Path, Result, Type};
+
+/// Implement `DynamicQueryParameters` for a struct with named fields, like `new_query_type!` does
+/// but without declaring the struct, so it can have its own derives, attributes and visibility.
+///
+/// Every field is a bind parameter named after the field unless `#[query(...)]` says otherwise.
+/// `Option` fields are only present if they are `Some`, other fields are always present.
+/// - `render`: a parameter replaced by its value when rendering, like `=>`.
+/// - `patch`: a `Patch` bind parameter, like `~>`.
+/// - `list`: a bind parameter of a `Vec<T>` expanded by the `in` helper, like `*>`.
+/// - `filter = "column op"`: a filter field, like `%>`, e.g. `#[query(filter = "weight le")]`.
+/// - `flatten`: a nested query type whose parameters are merged, like `&>`.
+/// - `prefix = "query"`: with `flatten`, the parameters of the nested query type are prefixed, e.g.
+///   `:query.name`, like `&> query: FooQuery as "query"`.
+/// - `skip`: not a parameter.
+/// - `rename = "name"`: the parameter is `:name` instead of the name of the field.
+/// - `default = expr`: the value of an `Option` bind or render parameter when it is `None`. For a
+///   bind parameter, `&expr` has to be a constant, e.g. a literal.
+///
+/// Two fields with the same parameter, e.g. because of `rename`, fail to compile, and so do
+/// parameters that a nested query type has in common with the struct, as long as the nested type
+/// declares `DynamicQueryParameters::PARAMS` and the struct has no type parameters. Otherwise they
+/// fail rendering.
+///
+/// The generated code refers to `::shunlib`, which `#[query(crate = "path")]` on the struct
+/// changes, e.g. when `shunlib` is renamed.
+///
+/// ```ignore
+/// #[derive(Default, DynamicQuery)]
+/// struct DogQuery<'q> {
+///     #[query(rename = "q_name")]
+///     name: Option<&'q str>,
+///     #[query(filter = "weight le")]
+///     weight_upper: Option<f32>,
+///     #[query(render, default = 10)]
+///     limit: Option<u32>,
+/// }
+/// ```
+#[proc_macro_derive(DynamicQuery, attributes(query))]
+pub fn derive_dynamic_query(input: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(input as DeriveInput);
+    expand(&input)
+        .unwrap_or_else(Error::into_compile_error)
+        .into()
+}
+
+/// How a field takes part in a query.
+enum Kind {
+    /// A bind parameter, the default.
+    Bind,
+    /// A parameter substituted into the SQL when rendering.
+    Render,
+    /// A bind parameter with a list of values.
+    List,
+    /// A `Patch` bind parameter.
+    Patch,
+    /// A filter field with the column and the operator of its condition.
+    Filter(String, Op),
+    /// A nested query type whose parameters are merged.
+    Flatten,
+    Skip,
+}
+
+#[derive(Clone, Copy)]
+enum Op {
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+    Like,
+    Contains,
+    Between,
+    In,
+    IsNull,
+}
+
+impl Op {
+    fn parse(s: &str) -> Option<Self> {
+        let op = match s {
+            "eq" => Op::Eq,
+            "ne" => Op::Ne,
+            "lt" => Op::Lt,
+            "le" => Op::Le,
+            "gt" => Op::Gt,
+            "ge" => Op::Ge,
+            "like" => Op::Like,
+            "contains" => Op::Contains,
+            "between" => Op::Between,
+            "in" => Op::In,
+            "is_null" => Op::IsNull,
+            _ => return None,
+        };
+        Some(op)
+    }
+}
+
+struct Field {
+    ident: Ident,
+    kind: Kind,
+    /// The parameter name, e.g. `:name`.
+    param: String,
+    /// Whether the field is an `Option` and hence only present if it is `Some`.
+    optional: bool,
+    default: Option<Expr>,
+    /// The prefix of the parameters of a nested query type.
+    prefix: Option<LitStr>,
+    ty: Type,
+}
+
+impl Field {
+    fn parse(field: &syn::Field) -> Result<Self> {
+        let ident = field
+            .ident
+            .clone()
+            .ok_or_else(|| Error::new(field.span(), "DynamicQuery needs named fields"))?;
+        let mut kind = None;
+        let mut name = ident.to_string();
+        let mut default = None;
+        let mut prefix = None;
+        for attr in field.attrs.iter().filter(|a| a.path().is_ident("query")) {
+            attr.parse_nested_meta(|meta| {
+                let found = if meta.path.is_ident("bind") {
+                    Kind::Bind
+                } else if meta.path.is_ident("render") {
+                    Kind::Render
+                } else if meta.path.is_ident("list") {
+                    Kind::List
+                } else if meta.path.is_ident("patch") {
+                    Kind::Patch
+                } else if meta.path.is_ident("flatten") {
+                    Kind::Flatten
+                } else if meta.path.is_ident("skip") {
+                    Kind::Skip
+                } else if meta.path.is_ident("filter") {
+                    let filter: LitStr = meta.value()?.parse()?;
+                    let value = filter.value();
+                    let mut parts = value.split_whitespace();
+                    match (parts.next(), parts.next().and_then(Op::parse), parts.next()) {
+                        (Some(column), Some(op), None) => Kind::Filter(column.to_string(), op),
+                        _ => {
+                            return Err(Error::new(
+                                filter.span(),
+                                "expected a column and an operator, e.g. `weight le`; the operators are \
+                                eq, ne, lt, le, gt, ge, like, contains, between, in and is_null",
+                            ))
+                        }
+                    }
+                } else if meta.path.is_ident("rename") {
+                    let rename: LitStr = meta.value()?.parse()?;
+                    name = rename.value();
+                    return Ok(());
+                } else if meta.path.is_ident("default") {
+                    default = Some(meta.value()?.parse::<Expr>()?);
+                    return Ok(());
+                } else if meta.path.is_ident("prefix") {
+                    prefix = Some(meta.value()?.parse::<LitStr>()?);
+                    return Ok(());
+                } else {
+                    return Err(meta.error("unsupported query attribute"));
+                };
+                if kind.is_some() {
+                    return Err(meta.error(
+                        "only one of bind, render, list, patch, filter, flatten and skip can be given",
+                    ));
+                }
+                kind = Some(found);
+                Ok(())
+            })?;
+        }
+        let kind = kind.unwrap_or(Kind::Bind);
+        let optional = is_option(&field.ty);
+        if default.is_some() && !(optional && matches!(kind, Kind::Bind | Kind::Render)) {
+            return Err(Error::new(
+                field.span(),
+                "a default is only supported for `Option` fields that are bind or render parameters",
+            ));
+        }
+        if let (Some(prefix), false) = (&prefix, matches!(kind, Kind::Flatten)) {
+            return Err(Error::new(prefix.span(), "a prefix is only supported for flatten fields"));
+        }
+        if matches!(kind, Kind::Patch) && optional {
+            return Err(Error::new(field.ty.span(), "a patch field must be a `Patch`, not an `Option`"));
+        }
+        Ok(Field {
+            ident,
+            kind,
+            param: format!(":{}", name),
+            optional,
+            default,
+            prefix,
+            ty: field.ty.clone(),
+        })
+    }
+
+    /// An `Option` of a reference to the value of the field.
+    fn value(&self) -> TokenStream2 {
+        let ident = &self.ident;
+        if self.optional {
+            quote!(self.#ident.as_ref())
+        } else {
+            quote!(::std::option::Option::Some(&self.#ident))
+        }
+    }
+}
+
+fn is_option(ty: &Type) -> bool {
+    match ty {
+        Type::Path(p) => p
+            .path
+            .segments
+            .last()
+            .map(|s| s.ident == "Option")
+            .unwrap_or(false),
+        _ => false,
+    }
+}
+
+/// `T` of `Option<T>`, or `ty` itself if it is not an `Option`.
+fn option_inner(ty: &Type) -> &Type {
+    if let Type::Path(p) = ty {
+        if let Some(syn::PathArguments::AngleBracketed(args)) = p.path.segments.last().map(|s| &s.arguments) {
+            if let (true, Some(syn::GenericArgument::Type(inner))) = (is_option(ty), args.args.first()) {
+                return inner;
+            }
+        }
+    }
+    ty
+}
+
+/// The path of the `shunlib` crate, `::shunlib` unless given with `#[query(crate = "...")]`.
+fn crate_path(input: &DeriveInput) -> Result<Path> {
+    let mut path = syn::parse_quote!(::shunlib);
+    for attr in input.attrs.iter().filter(|a| a.path().is_ident("query")) {
+        attr.parse_nested_meta(|meta| {
+            if meta.path.is_ident("crate") {
+                let s: LitStr = meta.value()?.parse()?;
+                path = s.parse()?;
+                Ok(())
+            } else {
+                Err(meta.error("unsupported query attribute"))
+            }
+        })?;
+    }
+    Ok(path)
+}
+
+/// Parameters of the fields of the same struct and prefixes of its nested query types must be
+/// unique. Parameters that a nested query type duplicates are only known to `check_params`, which
+/// is evaluated by the generated code.
+fn check_duplicates(fields: &[Field]) -> Result<()> {
+    let mut params = HashSet::new();
+    let mut prefixes = HashSet::new();
+    for field in fields {
+        let unique = match (&field.kind, &field.prefix) {
+            (Kind::Skip, _) | (Kind::Flatten, None) => true,
+            (Kind::Flatten, Some(prefix)) => prefixes.insert(prefix.value()),
+            _ => params.insert(field.param.as_str()),
+        };
+        if !unique {
+            let message = match &field.prefix {
+                Some(prefix) => format!("duplicate prefix `{}`", prefix.value()),
+                None => format!("duplicate parameter `{}`", field.param),
+            };
+            return Err(Error::new(field.ident.span(), message));
+        }
+    }
+    Ok(())
+}
+
+fn expand(input: &DeriveInput) -> Result<TokenStream2> {
+    let fields = match &input.data {
+        Data::Struct(s) => match &s.fields {
+            Fields::Named(fields) => fields.named.iter().map(Field::parse).collect::<Result<Vec<_>>>()?,
+            _ => return Err(Error::new(input.span(), "DynamicQuery needs named fields")),
+        },
+        _ => return Err(Error::new(input.span(), "DynamicQuery can only be derived for structs")),
+    };
+    check_duplicates(&fields)?;
+    let krate = crate_path(input)?;
+    let sql = quote!(#krate::dynamic_sql);
+
+    let mut render = vec![];
+    let mut execution = vec![];
+    let mut lists = vec![];
+    let mut filters = vec![];
+    // after the other parameters, so that `extend_render` sees all parameters the nested ones may
+    // duplicate
+    let mut nested_render = vec![];
+    let mut names = vec![];
+    let mut nested = vec![];
+    for field in &fields {
+        let ident = &field.ident;
+        let param = &field.param;
+        let value = field.value();
+        let segment = |v: TokenStream2| quote!(#sql::ToSqlSegment::to_sql_segment(#v).unwrap_or_default());
+        match &field.kind {
+            Kind::Bind | Kind::Render => {
+                let present = segment(quote!(value));
+                render.push(match &field.default {
+                    Some(default) => {
+                        let default = segment(quote!(&(#default)));
+                        quote! {
+                            match #value {
+                                ::std::option::Option::Some(value) => v.insert(#param, #present),
+                                ::std::option::Option::None => v.insert(#param, #default),
+                            };
+                        }
+                    }
+                    None => quote! {
+                        if let ::std::option::Option::Some(value) = #value {
+                            v.insert(#param, #present);
+                        }
+                    },
+                });
+                if let Kind::Bind = field.kind {
+                    execution.push(match &field.default {
+                        // the default is promoted to a constant, so it has to be one, e.g. a literal
+                        Some(default) => quote! {
+                            match #value {
+                                ::std::option::Option::Some(value) => v.push((#param, value as &dyn #sql::ToSql)),
+                                ::std::option::Option::None => v.push((#param, &#default as &dyn #sql::ToSql)),
+                            }
+                        },
+                        None => quote! {
+                            if let ::std::option::Option::Some(value) = #value {
+                                v.push((#param, value as &dyn #sql::ToSql));
+                            }
+                        },
+                    });
+                }
+            }
+            Kind::List => {
+                render.push(quote! {
+                    if let ::std::option::Option::Some(value) = #value {
+                        v.insert(#param, value.len().to_string());
+                    }
+                });
+                lists.push(quote! {
+                    if let ::std::option::Option::Some(value) = #value {
+                        v.push((#param, value.iter().map(|it| it as &dyn #sql::ToSql).collect()));
+                    }
+                });
+            }
+            Kind::Patch => {
+                let present = segment(quote!(&self.#ident));
+                render.push(quote! {
+                    if !self.#ident.is_absent() {
+                        v.insert(#param, #present);
+                    }
+                });
+                execution.push(quote! {
+                    if !self.#ident.is_absent() {
+                        v.push((#param, &self.#ident as &dyn #sql::ToSql));
+                    }
+                });
+            }
+            Kind::Filter(column, op) => {
+                let filter = match op {
+                    Op::Eq => quote!(compare(#column, "=", #param, value)),
+                    Op::Ne => quote!(compare(#column, "<>", #param, value)),
+                    Op::Lt => quote!(compare(#column, "<", #param, value)),
+                    Op::Le => quote!(compare(#column, "<=", #param, value)),
+                    Op::Gt => quote!(compare(#column, ">", #param, value)),
+                    Op::Ge => quote!(compare(#column, ">=", #param, value)),
+                    Op::Like => quote!(compare(#column, "LIKE", #param, value)),
+                    Op::Contains => quote!(contains(#column, #param, value)),
+                    Op::Between => quote!(between(#column, #param, value)),
+                    Op::In => quote!(in_list(#column, #param, value)),
+                    Op::IsNull => quote!(is_null(#column, #param, value)),
+                };
+                filters.push(quote! {
+                    if let ::std::option::Option::Some(value) = #value {
+                        filters.push(#sql::Filter::#filter);
+                    }
+                });
+            }
+            Kind::Flatten => {
+                let ty = option_inner(&field.ty);
+                let prefix = match &field.prefix {
+                    Some(prefix) => quote!(::std::option::Option::Some(#prefix)),
+                    None => quote!(::std::option::Option::None),
+                };
+                nested.push(quote!((#prefix, <#ty as #sql::DynamicQueryParameters>::PARAMS)));
+                let (prefix_render, prefix_params) = match &field.prefix {
+                    Some(prefix) => {
+                        // every field has its own `static` with the prefixed names
+                        let prefix = quote! {
+                            static PREFIX: #sql::Prefix = #sql::Prefix::new(#prefix);
+                            let params = <#ty as #sql::DynamicQueryParameters>::PARAMS;
+                        };
+                        (
+                            quote!(let nested = { #prefix PREFIX.render(params, nested) };),
+                            quote!(let nested = { #prefix PREFIX.params(params, nested) };),
+                        )
+                    }
+                    None => (quote!(), quote!()),
+                };
+                nested_render.push(quote! {
+                    if let ::std::option::Option::Some(value) = #value {
+                        let nested = #sql::DynamicQueryParameters::for_render(value);
+                        #prefix_render
+                        #sql::extend_render(&mut v, nested);
+                    }
+                });
+                execution.push(quote! {
+                    if let ::std::option::Option::Some(value) = #value {
+                        let nested = #sql::DynamicQueryParameters::for_execution(value);
+                        #prefix_params
+                        v.extend(nested);
+                    }
+                });
+                lists.push(quote! {
+                    if let ::std::option::Option::Some(value) = #value {
+                        let nested = #sql::DynamicQueryParameters::for_lists(value);
+                        #prefix_params
+                        v.extend(nested);
+                    }
+                });
+            }
+            Kind::Skip => {}
+        }
+        if !matches!(field.kind, Kind::Flatten | Kind::Skip) {
+            names.push(param);
+        }
+    }
+
+    let filters = quote! {
+        {
+            #[allow(unused_mut)]
+            let mut filters = ::std::vec::Vec::<#sql::Filter<'_>>::new();
+            #(#filters)*
+            filters
+        }
+    };
+    let name = &input.ident;
+    let (impl_generics, ty_generics, where_clause) = input.generics.split_for_impl();
+    // the lifetimes are elided, but type parameters would have to be known
+    let check = if input.generics.type_params().next().is_none() && input.generics.const_params().next().is_none() {
+        quote!(const _: () = #sql::check_params(<#name as #sql::DynamicQueryParameters>::PARAMS);)
+    } else {
+        quote!()
+    };
+    Ok(quote! {
+        impl #impl_generics #sql::DynamicQueryParameters for #name #ty_generics #where_clause {
+            const PARAMS: &'static #sql::ParamNames = &#sql::ParamNames {
+                names: &[#(#names),*],
+                nested: &[#(#nested),*],
+            };
+
+            fn for_render(&self) -> ::std::collections::HashMap<&'static str, ::std::string::String> {
+                let mut v = ::std::collections::HashMap::new();
+                let filters = #filters;
+                if !filters.is_empty() {
+                    let sql = filters.iter().map(|f| f.sql()).collect::<::std::vec::Vec<_>>();
+                    v.insert(#sql::FILTERS, sql.join(" AND "));
+                }
+                v.extend(filters.iter().map(|f| (f.param, f.shape())));
+                #(#render)*
+                #(#nested_render)*
+                v
+            }
+
+            fn for_execution(&self) -> ::std::vec::Vec<#sql::DynamicParam<'_>> {
+                let mut v = ::std::vec::Vec::<#sql::DynamicParam<'_>>::new();
+                #(#execution)*
+                for filter in #filters {
+                    if let (param, #sql::FilterBind::One(value)) = (filter.param, filter.bind()) {
+                        v.push((param, value));
+                    }
+                }
+                v
+            }
+
+            fn for_lists(&self) -> ::std::vec::Vec<#sql::DynamicListParam<'_>> {
+                let mut v = ::std::vec::Vec::<#sql::DynamicListParam<'_>>::new();
+                #(#lists)*
+                for filter in #filters {
+                    if let (param, #sql::FilterBind::List(values)) = (filter.param, filter.bind()) {
+                        v.push((param, values));
+                    }
+                }
+                v
+            }
+        }
+
+        #check
+    })
+}
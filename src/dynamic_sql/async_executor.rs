@@ -0,0 +1,157 @@
+use std::future::Future;
+use std::panic::{catch_unwind, AssertUnwindSafe};
+use std::sync::mpsc::{channel, Sender};
+use std::thread;
+
+use rusqlite::Row;
+use tokio::sync::oneshot;
+
+use crate::dynamic_sql::executor::{DynamicSqlExecutor, Repository};
+use crate::dynamic_sql::query::DynamicQueryParameters;
+use crate::dynamic_sql::template::SqlTemplate;
+use crate::error::{Error, Result};
+
+/// Async counterpart of [DynamicSqlExecutor]. Because the work is done on another thread, the
+/// template, the query parameters and the row mapper are all taken by value and must be `'static`,
+/// i.e. query types should own their values (`String` instead of `&str`).
+pub trait AsyncDynamicSqlExecutor {
+    /// Perform a query and resolve to the rows mapped by `f`.
+    fn query<S, P, F, T>(&self, template: S, params: P, f: F) -> impl Future<Output = Result<Vec<T>>> + Send
+        where
+            S: SqlTemplate + Send + 'static,
+            P: DynamicQueryParameters + Send + 'static,
+            F: FnMut(&Row<'_>) -> rusqlite::Result<T> + Send + 'static,
+            T: Send + 'static;
+
+    /// Execute a query and resolve to the number of rows that are affected.
+    fn execute<S, P>(&self, template: S, params: P) -> impl Future<Output = Result<usize>> + Send
+        where
+            S: SqlTemplate + Send + 'static,
+            P: DynamicQueryParameters + Send + 'static;
+}
+
+type Job = Box<dyn FnOnce(&mut Repository<'static>) + Send>;
+
+/// An [AsyncDynamicSqlExecutor] that moves a [Repository] onto a dedicated worker thread. Requests
+/// are queued to the worker and run one at a time, so neither rendering nor SQLite calls ever block
+/// the async runtime. The worker stops once the [AsyncRepository] is dropped.
+pub struct AsyncRepository {
+    sender: Sender<Job>,
+}
+
+impl AsyncRepository {
+    pub fn new(mut repository: Repository<'static>) -> Self {
+        let (sender, receiver) = channel::<Job>();
+        thread::Builder::new()
+            .name("dynamic-sql-worker".to_string())
+            .spawn(move || {
+                for job in receiver {
+                    // A panicking job drops its result sender, which is reported to the caller as
+                    // [Error::WorkerStopped]; the worker itself keeps serving other requests.
+                    if catch_unwind(AssertUnwindSafe(|| job(&mut repository))).is_err() {
+                        log::warn!("a dynamic SQL job panicked on the worker thread");
+                    }
+                }
+            })
+            .expect("failed to spawn dynamic SQL worker thread");
+        AsyncRepository { sender }
+    }
+
+    /// Run `f` with the underlying [Repository] on the worker thread, e.g. to run several
+    /// statements inside [Repository::transaction].
+    pub fn call<F, R>(&self, f: F) -> impl Future<Output = Result<R>> + Send
+        where
+            F: FnOnce(&mut Repository<'static>) -> Result<R> + Send + 'static,
+            R: Send + 'static,
+    {
+        let (tx, rx) = oneshot::channel();
+        let sent = self
+            .sender
+            .send(Box::new(move |repository| {
+                let _ = tx.send(f(repository));
+            }))
+            .is_ok();
+        async move {
+            if !sent {
+                return Err(Error::WorkerStopped);
+            }
+            rx.await.map_err(|_| Error::WorkerStopped)?
+        }
+    }
+}
+
+impl AsyncDynamicSqlExecutor for AsyncRepository {
+    fn query<S, P, F, T>(&self, template: S, params: P, f: F) -> impl Future<Output = Result<Vec<T>>> + Send
+        where
+            S: SqlTemplate + Send + 'static,
+            P: DynamicQueryParameters + Send + 'static,
+            F: FnMut(&Row<'_>) -> rusqlite::Result<T> + Send + 'static,
+            T: Send + 'static,
+    {
+        self.call(move |repository| repository.query(&template, params, f))
+    }
+
+    fn execute<S, P>(&self, template: S, params: P) -> impl Future<Output = Result<usize>> + Send
+        where
+            S: SqlTemplate + Send + 'static,
+            P: DynamicQueryParameters + Send + 'static,
+    {
+        self.call(move |repository| repository.execute(&template, params))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::iter::FromIterator;
+
+    use rusqlite::ToSql;
+
+    use crate::dynamic_sql::executor::dog::*;
+    use crate::dynamic_sql::{DynamicParam, ToSqlSegment};
+    use crate::new_query_type;
+
+    use super::*;
+
+    new_query_type!(
+        (OwnedDogQuery,
+        -> q_name: String, q_color: String,)
+    );
+
+    #[tokio::test]
+    async fn test_async_repository() {
+        let repo = AsyncRepository::new(memory_repository());
+        let affected = repo
+            .call(|r| {
+                r.transaction(|tx| {
+                    tx.execute(&Q_DOGS_INSERT, dog_insert("Jeff"))?;
+                    tx.execute(&Q_DOGS_INSERT, dog_insert("Bob"))
+                })
+            })
+            .await
+            .unwrap();
+        assert_eq!(1, affected);
+
+        let query = OwnedDogQuery {
+            q_name: Some("Je".to_string()),
+            ..Default::default()
+        };
+        let dogs = repo
+            .query(Q_DOGS_SELECT, query, |row| row.get::<_, String>("name"))
+            .await
+            .unwrap();
+        assert_eq!(vec!["Jeff"], dogs);
+    }
+
+    #[tokio::test]
+    async fn test_panicking_job() {
+        let repo = AsyncRepository::new(memory_repository());
+        let result = repo.call(|_| -> Result<()> { panic!("boom") }).await;
+        assert!(matches!(result, Err(Error::WorkerStopped)));
+        // the worker is still alive
+        let dogs = repo
+            .query(Q_DOGS_SELECT, OwnedDogQuery::default(), |row| row.get::<_, String>("name"))
+            .await
+            .unwrap();
+        assert!(dogs.is_empty());
+    }
+}
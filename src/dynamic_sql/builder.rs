@@ -0,0 +1,383 @@
+use std::collections::{HashMap, HashSet};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use handlebars::HelperDef;
+use rusqlite::{Connection, OpenFlags, ToSql};
+
+use crate::dynamic_sql::engine::Engine;
+use crate::dynamic_sql::executor::Repository;
+use crate::dynamic_sql::interceptor::Interceptor;
+use crate::dynamic_sql::loader::{partial_references, SqlFiles};
+use crate::dynamic_sql::policy::RowErrorPolicy;
+use crate::dynamic_sql::template::SqlTemplate;
+use crate::error::{Error, Result};
+
+enum Source {
+    File(PathBuf),
+    Memory,
+    Connection(Connection),
+}
+
+/// Builds a [Repository] step by step. Unlike [Repository::new] it can open databases with
+/// [OpenFlags] (e.g. read-only), in memory or by URI filename (`file:data.db?mode=ro`), use an
+/// existing [Connection], set pragmas and register partials and helpers.
+///
+/// Everything is validated by [RepositoryBuilder::build], so that a [Repository] is only returned
+/// if the names of all templates and partials are unique, all templates compile, all partials they
+/// include exist, and all pragmas and init statements have been applied successfully.
+pub struct RepositoryBuilder<'reg> {
+    source: Source,
+    flags: OpenFlags,
+    pragmas: Vec<(String, Box<dyn ToSql>)>,
+    busy_timeout: Option<Duration>,
+    templates: Vec<(String, String)>,
+    partials: Vec<(String, String)>,
+    /// Where each template and partial was added, in order, to report duplicate names.
+    origins: Vec<(String, String)>,
+    helpers: Vec<(String, Box<dyn HelperDef + Send + Sync + 'reg>)>,
+    init: Vec<String>,
+    interceptors: Vec<Box<dyn Interceptor>>,
+    #[cfg(feature = "tracing")]
+    trace_values: bool,
+    /// Only set on the connection if configured, so that rusqlite's default applies otherwise.
+    cache_capacity: Option<usize>,
+    row_error_policy: RowErrorPolicy,
+}
+
+impl<'reg> RepositoryBuilder<'reg> {
+    fn with_source(source: Source) -> Self {
+        RepositoryBuilder {
+            source,
+            flags: OpenFlags::default(),
+            pragmas: vec![],
+            busy_timeout: None,
+            templates: vec![],
+            partials: vec![],
+            origins: vec![],
+            helpers: vec![],
+            init: vec![],
+            interceptors: vec![],
+            #[cfg(feature = "tracing")]
+            trace_values: false,
+            cache_capacity: None,
+            row_error_policy: RowErrorPolicy::default(),
+        }
+    }
+
+    /// Open the database at `path`, which may also be a URI filename.
+    pub fn file<P: AsRef<Path> + ?Sized>(path: &P) -> Self {
+        Self::with_source(Source::File(path.as_ref().to_path_buf()))
+    }
+
+    /// Open a new in-memory database.
+    pub fn memory() -> Self {
+        Self::with_source(Source::Memory)
+    }
+
+    /// Use an already opened connection. [RepositoryBuilder::flags] has no effect in this case.
+    pub fn connection(conn: Connection) -> Self {
+        Self::with_source(Source::Connection(conn))
+    }
+
+    /// Open the database with `flags` instead of [OpenFlags::default].
+    pub fn flags(mut self, flags: OpenFlags) -> Self {
+        self.flags = flags;
+        self
+    }
+
+    /// Open the database read-only.
+    pub fn read_only(self) -> Self {
+        self.flags(
+            OpenFlags::SQLITE_OPEN_READ_ONLY
+                | OpenFlags::SQLITE_OPEN_URI
+                | OpenFlags::SQLITE_OPEN_NO_MUTEX,
+        )
+    }
+
+    /// Set a pragma, e.g. `pragma("journal_mode", "WAL")` or `pragma("foreign_keys", true)`.
+    /// Pragmas are set in the order they are added, before the init statements are run.
+    pub fn pragma<V: ToSql + 'static>(mut self, name: &str, value: V) -> Self {
+        self.pragmas.push((name.to_string(), Box::new(value)));
+        self
+    }
+
+    /// How long to wait for a locked database before failing with `SQLITE_BUSY`.
+    pub fn busy_timeout(mut self, timeout: Duration) -> Self {
+        self.busy_timeout = Some(timeout);
+        self
+    }
+
+    pub fn template<S: SqlTemplate + ?Sized>(mut self, template: &S) -> Self {
+        self.templates
+            .push((template.name().to_string(), template.sql().to_string()));
+        self.origins
+            .push((template.name().to_string(), "a template".to_string()));
+        self
+    }
+
+    pub fn templates<'a, T, I>(mut self, templates: &'a T) -> Self
+        where
+            &'a T: IntoIterator<Item = &'a I>,
+            I: SqlTemplate + 'a,
+    {
+        for t in templates {
+            self = self.template(t);
+        }
+        self
+    }
+
+    /// Register a partial that templates can include with `{{> name}}`.
+    pub fn partial<S: SqlTemplate + ?Sized>(mut self, partial: &S) -> Self {
+        self.partials
+            .push((partial.name().to_string(), partial.sql().to_string()));
+        self.origins
+            .push((partial.name().to_string(), "a partial".to_string()));
+        self
+    }
+
+    /// Register all templates and partials of `files`.
+    pub fn files(mut self, files: &SqlFiles) -> Self {
+        self.templates.extend_from_slice(files.templates());
+        self.partials.extend_from_slice(files.partials());
+        for (name, _) in files.templates().iter().chain(files.partials()) {
+            self.origins.push((name.clone(), files.origin(name).to_string()));
+        }
+        self
+    }
+
+    /// Register a Handlebars helper in addition to the [sql_helpers](crate::dynamic_sql::sql_helpers).
+    pub fn helper<N: Into<String>>(mut self, name: N, helper: Box<dyn HelperDef + Send + Sync + 'reg>) -> Self {
+        self.helpers.push((name.into(), helper));
+        self
+    }
+
+    /// Run `sql`, which may contain several statements, once the connection has been opened.
+    pub fn init<S: Into<String>>(mut self, sql: S) -> Self {
+        self.init.push(sql.into());
+        self
+    }
+
+    /// See [Repository::with_interceptor].
+    pub fn interceptor<I: Interceptor + 'static>(mut self, interceptor: I) -> Self {
+        self.interceptors.push(Box::new(interceptor));
+        self
+    }
+
+    /// Record the values of bound parameters in the spans of the `tracing` feature, not only their
+    /// names. Disabled by default because values may contain personal data.
+    #[cfg(feature = "tracing")]
+    pub fn trace_param_values(mut self, enabled: bool) -> Self {
+        self.trace_values = enabled;
+        self
+    }
+
+    /// See [Repository::with_cache].
+    pub fn cache_capacity(mut self, capacity: usize) -> Self {
+        self.cache_capacity = Some(capacity);
+        self
+    }
+
+    /// See [Repository::with_row_error_policy].
+    pub fn row_error_policy(mut self, policy: RowErrorPolicy) -> Self {
+        self.row_error_policy = policy;
+        self
+    }
+
+    /// Validate the configuration, open the connection and set it up.
+    pub fn build(self) -> Result<Repository<'reg>> {
+        for (name, _) in &self.pragmas {
+            if name.is_empty() || !name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_') {
+                return Err(Error::InvalidConfiguration(format!("invalid pragma name `{}`", name)));
+            }
+        }
+
+        // templates and partials share one namespace, whether added directly or from files
+        let mut origins = HashMap::new();
+        for (name, origin) in &self.origins {
+            if let Some(first) = origins.insert(name.as_str(), origin) {
+                return Err(Error::DuplicateTemplate {
+                    name: name.clone(),
+                    first: first.clone(),
+                    second: origin.clone(),
+                });
+            }
+        }
+
+        let names = self
+            .templates
+            .iter()
+            .chain(self.partials.iter())
+            .map(|(name, _)| name.as_str())
+            .collect::<HashSet<_>>();
+        for (template, sql) in self.templates.iter().chain(self.partials.iter()) {
+            if let Some(partial) = partial_references(sql).into_iter().find(|p| !names.contains(p)) {
+                return Err(Error::MissingPartial {
+                    template: template.clone(),
+                    partial: partial.to_string(),
+                });
+            }
+        }
+
+        let mut engine = Engine::new(&self.templates)?;
+        for (name, helper) in self.helpers {
+            engine.register_helper(&name, helper);
+        }
+        for (name, sql) in &self.partials {
+            engine.register_partial(name, sql)?;
+        }
+        for interceptor in self.interceptors {
+            engine.add_interceptor(interceptor);
+        }
+        #[cfg(feature = "tracing")]
+        engine.set_trace_values(self.trace_values);
+        engine.set_cache_capacity(self.cache_capacity.unwrap_or(0));
+        engine.set_row_error_policy(self.row_error_policy);
+
+        let conn = match self.source {
+            Source::File(path) => Connection::open_with_flags(path, self.flags)?,
+            Source::Memory => Connection::open_in_memory_with_flags(self.flags)?,
+            Source::Connection(conn) => conn,
+        };
+        if let Some(timeout) = self.busy_timeout {
+            conn.busy_timeout(timeout)?;
+        }
+        for (name, value) in &self.pragmas {
+            conn.pragma_update(None, name, value)?;
+        }
+        for sql in &self.init {
+            conn.execute_batch(sql)?;
+        }
+        if let Some(capacity) = self.cache_capacity {
+            conn.set_prepared_statement_cache_capacity(capacity);
+        }
+        Ok(Repository::from_parts(conn, engine))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use handlebars::{Context, Handlebars, Helper, HelperResult, Output, RenderContext};
+
+    use crate::dynamic_sql::executor::dog::*;
+    use crate::dynamic_sql::DynamicSqlExecutor;
+
+    use super::*;
+
+    const Q_DOG_COLUMNS: (&str, &str) = ("dog_columns", "name, color, weight");
+    const Q_DOG_NAMES: (&str, &str) = (
+        "dog_names",
+        "SELECT {{> dog_columns}} FROM {{table}} ORDER BY name",
+    );
+
+    fn table(
+        _: &Helper<'_, '_>,
+        _: &Handlebars<'_>,
+        _: &Context,
+        _: &mut RenderContext<'_, '_>,
+        out: &mut dyn Output,
+    ) -> HelperResult {
+        out.write("dogs")?;
+        Ok(())
+    }
+
+    fn name(row: &rusqlite::Row<'_>) -> rusqlite::Result<String> {
+        row.get("name")
+    }
+
+    #[test]
+    fn test_build_memory_repository() {
+        let repo = RepositoryBuilder::memory()
+            .pragma("foreign_keys", true)
+            .busy_timeout(Duration::from_millis(100))
+            .templates(&[Q_DOGS_INSERT, Q_DOG_NAMES])
+            .partial(&Q_DOG_COLUMNS)
+            .helper("table", Box::new(table))
+            .init(DDL)
+            .init("INSERT INTO dogs(name, weight) VALUES('Bob', 1)")
+            .build()
+            .unwrap();
+        let foreign_keys: bool = repo
+            .conn
+            .pragma_query_value(None, "foreign_keys", |row| row.get(0))
+            .unwrap();
+        assert!(foreign_keys);
+
+        repo.execute(&Q_DOGS_INSERT, dog_insert("Jeff")).unwrap();
+        let names = repo.query(&Q_DOG_NAMES, DogQuery::default(), name).unwrap();
+        assert_eq!(vec!["Bob", "Jeff"], names);
+    }
+
+    #[test]
+    fn test_read_only() {
+        let path = std::env::temp_dir().join(format!("shunlib_builder_{}.db", std::process::id()));
+        RepositoryBuilder::file(&path)
+            .pragma("journal_mode", "WAL")
+            .init(DDL)
+            .build()
+            .unwrap();
+
+        let repo = RepositoryBuilder::file(&path)
+            .read_only()
+            .templates(&[Q_DOGS_INSERT, Q_DOGS_SELECT, Q_DOGS_WHERE])
+            .build()
+            .unwrap();
+        assert!(repo.execute(&Q_DOGS_INSERT, dog_insert("Jeff")).is_err());
+        assert!(repo.query(&Q_DOGS_SELECT, DogQuery::default(), name).unwrap().is_empty());
+        drop(repo);
+        for suffix in &["", "-wal", "-shm"] {
+            let _ = std::fs::remove_file(format!("{}{}", path.display(), suffix));
+        }
+    }
+
+    #[test]
+    fn test_invalid_configuration() {
+        let result = RepositoryBuilder::memory().pragma("foreign_keys = 1; --", true).build();
+        assert!(matches!(result, Err(Error::InvalidConfiguration(_))));
+
+        let result = RepositoryBuilder::memory().template(&("broken", "{{#if}}")).build();
+        assert!(matches!(result, Err(Error::TemplateError(_))));
+
+        let result = RepositoryBuilder::connection(Connection::open_in_memory().unwrap())
+            .init("INSERT INTO nothing VALUES(1)")
+            .build();
+        assert!(matches!(result, Err(Error::DatabaseError(_))));
+    }
+
+    #[test]
+    fn test_partial_block_fallback() {
+        const Q_NAMES: (&str, &str) = ("names", "SELECT {{#> columns}}name{{/columns}} FROM dogs");
+        let repo = RepositoryBuilder::memory()
+            .template(&Q_NAMES)
+            .init(DDL)
+            .init("INSERT INTO dogs(name, weight) VALUES('Bob', 1)")
+            .build()
+            .unwrap();
+        assert_eq!(vec!["Bob"], repo.query(&Q_NAMES, DogQuery::default(), name).unwrap());
+    }
+
+    #[test]
+    fn test_duplicate_templates() {
+        let files = SqlFiles::embedded(&[("dog_names.sql", "SELECT 1"), ("_dog_columns.sql", "name")]).unwrap();
+        let duplicate = |builder: RepositoryBuilder<'_>| match builder.build() {
+            Err(Error::DuplicateTemplate { name, first, second }) => (name, first, second),
+            other => panic!("unexpected result: {:?}", other.map(|_| ())),
+        };
+        assert_eq!(
+            ("dog_names".to_string(), "a template".to_string(), "dog_names.sql".to_string()),
+            duplicate(RepositoryBuilder::memory().template(&Q_DOG_NAMES).files(&files))
+        );
+        assert_eq!(
+            ("dog_columns".to_string(), "_dog_columns.sql".to_string(), "a partial".to_string()),
+            duplicate(RepositoryBuilder::memory().files(&files).partial(&Q_DOG_COLUMNS))
+        );
+        assert_eq!(
+            ("dog_names".to_string(), "a template".to_string(), "a template".to_string()),
+            duplicate(RepositoryBuilder::memory().templates(&[Q_DOG_NAMES, Q_DOG_NAMES]))
+        );
+        assert_eq!(
+            ("dog_columns".to_string(), "a template".to_string(), "a partial".to_string()),
+            duplicate(RepositoryBuilder::memory().template(&Q_DOG_COLUMNS).partial(&Q_DOG_COLUMNS))
+        );
+    }
+}
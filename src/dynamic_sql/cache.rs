@@ -0,0 +1,179 @@
+use std::collections::{HashMap, HashSet};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+
+use hashlink::LruCache;
+
+use crate::dynamic_sql::query::DynamicParam;
+use crate::error::Result;
+
+/// Statistics of the rendered SQL cache of a repository.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CacheStats {
+    pub hits: u64,
+    pub misses: u64,
+    pub size: usize,
+    pub capacity: usize,
+}
+
+/// Identifies the SQL rendered from a template. Bind parameters (`->`) only take part in rendering
+/// through their presence and whether `{{#if [:param]}}` is true for them, e.g. not for `Some("")`,
+/// while the values of render parameters (`=>`) are substituted into the SQL and hence are part of
+/// the key.
+///
+/// NOTE: this relies on templates only testing bind parameters with `{{#if [:param]}}`. A template
+/// that prints the value of a bind parameter must not be used with caching enabled.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub(crate) struct ShapeKey {
+    template: String,
+    params: Vec<(&'static str, ParamShape)>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+enum ParamShape {
+    /// A bind parameter, with whether its rendered value is empty and hence false for `{{#if}}`.
+    Bound { empty: bool },
+    Rendered(String),
+}
+
+impl ShapeKey {
+    pub(crate) fn new(
+        template: &str,
+        context: &HashMap<&'static str, String>,
+        bound: &[DynamicParam<'_>],
+    ) -> Self {
+        let bound = bound.iter().map(|(k, _)| *k).collect::<HashSet<_>>();
+        let mut params = context
+            .iter()
+            .map(|(k, v)| {
+                let shape = if bound.contains(k) {
+                    ParamShape::Bound { empty: v.is_empty() }
+                } else {
+                    ParamShape::Rendered(v.clone())
+                };
+                (*k, shape)
+            })
+            .collect::<Vec<_>>();
+        params.sort();
+        ShapeKey {
+            template: template.to_string(),
+            params,
+        }
+    }
+}
+
+/// A thread-safe LRU cache of rendered SQL.
+pub(crate) struct RenderCache {
+    entries: Mutex<LruCache<ShapeKey, String>>,
+    hits: AtomicU64,
+    misses: AtomicU64,
+}
+
+impl RenderCache {
+    pub(crate) fn new(capacity: usize) -> Self {
+        RenderCache {
+            entries: Mutex::new(LruCache::new(capacity)),
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+        }
+    }
+
+    pub(crate) fn get_or_render<F, E>(&self, key: ShapeKey, render: F) -> Result<String>
+        where
+            F: FnOnce() -> std::result::Result<String, E>,
+            crate::error::Error: From<E>,
+    {
+        if let Some(q) = self.entries.lock().unwrap().get(&key) {
+            self.hits.fetch_add(1, Ordering::Relaxed);
+            return Ok(q.clone());
+        }
+        self.misses.fetch_add(1, Ordering::Relaxed);
+        let q = render()?;
+        self.entries.lock().unwrap().insert(key, q.clone());
+        Ok(q)
+    }
+
+    pub(crate) fn stats(&self) -> CacheStats {
+        let entries = self.entries.lock().unwrap();
+        CacheStats {
+            hits: self.hits.load(Ordering::Relaxed),
+            misses: self.misses.load(Ordering::Relaxed),
+            size: entries.len(),
+            capacity: entries.capacity(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::iter::FromIterator;
+
+    use crate::dynamic_sql::executor::dog::*;
+    use crate::dynamic_sql::{DynamicQueryParameters, DynamicSqlExecutor};
+
+    use super::*;
+
+    #[test]
+    fn test_shape_key() {
+        let query = |q_color| DogQuery {
+            q_color,
+            ..Default::default()
+        };
+        let key = |q: DogQuery| ShapeKey::new("Q", &q.for_render(), &q.for_execution());
+        assert_eq!(key(query(Some("white"))), key(query(Some("yellow"))));
+        assert_ne!(key(query(Some("white"))), key(query(None)));
+        // an empty value is present, but renders like an absent one
+        assert_ne!(key(query(Some("white"))), key(query(Some(""))));
+
+        // values of parameters which are not bound are part of the key
+        let context = HashMap::from_iter(vec![(":limit", "10".to_string())]);
+        assert_ne!(
+            ShapeKey::new("Q", &context, &[]),
+            ShapeKey::new("Q", &HashMap::from_iter(vec![(":limit", "20".to_string())]), &[]),
+        );
+    }
+
+    #[test]
+    fn test_cached_repository() {
+        let repo = memory_repository().with_cache(2);
+        for name in &["Jeff", "Bob", "Tom"] {
+            repo.execute(&Q_DOGS_INSERT, dog_insert(name)).unwrap();
+        }
+        for name in &["Je", "Bo", "To"] {
+            let query = DogQuery {
+                q_name: Some(name),
+                ..Default::default()
+            };
+            let dogs = repo
+                .query(&Q_DOGS_SELECT, query, |row| row.get::<_, String>("name"))
+                .unwrap();
+            assert_eq!(1, dogs.len());
+            assert!(dogs[0].starts_with(name));
+        }
+        assert_eq!(
+            Some(CacheStats {
+                hits: 4,
+                misses: 2,
+                size: 2,
+                capacity: 2,
+            }),
+            repo.cache_stats()
+        );
+        assert_eq!(None, memory_repository().cache_stats());
+    }
+
+    #[test]
+    fn test_cached_empty_values() {
+        let repo = memory_repository().with_cache(4);
+        repo.execute(&Q_DOGS_INSERT, dog_insert("Jeff")).unwrap();
+        let render = |q_name| {
+            let query = DogQuery {
+                q_name: Some(q_name),
+                ..Default::default()
+            };
+            repo.render(&Q_DOGS_SELECT, query).unwrap().sql
+        };
+        assert_eq!("SELECT * FROM dogs WHERE name LIKE '%' || :q_name || '%'", render("Je"));
+        assert_eq!("SELECT * FROM dogs", render(""));
+    }
+}
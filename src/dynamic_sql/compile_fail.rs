@@ -0,0 +1,105 @@
+//! Query types that fail to compile, checked by doctests.
+//!
+//! A nested query type with a parameter of the referencing type:
+//!
+//! ```compile_fail,E0080
+//! use std::iter::FromIterator;
+//!
+//! use rusqlite::ToSql;
+//! use shunlib::dynamic_sql::{DynamicParam, DynamicQueryParameters, ToSqlSegment};
+//!
+//! shunlib::new_query_type!(
+//!     (DogQuery, -> name: String,)
+//!     (DogUpdate, -> name: String, &> query: DogQuery,)
+//! );
+//! # fn main() {}
+//! ```
+//!
+//! Two nested query types with the same prefix:
+//!
+//! ```compile_fail,E0080
+//! use std::iter::FromIterator;
+//!
+//! use rusqlite::ToSql;
+//! use shunlib::dynamic_sql::{DynamicParam, DynamicQueryParameters, ToSqlSegment};
+//!
+//! shunlib::new_query_type!(
+//!     (DogQuery, -> name: String,)
+//!     (DogPair, &> first: DogQuery as "dog", second: DogQuery as "dog",)
+//! );
+//! # fn main() {}
+//! ```
+
+/// A nested query type with a parameter of the deriving struct:
+///
+/// ```compile_fail,E0080
+/// use shunlib::dynamic_sql::DynamicQuery;
+///
+/// #[derive(DynamicQuery)]
+/// struct DogQuery<'q> {
+///     name: Option<&'q str>,
+/// }
+///
+/// #[derive(DynamicQuery)]
+/// struct DogUpdate<'q> {
+///     name: Option<&'q str>,
+///     #[query(flatten)]
+///     query: Option<DogQuery<'q>>,
+/// }
+/// # fn main() {}
+/// ```
+///
+/// Two fields with the same parameter:
+///
+/// ```compile_fail
+/// use shunlib::dynamic_sql::DynamicQuery;
+///
+/// #[derive(DynamicQuery)]
+/// struct DogQuery<'q> {
+///     name: Option<&'q str>,
+///     #[query(rename = "name")]
+///     q_name: Option<&'q str>,
+/// }
+/// # fn main() {}
+/// ```
+///
+/// A default of a field that is not an `Option`:
+///
+/// ```compile_fail
+/// use shunlib::dynamic_sql::DynamicQuery;
+///
+/// #[derive(DynamicQuery)]
+/// struct DogQuery {
+///     #[query(default = 10)]
+///     limit: u32,
+/// }
+/// # fn main() {}
+/// ```
+///
+/// A prefix of a field that is not flattened:
+///
+/// ```compile_fail
+/// use shunlib::dynamic_sql::DynamicQuery;
+///
+/// #[derive(DynamicQuery)]
+/// struct DogQuery<'q> {
+///     #[query(prefix = "dog")]
+///     name: Option<&'q str>,
+/// }
+/// # fn main() {}
+/// ```
+///
+/// A filter with an unknown operator:
+///
+/// ```compile_fail
+/// use shunlib::dynamic_sql::DynamicQuery;
+///
+/// #[derive(DynamicQuery)]
+/// struct DogQuery {
+///     #[query(filter = "weight at_most")]
+///     weight_upper: Option<f32>,
+/// }
+/// # fn main() {}
+/// ```
+#[cfg(feature = "derive")]
+mod derive {}
@@ -0,0 +1,446 @@
+use std::fmt::{self, Display, Formatter};
+
+use rusqlite::types::{FromSqlError, Type, ValueRef};
+use rusqlite::Row;
+use serde::de::{
+    self, DeserializeOwned, DeserializeSeed, IntoDeserializer, MapAccess, SeqAccess, Visitor,
+};
+use serde::forward_to_deserialize_any;
+
+/// Map a [Row] into any type implementing [serde::Deserialize]. It can be used directly as the row
+/// mapper of [DynamicSqlExecutor::query](crate::dynamic_sql::DynamicSqlExecutor::query).
+///
+/// - Structs and maps are filled by column name, so `#[serde(rename)]` etc. work as usual.
+/// - Tuples and sequences are filled by column index.
+/// - `NULL` is deserialized as [None] for [Option] fields.
+/// - Other types (e.g. [String] or [i64]) are deserialized from the only column of the row.
+///
+/// Failures of a column are reported as [rusqlite::Error::FromSqlConversionFailure] naming the
+/// offending column. A field without a column is reported as [rusqlite::Error::InvalidColumnName],
+/// a tuple with more elements than columns as [rusqlite::Error::InvalidColumnIndex], and other
+/// failures of the whole row as [rusqlite::Error::FromSqlConversionFailure] of the first column,
+/// with a message that names no column.
+pub fn from_row<T: DeserializeOwned>(row: &Row<'_>) -> rusqlite::Result<T> {
+    T::deserialize(RowDeserializer { row }).map_err(|err| {
+        let conversion_failure = |i: usize, err: DeError| {
+            let data_type = row.get_ref(i).map(|v| v.data_type()).unwrap_or(Type::Null);
+            rusqlite::Error::FromSqlConversionFailure(i, data_type, Box::new(err))
+        };
+        match err.location {
+            Location::Column(i) => conversion_failure(i, err),
+            Location::MissingColumn(name) => rusqlite::Error::InvalidColumnName(name.to_string()),
+            Location::MissingIndex(i) => rusqlite::Error::InvalidColumnIndex(i),
+            Location::Row => conversion_failure(0, err),
+        }
+    })
+}
+
+#[derive(Debug)]
+pub(crate) struct DeError {
+    message: String,
+    location: Location,
+}
+
+/// Where in the row a [DeError] occurred.
+#[derive(Debug)]
+enum Location {
+    Column(usize),
+    /// A field of a struct without a column of its name.
+    MissingColumn(&'static str),
+    /// An element of a tuple or sequence after the last column.
+    MissingIndex(usize),
+    Row,
+}
+
+impl DeError {
+    fn at(mut self, column: usize, name: &str) -> Self {
+        if let Location::Row = self.location {
+            self.message = format!("column `{}`: {}", name, self.message);
+            self.location = Location::Column(column);
+        }
+        self
+    }
+}
+
+impl Display for DeError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.message)
+    }
+}
+
+impl std::error::Error for DeError {}
+
+impl de::Error for DeError {
+    fn custom<T: Display>(msg: T) -> Self {
+        DeError {
+            message: msg.to_string(),
+            location: Location::Row,
+        }
+    }
+
+    fn invalid_length(len: usize, exp: &dyn de::Expected) -> Self {
+        DeError {
+            message: format!("invalid length {}, expected {}", len, exp),
+            location: Location::MissingIndex(len),
+        }
+    }
+
+    fn missing_field(field: &'static str) -> Self {
+        DeError {
+            message: format!("missing field `{}`", field),
+            location: Location::MissingColumn(field),
+        }
+    }
+}
+
+impl From<rusqlite::Error> for DeError {
+    fn from(err: rusqlite::Error) -> Self {
+        de::Error::custom(err)
+    }
+}
+
+impl From<FromSqlError> for DeError {
+    fn from(err: FromSqlError) -> Self {
+        de::Error::custom(err)
+    }
+}
+
+struct RowDeserializer<'a, 'stmt> {
+    row: &'a Row<'stmt>,
+}
+
+impl<'a, 'stmt> RowDeserializer<'a, 'stmt> {
+    fn columns(&self) -> Columns<'a, 'stmt> {
+        Columns {
+            row: self.row,
+            index: 0,
+            count: self.row.column_count(),
+        }
+    }
+
+    fn single_column(&self) -> Result<ValueDeserializer<'a>, DeError> {
+        match self.row.column_count() {
+            0 => Err(de::Error::invalid_length(0, &"a single column for a non-compound type")),
+            1 => Ok(ValueDeserializer {
+                value: self.row.get_ref(0)?,
+            }),
+            n => {
+                let err: DeError = de::Error::custom(format!(
+                    "expected a single column for a non-compound type, found {} columns",
+                    n
+                ));
+                Err(err.at(1, self.row.column_name(1)?))
+            }
+        }
+    }
+}
+
+/// Deserialize scalars from the only column of the row.
+macro_rules! forward_to_single_column {
+    ($($method:ident)*) => {
+        $(
+            fn $method<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, DeError> {
+                self.single_column()?
+                    .$method(visitor)
+                    .map_err(|e| e.at(0, self.row.column_name(0).unwrap_or("")))
+            }
+        )*
+    };
+}
+
+impl<'de, 'a, 'stmt> de::Deserializer<'de> for RowDeserializer<'a, 'stmt> {
+    type Error = DeError;
+
+    fn deserialize_any<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, DeError> {
+        self.deserialize_map(visitor)
+    }
+
+    fn deserialize_map<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, DeError> {
+        visitor.visit_map(self.columns())
+    }
+
+    fn deserialize_struct<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        _fields: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value, DeError> {
+        self.deserialize_map(visitor)
+    }
+
+    fn deserialize_seq<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, DeError> {
+        visitor.visit_seq(self.columns())
+    }
+
+    fn deserialize_tuple<V: Visitor<'de>>(self, _len: usize, visitor: V) -> Result<V::Value, DeError> {
+        self.deserialize_seq(visitor)
+    }
+
+    fn deserialize_tuple_struct<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        _len: usize,
+        visitor: V,
+    ) -> Result<V::Value, DeError> {
+        self.deserialize_seq(visitor)
+    }
+
+    fn deserialize_newtype_struct<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        visitor: V,
+    ) -> Result<V::Value, DeError> {
+        visitor.visit_newtype_struct(self)
+    }
+
+    fn deserialize_enum<V: Visitor<'de>>(
+        self,
+        name: &'static str,
+        variants: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value, DeError> {
+        self.single_column()?
+            .deserialize_enum(name, variants, visitor)
+            .map_err(|e| e.at(0, self.row.column_name(0).unwrap_or("")))
+    }
+
+    forward_to_single_column! {
+        deserialize_bool deserialize_i8 deserialize_i16 deserialize_i32 deserialize_i64
+        deserialize_u8 deserialize_u16 deserialize_u32 deserialize_u64 deserialize_f32
+        deserialize_f64 deserialize_char deserialize_str deserialize_string deserialize_bytes
+        deserialize_byte_buf deserialize_option deserialize_unit deserialize_identifier
+    }
+
+    forward_to_deserialize_any! {
+        i128 u128 unit_struct ignored_any
+    }
+}
+
+/// Accesses the columns of a row either by name (as a map) or by index (as a sequence).
+struct Columns<'a, 'stmt> {
+    row: &'a Row<'stmt>,
+    index: usize,
+    count: usize,
+}
+
+impl<'a, 'stmt> Columns<'a, 'stmt> {
+    fn next_value<'de, T: DeserializeSeed<'de>>(&mut self, seed: T) -> Result<T::Value, DeError> {
+        let index = self.index;
+        self.index += 1;
+        let name = self.row.column_name(index)?;
+        let value = self.row.get_ref(index)?;
+        seed.deserialize(ValueDeserializer { value }).map_err(|e| e.at(index, name))
+    }
+}
+
+impl<'de, 'a, 'stmt> MapAccess<'de> for Columns<'a, 'stmt> {
+    type Error = DeError;
+
+    fn next_key_seed<K: DeserializeSeed<'de>>(&mut self, seed: K) -> Result<Option<K::Value>, DeError> {
+        if self.index >= self.count {
+            return Ok(None);
+        }
+        let name = self.row.column_name(self.index)?;
+        seed.deserialize(name.into_deserializer()).map(Some)
+    }
+
+    fn next_value_seed<V: DeserializeSeed<'de>>(&mut self, seed: V) -> Result<V::Value, DeError> {
+        self.next_value(seed)
+    }
+
+    fn size_hint(&self) -> Option<usize> {
+        Some(self.count - self.index)
+    }
+}
+
+impl<'de, 'a, 'stmt> SeqAccess<'de> for Columns<'a, 'stmt> {
+    type Error = DeError;
+
+    fn next_element_seed<T: DeserializeSeed<'de>>(&mut self, seed: T) -> Result<Option<T::Value>, DeError> {
+        if self.index >= self.count {
+            return Ok(None);
+        }
+        self.next_value(seed).map(Some)
+    }
+
+    fn size_hint(&self) -> Option<usize> {
+        Some(self.count - self.index)
+    }
+}
+
+/// Deserializes a single SQLite value.
+struct ValueDeserializer<'a> {
+    value: ValueRef<'a>,
+}
+
+impl<'de, 'a> de::Deserializer<'de> for ValueDeserializer<'a> {
+    type Error = DeError;
+
+    fn deserialize_any<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, DeError> {
+        match self.value {
+            ValueRef::Null => visitor.visit_unit(),
+            ValueRef::Integer(i) => visitor.visit_i64(i),
+            ValueRef::Real(f) => visitor.visit_f64(f),
+            ValueRef::Text(_) => visitor.visit_str(self.value.as_str()?),
+            ValueRef::Blob(b) => visitor.visit_bytes(b),
+        }
+    }
+
+    fn deserialize_option<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, DeError> {
+        match self.value {
+            ValueRef::Null => visitor.visit_none(),
+            _ => visitor.visit_some(self),
+        }
+    }
+
+    /// SQLite has no boolean type, booleans are stored as integers.
+    fn deserialize_bool<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, DeError> {
+        match self.value {
+            ValueRef::Integer(i) => visitor.visit_bool(i != 0),
+            _ => self.deserialize_any(visitor),
+        }
+    }
+
+    /// Unit variants are stored as text.
+    fn deserialize_enum<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        _variants: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value, DeError> {
+        match self.value {
+            ValueRef::Text(_) => {
+                let s = self.value.as_str()?;
+                visitor.visit_enum(s.into_deserializer())
+            }
+            _ => self.deserialize_any(visitor),
+        }
+    }
+
+    fn deserialize_newtype_struct<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        visitor: V,
+    ) -> Result<V::Value, DeError> {
+        visitor.visit_newtype_struct(self)
+    }
+
+    forward_to_deserialize_any! {
+        i8 i16 i32 i64 i128 u8 u16 u32 u64 u128 f32 f64 char str string
+        bytes byte_buf unit unit_struct seq tuple tuple_struct map struct
+        identifier ignored_any
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use rusqlite::types::Type;
+    use serde::Deserialize;
+
+    use crate::dynamic_sql::executor::dog::*;
+    use crate::dynamic_sql::DynamicSqlExecutor;
+    use crate::error::Error;
+
+    #[derive(Debug, PartialEq, Deserialize)]
+    #[serde(rename_all = "lowercase")]
+    enum Color {
+        White,
+        Yellow,
+    }
+
+    #[derive(Debug, PartialEq, Deserialize)]
+    struct Pet {
+        #[serde(rename = "name")]
+        nickname: String,
+        color: Option<Color>,
+        weight: f32,
+    }
+
+    #[test]
+    fn test_query_as() {
+        let repo = memory_repository();
+        repo.execute(&Q_DOGS_INSERT, dog_insert("Jeff")).unwrap();
+        let bob = DogInsert {
+            color: None,
+            ..dog_insert("Bob")
+        };
+        repo.execute(&Q_DOGS_INSERT, bob).unwrap();
+
+        let pets: Vec<Pet> = repo.query_as(&Q_DOGS_SELECT, DogQuery::default()).unwrap();
+        assert_eq!(
+            vec![
+                Pet {
+                    nickname: "Jeff".to_string(),
+                    color: Some(Color::White),
+                    weight: 20.5,
+                },
+                Pet {
+                    nickname: "Bob".to_string(),
+                    color: None,
+                    weight: 20.5,
+                },
+            ],
+            pets
+        );
+
+        let tuples: Vec<(String, Option<String>, f64)> =
+            repo.query_as(&Q_DOGS_SELECT, DogQuery::default()).unwrap();
+        assert_eq!(("Bob".to_string(), None, 20.5), tuples[1]);
+
+        let result: crate::Result<Vec<(String, String, f64)>> =
+            repo.query_as(&Q_DOGS_SELECT, DogQuery::default());
+        match result {
+            Err(Error::RowMappingError { index: 1, source }) => {
+                assert!(source.to_string().contains("column `color`"), "{}", source)
+            }
+            other => panic!("unexpected result: {:?}", other),
+        }
+    }
+
+    #[derive(Debug, Deserialize)]
+    struct Owner {
+        #[allow(dead_code)]
+        owner: String,
+    }
+
+    #[derive(Debug, Deserialize)]
+    #[serde(deny_unknown_fields)]
+    struct Named {
+        #[allow(dead_code)]
+        name: String,
+    }
+
+    #[test]
+    fn test_row_errors() {
+        let repo = memory_repository();
+        repo.execute(&Q_DOGS_INSERT, dog_insert("Jeff")).unwrap();
+        let error = |result: crate::Result<()>| match result {
+            Err(Error::RowMappingError { index: 0, source }) => source,
+            other => panic!("unexpected result: {:?}", other),
+        };
+        let result: crate::Result<Vec<Owner>> = repo.query_as(&Q_DOGS_SELECT, DogQuery::default());
+        assert!(matches!(error(result.map(|_| ())), rusqlite::Error::InvalidColumnName(ref n) if n == "owner"));
+        let result: crate::Result<Vec<(String, String, f64, i64)>> = repo.query_as(&Q_DOGS_SELECT, DogQuery::default());
+        assert!(matches!(error(result.map(|_| ())), rusqlite::Error::InvalidColumnIndex(3)));
+        let result: crate::Result<Vec<String>> = repo.query_as(&Q_DOGS_SELECT, DogQuery::default());
+        assert!(matches!(error(result.map(|_| ())), rusqlite::Error::FromSqlConversionFailure(1, ..)));
+        let result: crate::Result<Vec<Named>> = repo.query_as(&Q_DOGS_SELECT, DogQuery::default());
+        match error(result.map(|_| ())) {
+            rusqlite::Error::FromSqlConversionFailure(0, Type::Text, err) => {
+                assert!(err.to_string().starts_with("unknown field `color`"))
+            }
+            other => panic!("unexpected error: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_single_column() {
+        let repo = memory_repository();
+        repo.execute(&Q_DOGS_INSERT, dog_insert("Jeff")).unwrap();
+        let count = repo
+            .conn
+            .query_row("SELECT COUNT(*) FROM dogs", [], super::from_row::<i64>)
+            .unwrap();
+        assert_eq!(1, count);
+    }
+}
@@ -0,0 +1,589 @@
+use handlebars::{Handlebars, HelperDef};
+use rusqlite::{Connection, Row, Statement, ToSql};
+
+use std::cell::Cell;
+use std::collections::{HashMap, HashSet};
+use std::time::Instant;
+
+use crate::dynamic_sql::cache::{CacheStats, RenderCache, ShapeKey};
+use crate::dynamic_sql::executor::QueryRows;
+use crate::dynamic_sql::handlebars_helpers::sql_helpers;
+use crate::dynamic_sql::interceptor::{Call, CallKind, Interceptor, Outcome};
+use crate::dynamic_sql::loader::partial_references;
+use crate::dynamic_sql::nested::{sqlite_params, DUPLICATES, UNPREFIXED};
+use crate::dynamic_sql::plan::{aliased_table, full_scan, QueryPlan};
+use crate::dynamic_sql::policy::{CollectedRows, RowErrorPolicy};
+use crate::dynamic_sql::query::{bind_params, DynamicQueryParameters, RenderedQuery};
+use crate::dynamic_sql::template::SqlTemplate;
+use crate::dynamic_sql::trace::Trace;
+use crate::dynamic_sql::validate::{combinations, conditions, ValidationFailure};
+use crate::error::{Error, Result};
+
+/// [Engine] holds everything that is needed to turn a template and query parameters into a result,
+/// except for the [Connection] itself. It is shared by all executors, so that e.g. a
+/// [Transaction](crate::dynamic_sql::Transaction) behaves exactly like the repository it belongs to.
+pub(crate) struct Engine<'reg> {
+    handlebars: Handlebars<'reg>,
+    /// Sources of the registered templates and partials, for [Engine::validate].
+    templates: HashMap<String, String>,
+    partials: HashMap<String, String>,
+    cache: Option<RenderCache>,
+    row_error_policy: RowErrorPolicy,
+    interceptors: Vec<Box<dyn Interceptor>>,
+    /// Record the values of parameters in traces, not only their names.
+    trace_values: bool,
+}
+
+/// Where a statement is run from, for [Engine::run].
+struct Site<'t, 'c> {
+    kind: CallKind,
+    template: &'t str,
+    /// The statements of a batch to reuse instead of preparing a new or cached one.
+    statements: Option<&'t mut HashMap<String, Statement<'c>>>,
+}
+
+/// What [Engine::execute_batch] keeps across the items of a batch: the SQL rendered for every
+/// parameter shape and the statement prepared for it. Unlike cached statements, these are reused
+/// even if the statement cache of the connection is disabled.
+#[derive(Default)]
+pub(crate) struct BatchShapes<'c> {
+    sql: HashMap<ShapeKey, String>,
+    /// By the SQL that is run, which interceptors may have rewritten.
+    statements: HashMap<String, Statement<'c>>,
+}
+
+impl<'reg> Engine<'reg> {
+    /// Create an [Engine] with `templates` and the SQL helpers registered.
+    pub(crate) fn new<'a, T, I>(templates: &'a T) -> Result<Self>
+        where
+            &'a T: IntoIterator<Item = &'a I>,
+            I: SqlTemplate + 'a,
+    {
+        let mut handlebars = Handlebars::new();
+        for (k, h) in sql_helpers() {
+            handlebars.register_helper(k, h);
+        }
+        let mut engine = Engine {
+            handlebars,
+            templates: HashMap::new(),
+            partials: HashMap::new(),
+            cache: None,
+            row_error_policy: RowErrorPolicy::default(),
+            interceptors: vec![],
+            trace_values: false,
+        };
+        for q in templates {
+            engine.register_template(q)?;
+        }
+        Ok(engine)
+    }
+
+    pub(crate) fn register_template<S: SqlTemplate + ?Sized>(&mut self, template: &S) -> Result<()> {
+        self.handlebars
+            .register_template_string(template.name(), template.sql())?;
+        self.templates
+            .insert(template.name().to_string(), template.sql().to_string());
+        Ok(())
+    }
+
+    /// Register a partial that templates can include with `{{> name}}`.
+    pub(crate) fn register_partial(&mut self, name: &str, sql: &str) -> Result<()> {
+        self.handlebars.register_partial(name, sql)?;
+        self.partials.insert(name.to_string(), sql.to_string());
+        Ok(())
+    }
+
+    pub(crate) fn register_helper(&mut self, name: &str, helper: Box<dyn HelperDef + Send + Sync + 'reg>) {
+        self.handlebars.register_helper(name, helper);
+    }
+
+    /// Cache up to `capacity` rendered SQL strings and use prepared statements cached by the
+    /// connection. A `capacity` of `0` disables caching.
+    pub(crate) fn set_cache_capacity(&mut self, capacity: usize) {
+        self.cache = if capacity == 0 {
+            None
+        } else {
+            Some(RenderCache::new(capacity))
+        };
+    }
+
+    pub(crate) fn set_row_error_policy(&mut self, policy: RowErrorPolicy) {
+        self.row_error_policy = policy;
+    }
+
+    /// Add an interceptor that runs after the ones already added.
+    pub(crate) fn add_interceptor(&mut self, interceptor: Box<dyn Interceptor>) {
+        self.interceptors.push(interceptor);
+    }
+
+    #[cfg(feature = "tracing")]
+    pub(crate) fn set_trace_values(&mut self, enabled: bool) {
+        self.trace_values = enabled;
+    }
+
+    pub(crate) fn cache_stats(&self) -> Option<CacheStats> {
+        self.cache.as_ref().map(|c| c.stats())
+    }
+
+    /// Render `template` into SQL, reusing a cached result for the same parameter shape if caching is enabled.
+    pub(crate) fn render<S, P>(&self, template: &S, params: &P) -> Result<String>
+        where
+            S: SqlTemplate,
+            P: DynamicQueryParameters,
+    {
+        let context = params.for_render();
+        if let Some(duplicates) = context.get(DUPLICATES) {
+            return Err(Error::DuplicateParams(duplicates.clone()));
+        }
+        if let Some(unprefixed) = context.get(UNPREFIXED) {
+            return Err(Error::UnprefixedParams(unprefixed.clone()));
+        }
+        let render = || -> Result<String> {
+            let q = self.handlebars.render(template.name(), &context)?;
+            Ok(sqlite_params(&q).into_owned())
+        };
+        let q = match self.cache {
+            Some(ref cache) => {
+                let key = ShapeKey::new(template.name(), &context, &params.for_execution());
+                cache.get_or_render(key, render)?
+            }
+            None => render()?,
+        };
+        log::debug!("{}", &q);
+        Ok(q)
+    }
+
+    /// Render `template` without running it.
+    pub(crate) fn render_query<S, P>(&self, template: &S, params: P) -> Result<RenderedQuery>
+        where
+            S: SqlTemplate,
+            P: DynamicQueryParameters,
+    {
+        let q = self.render(template, &params)?;
+        RenderedQuery::new(q, &params)
+    }
+
+    /// Prepare `sql` on `conn` and hand the statement to `f`. Cached statements are used when
+    /// caching is enabled.
+    pub(crate) fn with_statement<R, F>(&self, conn: &Connection, sql: &str, f: F) -> Result<R>
+        where
+            F: FnOnce(&mut Statement<'_>) -> Result<R>,
+    {
+        if self.cache.is_some() {
+            f(&mut *conn.prepare_cached(sql)?)
+        } else {
+            f(&mut conn.prepare(sql)?)
+        }
+    }
+
+    /// Render the SQL with `render`, prepare it and hand the statement and the parameters to bind
+    /// to `run`. If there are interceptors, they may rewrite or reject the SQL and `params` first,
+    /// and are told about the outcome afterwards, with the number of rows taken from the result
+    /// by `count`. All of it is traced if the `tracing` feature is enabled.
+    fn run<'c, P, R, Q, F, N>(
+        &self,
+        conn: &'c Connection,
+        site: Site<'_, 'c>,
+        render: Q,
+        params: &P,
+        run: F,
+        count: N,
+    ) -> Result<R>
+        where
+            P: DynamicQueryParameters + ?Sized,
+            Q: FnOnce() -> Result<String>,
+            F: FnOnce(&mut Statement<'_>, &[(&str, &dyn ToSql)]) -> Result<R>,
+            N: FnOnce(&R) -> Option<usize>,
+    {
+        let trace = Trace::new(site.kind, site.template);
+        let _entered = trace.enter();
+        let fail = |e: Error| {
+            trace.failed(&e);
+            e
+        };
+
+        let start = Instant::now();
+        let sql = render().map_err(fail)?;
+        trace.rendered(start.elapsed(), &sql);
+
+        // the values are only converted for tracing if the span is recorded
+        let trace_values = self.trace_values && trace.enabled();
+        let mut rewritten = false;
+        let call = if self.interceptors.is_empty() && !trace_values {
+            None
+        } else {
+            let RenderedQuery { sql, params } = RenderedQuery::new(sql.clone(), params).map_err(fail)?;
+            let mut call = Call {
+                kind: site.kind,
+                template: site.template.to_string(),
+                sql,
+                params,
+            };
+            let rendered = match self.interceptors.is_empty() {
+                true => None,
+                false => Some(call.params.clone()),
+            };
+            for interceptor in &self.interceptors {
+                interceptor.before(&mut call).map_err(fail)?;
+            }
+            rewritten = rendered.is_some_and(|params| params != call.params);
+            trace.params(call.params.iter().map(|(k, _)| k.as_str()));
+            if trace_values {
+                trace.values(&call.params);
+            }
+            Some(call)
+        };
+
+        // the caller's values are bound unless an interceptor changed the parameters
+        let direct = match rewritten {
+            true => vec![],
+            false => {
+                let bound = bind_params(params).map_err(fail)?;
+                if call.is_none() {
+                    trace.params(bound.iter().map(|(k, _)| k.as_ref()));
+                }
+                bound
+            }
+        };
+
+        let start = Instant::now();
+        let execute = |sql: &str, bound: &[(&str, &dyn ToSql)]| {
+            let run = |stmt: &mut Statement<'_>| {
+                trace.prepared(start.elapsed());
+                let stepping = Instant::now();
+                let result = run(stmt, bound);
+                trace.stepped(stepping.elapsed());
+                result
+            };
+            match site.statements {
+                Some(statements) => {
+                    if !statements.contains_key(sql) {
+                        statements.insert(sql.to_string(), conn.prepare(sql)?);
+                    }
+                    run(statements.get_mut(sql).unwrap())
+                }
+                None => self.with_statement(conn, sql, run),
+            }
+        };
+        let sql = call.as_ref().map_or(sql.as_str(), |call| call.sql.as_str());
+        let result = match call {
+            Some(ref call) if rewritten => {
+                let bound = call
+                    .params
+                    .iter()
+                    .map(|(k, v)| (k.as_str(), v as &dyn ToSql))
+                    .collect::<Vec<_>>();
+                execute(sql, &bound)
+            }
+            _ => {
+                let bound = direct.iter().map(|(k, v)| (k.as_ref(), *v)).collect::<Vec<_>>();
+                execute(sql, &bound)
+            }
+        };
+        let outcome = Outcome {
+            elapsed: start.elapsed(),
+            result: result.as_ref().map(count),
+        };
+        trace.finished(&outcome.result);
+        if let Some(ref call) = call {
+            for interceptor in self.interceptors.iter().rev() {
+                interceptor.after(call, &outcome);
+            }
+        }
+        result
+    }
+
+    /// Render `template` and run the resulting query on `conn`. Rows that cannot be mapped are
+    /// handled according to the [RowErrorPolicy] of this engine.
+    pub(crate) fn query<S, P, F, T>(
+        &self,
+        conn: &Connection,
+        template: &S,
+        params: P,
+        f: F,
+    ) -> Result<Vec<T>>
+        where
+            S: SqlTemplate,
+            P: DynamicQueryParameters,
+            F: FnMut(&Row<'_>) -> rusqlite::Result<T>,
+    {
+        let collected = self.run(
+            conn,
+            Site {
+                kind: CallKind::Query,
+                template: template.name(),
+                statements: None,
+            },
+            || self.render(template, &params),
+            &params,
+            |stmt, bound| self.row_error_policy.collect(stmt.query_map(bound, RowErrorPolicy::mapper(f))?),
+            |collected: &CollectedRows<T>| Some(collected.rows.len()),
+        )?;
+        if collected.errors.is_empty() {
+            Ok(collected.rows)
+        } else {
+            Err(Error::RowMappingErrors(collected.errors.into()))
+        }
+    }
+
+    /// Render `template`, transform the SQL with `derive` and hand the rows of the resulting query
+    /// to `consume` while the statement is alive.
+    pub(crate) fn query_derived<S, P, D, F, T, C, R>(
+        &self,
+        conn: &Connection,
+        template: &S,
+        params: P,
+        derive: D,
+        f: F,
+        consume: C,
+    ) -> Result<R>
+        where
+            S: SqlTemplate,
+            P: DynamicQueryParameters,
+            D: FnOnce(String) -> String,
+            F: FnMut(&Row<'_>) -> rusqlite::Result<T>,
+            C: FnOnce(QueryRows<'_, F>) -> Result<R>,
+    {
+        let site = Site {
+            kind: CallKind::Query,
+            template: template.name(),
+            statements: None,
+        };
+        let render = || Ok(derive(self.render(template, &params)?));
+        let fetched = Cell::new(0);
+        self.run(
+            conn,
+            site,
+            render,
+            &params,
+            |stmt, bound| consume(QueryRows::new(stmt.query_map(bound, f)?, &fetched)),
+            |_| Some(fetched.get()),
+        )
+    }
+
+    /// Same as [Engine::execute], but return the rowid of the inserted row.
+    pub(crate) fn execute_returning_rowid<S, P>(
+        &self,
+        conn: &Connection,
+        template: &S,
+        params: P,
+    ) -> Result<i64>
+        where
+            S: SqlTemplate,
+            P: DynamicQueryParameters,
+    {
+        let site = Site {
+            kind: CallKind::Execute,
+            template: template.name(),
+            statements: None,
+        };
+        self.run(conn, site, || self.render(template, &params), &params, |stmt, bound| Ok(stmt.insert(bound)?), |_| Some(1))
+    }
+
+    /// Execute `template` once for every item of `items` on `conn`, pushing the affected row counts
+    /// to `counts`. SQL is rendered and prepared once per parameter shape, see [BatchShapes].
+    pub(crate) fn execute_batch<'c, S, P, I>(
+        &self,
+        conn: &'c Connection,
+        template: &S,
+        items: I,
+        shapes: &mut BatchShapes<'c>,
+        counts: &mut Vec<usize>,
+    ) -> Result<()>
+        where
+            S: SqlTemplate,
+            P: DynamicQueryParameters,
+            I: Iterator<Item = P>,
+    {
+        let BatchShapes { sql, statements } = shapes;
+        for params in items {
+            let index = counts.len();
+            let key = ShapeKey::new(template.name(), &params.for_render(), &params.for_execution());
+            let site = Site {
+                kind: CallKind::Execute,
+                template: template.name(),
+                statements: Some(&mut *statements),
+            };
+            let render = || {
+                if !sql.contains_key(&key) {
+                    let q = self.render(template, &params)?;
+                    sql.insert(key.clone(), q);
+                }
+                Ok(sql[&key].clone())
+            };
+            let result =
+                self.run(conn, site, render, &params, |stmt, bound| Ok(stmt.execute(bound)?), |n| Some(*n));
+            counts.push(result.map_err(|e| Error::BatchError {
+                index,
+                source: Box::new(e),
+            })?);
+        }
+        Ok(())
+    }
+
+    /// Same as [Engine::query], but for statements that do not return rows.
+    pub(crate) fn execute<S, P>(&self, conn: &Connection, template: &S, params: P) -> Result<usize>
+        where
+            S: SqlTemplate,
+            P: DynamicQueryParameters,
+    {
+        let site = Site {
+            kind: CallKind::Execute,
+            template: template.name(),
+            statements: None,
+        };
+        self.run(conn, site, || self.render(template, &params), &params, |stmt, bound| Ok(stmt.execute(bound)?), |n| Some(*n))
+    }
+
+    /// Render every template for every presence combination of the parameters it tests with
+    /// `[:param]` and prepare the result on `conn`. Templates that are included by others are
+    /// only validated as part of those.
+    pub(crate) fn validate(&self, conn: &Connection) -> Result<()> {
+        let mut includable = self
+            .partials
+            .iter()
+            .map(|(k, v)| (k.as_str(), v.as_str()))
+            .collect::<HashMap<_, _>>();
+        // like Handlebars, fall back to templates for partials that are not registered as such
+        for (k, v) in &self.templates {
+            includable.entry(k.as_str()).or_insert(v.as_str());
+        }
+        let included = self
+            .templates
+            .values()
+            .chain(self.partials.values())
+            .flat_map(|sql| partial_references(sql))
+            .collect::<HashSet<_>>();
+
+        let mut names = self
+            .templates
+            .keys()
+            .filter(|name| !included.contains(name.as_str()))
+            .collect::<Vec<_>>();
+        names.sort();
+        let mut failures = vec![];
+        for name in names {
+            let conditions = conditions(&self.templates[name], &includable)
+                .into_iter()
+                .collect::<Vec<_>>();
+            for mask in combinations(conditions.len()) {
+                let params = conditions
+                    .iter()
+                    .enumerate()
+                    .filter(|(i, _)| mask & (1 << i) != 0)
+                    .map(|(_, c)| c.clone())
+                    .collect::<Vec<_>>();
+                // render parameters get a value that is valid in most positions, e.g. `LIMIT`;
+                // `{{filters}}` renders nothing without the filters of a query type
+                let context = params
+                    .iter()
+                    .map(|p| (p.as_str(), "1"))
+                    .collect::<HashMap<_, _>>();
+                let result = self
+                    .handlebars
+                    .render(name, &context)
+                    .map_err(Error::from)
+                    .and_then(|q| {
+                        if !q.trim().is_empty() {
+                            conn.prepare(&sqlite_params(&q))?;
+                        }
+                        Ok(())
+                    });
+                if let Err(error) = result {
+                    failures.push(ValidationFailure {
+                        template: name.clone(),
+                        params,
+                        error,
+                    });
+                }
+            }
+        }
+        if failures.is_empty() {
+            Ok(())
+        } else {
+            Err(Error::TemplateValidationErrors(failures.into()))
+        }
+    }
+
+    /// Render `template` and return the `EXPLAIN QUERY PLAN` of the resulting SQL on `conn`.
+    pub(crate) fn explain<S, P>(&self, conn: &Connection, template: &S, params: P) -> Result<QueryPlan>
+        where
+            S: SqlTemplate,
+            P: DynamicQueryParameters,
+    {
+        let sql = self.render(template, &params)?;
+        let q = format!("EXPLAIN QUERY PLAN {}", sql);
+        let bound = bind_params(&params)?;
+        let bound = bound.iter().map(|(k, v)| (k.as_ref(), *v)).collect::<Vec<_>>();
+        let rows = self.with_statement(conn, &q, |stmt| {
+            let rows = stmt
+                .query_map(bound.as_slice(), |row| {
+                    Ok((row.get(0)?, row.get(1)?, row.get(3)?))
+                })?
+                .collect::<rusqlite::Result<Vec<(i64, i64, String)>>>()?;
+            Ok(rows)
+        })?;
+        let has_indexes = |table: &str| -> Result<bool> {
+            let indexes: i64 = conn.query_row(
+                "SELECT COUNT(*) FROM pragma_index_list(?)",
+                [table],
+                |row| row.get(0),
+            )?;
+            Ok(indexes > 0)
+        };
+        let mut indexed = HashSet::new();
+        for table in rows.iter().filter_map(|(_, _, detail)| full_scan(detail)) {
+            // newer SQLite versions report the alias of an aliased table
+            let aliased = aliased_table(&sql, table);
+            if has_indexes(table)? || aliased.map(has_indexes).transpose()?.unwrap_or(false) {
+                indexed.insert(table.to_string());
+            }
+        }
+        Ok(QueryPlan::from_rows(&rows, &indexed))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use rusqlite::StatementStatus;
+
+    use crate::dynamic_sql::executor::dog::*;
+
+    use super::*;
+
+    #[test]
+    fn test_batch_statements() {
+        let engine = Engine::new(&[Q_DOGS_INSERT, Q_DOGS_UPDATE, Q_DOGS_WHERE]).unwrap();
+        let conn = Connection::open_in_memory().unwrap();
+        conn.set_prepared_statement_cache_capacity(0);
+        conn.execute_batch(DDL).unwrap();
+        let insert = vec![dog_insert("Jeff")].into_iter();
+        engine
+            .execute_batch(&conn, &Q_DOGS_INSERT, insert, &mut BatchShapes::default(), &mut vec![])
+            .unwrap();
+
+        let weight = |weight| DogUpdate {
+            weight: Some(weight),
+            ..Default::default()
+        };
+        let color = |color| DogUpdate {
+            color: Some(color),
+            ..Default::default()
+        };
+        let items = vec![weight(1.0), color("black"), weight(2.0), weight(3.0), color("white")];
+        let mut shapes = BatchShapes::default();
+        let mut counts = vec![];
+        engine
+            .execute_batch(&conn, &Q_DOGS_UPDATE, items.into_iter(), &mut shapes, &mut counts)
+            .unwrap();
+        assert_eq!(vec![1; 5], counts);
+        assert_eq!(2, shapes.sql.len());
+        let mut runs = shapes
+            .statements
+            .iter()
+            .map(|(sql, stmt)| (sql.as_str(), stmt.get_status(StatementStatus::Run)))
+            .collect::<Vec<_>>();
+        runs.sort();
+        assert_eq!(vec![("UPDATE dogs SET color=:color", 2), ("UPDATE dogs SET weight=:weight", 3)], runs);
+    }
+}
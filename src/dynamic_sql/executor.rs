@@ -1,20 +1,29 @@
-use std::iter::FromIterator;
+use std::cell::Cell;
 use std::path::Path;
 
-use handlebars::Handlebars;
-use rusqlite::{Connection, Row, ToSql};
-use crate::dynamic_sql::query::DynamicQueryParameters;
+use rusqlite::{Connection, MappedRows, Row};
+use serde::de::DeserializeOwned;
 
-use crate::dynamic_sql::template::SqlTemplate;
-use crate::error::Result;
+use crate::dynamic_sql::cache::CacheStats;
+use crate::dynamic_sql::de::from_row;
+use crate::dynamic_sql::engine::Engine;
+use crate::dynamic_sql::interceptor::Interceptor;
+use crate::dynamic_sql::migration::Migrations;
+use crate::dynamic_sql::page::{subquery, Page, PageParams, PageRequest};
+use crate::dynamic_sql::plan::QueryPlan;
+use crate::dynamic_sql::policy::{CollectedRows, RowErrorPolicy};
+use crate::dynamic_sql::query::{DynamicQueryParameters, RenderedQuery};
 
-use super::sql_helpers;
+use crate::dynamic_sql::template::SqlTemplate;
+use crate::dynamic_sql::transaction::Transaction;
+use crate::error::{Error, Result};
 
 
 /// [DynamicSqlExecutor] is the interface for performing Dynamic SQL queries. A query is dynamic if
 /// the final SQL can only be determined at runtime, generated from a template based on runtime parameters.
 pub trait DynamicSqlExecutor {
-    /// Perform a query and return result, which is handled by `f`.
+    /// Perform a query and return result, which is handled by `f`. Rows for which `f` returns
+    /// `Err` are handled according to the [RowErrorPolicy] of the executor.
     /// Note [Into] is for `&P` instead of for `P`, see [new_query_type] for details.
     fn query<S, P, F, T>(&self, template: &S, params: P, f: F) -> Result<Vec<T>>
         where
@@ -22,20 +31,313 @@ pub trait DynamicSqlExecutor {
             P: DynamicQueryParameters,
             F: FnMut(&Row<'_>) -> rusqlite::Result<T>;
 
+    /// Perform a query and deserialize every row into `T` by column name, see [from_row].
+    fn query_as<T, S, P>(&self, template: &S, params: P) -> Result<Vec<T>>
+        where
+            T: DeserializeOwned,
+            S: SqlTemplate,
+            P: DynamicQueryParameters,
+    {
+        self.query(template, params, from_row::<T>)
+    }
+
+    /// Fetch a page of the rows of a select template. The limit, and for keyset pagination the
+    /// ordering and cursor predicates, are appended to the rendered SQL with the cursor values as
+    /// bind parameters. Rows that cannot be mapped fail the query.
+    fn query_page<S, P, F, T>(
+        &self,
+        template: &S,
+        params: P,
+        page: &PageRequest,
+        mut f: F,
+    ) -> Result<Page<T>>
+        where
+            S: SqlTemplate,
+            P: DynamicQueryParameters,
+            F: FnMut(&Row<'_>) -> rusqlite::Result<T>,
+    {
+        page.validate()?;
+        let total = if page.wants_total() {
+            Some(self.count(template, &params)?)
+        } else {
+            None
+        };
+        let page_params = PageParams {
+            params,
+            cursor: page.cursor(),
+        };
+        let limit = page.limit() as usize;
+        let (items, last, more) = self.query_derived(
+            template,
+            page_params,
+            |q| page.derive_sql(q),
+            |row| Ok((f(row)?, page.cursor_of(row)?)),
+            |rows| {
+                let mut items = vec![];
+                let mut last = None;
+                for (index, row) in rows.enumerate() {
+                    if index == limit {
+                        return Ok((items, last, true));
+                    }
+                    let (item, cursor) =
+                        row.map_err(|source| Error::RowMappingError { index, source })?;
+                    items.push(item);
+                    last = cursor;
+                }
+                Ok((items, last, false))
+            },
+        )?;
+        Ok(Page {
+            items,
+            next: if more { Some(page.next(last)) } else { None },
+            total,
+        })
+    }
+
+    /// Perform a query like [DynamicSqlExecutor::query], but handle rows that fail to be mapped
+    /// according to `policy` instead of the policy of the executor.
+    fn query_with_policy<S, P, F, T>(
+        &self,
+        template: &S,
+        params: P,
+        policy: RowErrorPolicy,
+        f: F,
+    ) -> Result<CollectedRows<T>>
+        where
+            S: SqlTemplate,
+            P: DynamicQueryParameters,
+            F: FnMut(&Row<'_>) -> rusqlite::Result<T>,
+    {
+        self.query_iter(template, params, RowErrorPolicy::mapper(f), |rows| policy.collect(rows))
+    }
+
+    /// Perform a query whose SQL is derived from the rendered template by `derive`, e.g. by wrapping
+    /// it into `SELECT COUNT(*) FROM (...)`, and hand the rows, mapped by `f`, to `consume`.
+    /// The derived SQL is bound with the same parameters as the template.
+    fn query_derived<S, P, D, F, T, C, R>(
+        &self,
+        template: &S,
+        params: P,
+        derive: D,
+        f: F,
+        consume: C,
+    ) -> Result<R>
+        where
+            S: SqlTemplate,
+            P: DynamicQueryParameters,
+            D: FnOnce(String) -> String,
+            F: FnMut(&Row<'_>) -> rusqlite::Result<T>,
+            C: FnOnce(QueryRows<'_, F>) -> Result<R>;
+
+    /// Perform a query and hand the rows, mapped by `f`, to `consume` as an iterator. Rows are
+    /// fetched lazily while `consume` iterates, so the whole result never has to be held in memory.
+    fn query_iter<S, P, F, T, C, R>(&self, template: &S, params: P, f: F, consume: C) -> Result<R>
+        where
+            S: SqlTemplate,
+            P: DynamicQueryParameters,
+            F: FnMut(&Row<'_>) -> rusqlite::Result<T>,
+            C: FnOnce(QueryRows<'_, F>) -> Result<R>,
+    {
+        self.query_derived(template, params, |q| q, f, consume)
+    }
+
+    /// Perform a query that is expected to return at most one row. Returns [Error::TooManyRows]
+    /// if there are more.
+    fn query_optional<S, P, F, T>(&self, template: &S, params: P, f: F) -> Result<Option<T>>
+        where
+            S: SqlTemplate,
+            P: DynamicQueryParameters,
+            F: FnMut(&Row<'_>) -> rusqlite::Result<T>,
+    {
+        self.query_iter(template, params, f, |mut rows| {
+            let first = match rows.next() {
+                Some(row) => row.map_err(|source| Error::RowMappingError { index: 0, source })?,
+                None => return Ok(None),
+            };
+            if rows.next().is_some() {
+                return Err(Error::TooManyRows);
+            }
+            Ok(Some(first))
+        })
+    }
+
+    /// Perform a query that is expected to return exactly one row. Returns [Error::NoRows] if
+    /// there is none and [Error::TooManyRows] if there are more.
+    fn query_one<S, P, F, T>(&self, template: &S, params: P, f: F) -> Result<T>
+        where
+            S: SqlTemplate,
+            P: DynamicQueryParameters,
+            F: FnMut(&Row<'_>) -> rusqlite::Result<T>,
+    {
+        self.query_optional(template, params, f)?.ok_or(Error::NoRows)
+    }
+
+    /// Check whether a select template returns any row, using `SELECT EXISTS(...)`.
+    fn exists<S, P>(&self, template: &S, params: P) -> Result<bool>
+        where
+            S: SqlTemplate,
+            P: DynamicQueryParameters,
+    {
+        self.query_derived(
+            template,
+            params,
+            |q| format!("SELECT EXISTS({})", q),
+            |row| row.get(0),
+            |mut rows| rows.next().ok_or(Error::NoRows)?.map_err(Error::from),
+        )
+    }
+
+    /// Count the rows returned by a select template, using `SELECT COUNT(*) FROM (...)`, so that
+    /// no separate counting template has to be maintained.
+    fn count<S, P>(&self, template: &S, params: P) -> Result<i64>
+        where
+            S: SqlTemplate,
+            P: DynamicQueryParameters,
+    {
+        self.query_derived(
+            template,
+            params,
+            |q| subquery("COUNT(*)", &q),
+            |row| row.get(0),
+            |mut rows| rows.next().ok_or(Error::NoRows)?.map_err(Error::from),
+        )
+    }
+
     /// Execute a query and returns the number of rows that are affected.
     fn execute<S, P>(&self, template: &S, params: P) -> Result<usize>
         where
             S: SqlTemplate,
             P: DynamicQueryParameters;
+
+    /// Execute an `INSERT` and return the rowid of the inserted row. Unlike reading
+    /// [Connection::last_insert_rowid] afterwards, this is done on the connection the statement
+    /// runs on, so it is also correct for pooled connections.
+    fn execute_returning_rowid<S, P>(&self, template: &S, params: P) -> Result<i64>
+        where
+            S: SqlTemplate,
+            P: DynamicQueryParameters;
+
+    /// Render `template` with `params` without running it, e.g. for logging, debugging or
+    /// snapshot tests.
+    fn render<S, P>(&self, template: &S, params: P) -> Result<RenderedQuery>
+        where
+            S: SqlTemplate,
+            P: DynamicQueryParameters;
+
+    /// Render `template` with `params` and return the parsed `EXPLAIN QUERY PLAN` of the resulting
+    /// SQL, e.g. to assert in tests that a query uses an index, see [QueryPlan::assert_no_full_scan].
+    fn explain<S, P>(&self, template: &S, params: P) -> Result<QueryPlan>
+        where
+            S: SqlTemplate,
+            P: DynamicQueryParameters;
+
+    /// Execute a statement with a `RETURNING` clause and return the produced rows mapped by `f`,
+    /// e.g. to get back the persisted entity of an `INSERT` or `UPDATE` in one round trip.
+    fn execute_returning<S, P, F, T>(&self, template: &S, params: P, f: F) -> Result<Vec<T>>
+        where
+            S: SqlTemplate,
+            P: DynamicQueryParameters,
+            F: FnMut(&Row<'_>) -> rusqlite::Result<T>,
+    {
+        // SQLite makes all changes on the first step, so this is the same as a query.
+        self.query(template, params, f)
+    }
+
+    /// Same as [DynamicSqlExecutor::execute_returning], but for statements that affect exactly one row.
+    /// SQLite makes all changes of a statement before returning the first row, so executors with
+    /// a connection run it in a savepoint that is rolled back if the number of rows is wrong.
+    fn execute_returning_one<S, P, F, T>(&self, template: &S, params: P, f: F) -> Result<T>
+        where
+            S: SqlTemplate,
+            P: DynamicQueryParameters,
+            F: FnMut(&Row<'_>) -> rusqlite::Result<T>,
+    {
+        self.query_one(template, params, f)
+    }
+
+    /// Execute a template once for every item of `params` inside one transaction and return the
+    /// number of affected rows per item. SQL is only rendered and prepared once per parameter shape.
+    /// The first failing item is reported with its index as [Error::BatchError], in which case no
+    /// item is committed.
+    fn execute_batch<S, P, I>(&self, template: &S, params: I) -> Result<Vec<usize>>
+        where
+            S: SqlTemplate,
+            P: DynamicQueryParameters,
+            I: IntoIterator<Item = P>,
+    {
+        self.execute_batch_chunked(template, params, usize::MAX)
+    }
+
+    /// Same as [DynamicSqlExecutor::execute_batch], but commit every `chunk_size` items in a
+    /// transaction of their own. When an item fails, only the chunk containing it is rolled back.
+    fn execute_batch_chunked<S, P, I>(
+        &self,
+        template: &S,
+        params: I,
+        chunk_size: usize,
+    ) -> Result<Vec<usize>>
+        where
+            S: SqlTemplate,
+            P: DynamicQueryParameters,
+            I: IntoIterator<Item = P>;
+
+    /// Perform a query and call `each` with every row mapped by `f`, stopping at the first error.
+    /// Returns the number of rows that are visited.
+    fn query_for_each<S, P, F, T, E>(&self, template: &S, params: P, f: F, mut each: E) -> Result<usize>
+        where
+            S: SqlTemplate,
+            P: DynamicQueryParameters,
+            F: FnMut(&Row<'_>) -> rusqlite::Result<T>,
+            E: FnMut(T) -> Result<()>,
+    {
+        self.query_iter(template, params, f, |rows| {
+            let mut count = 0;
+            for row in rows {
+                let row = row.map_err(|source| Error::RowMappingError { index: count, source })?;
+                each(row)?;
+                count += 1;
+            }
+            Ok(count)
+        })
+    }
+}
+
+/// The mapped rows of a query that are handed to a closure, see [DynamicSqlExecutor::query_iter].
+/// Rows are fetched lazily, and the number of rows fetched is what interceptors and tracing report.
+pub struct QueryRows<'a, F> {
+    rows: MappedRows<'a, F>,
+    fetched: &'a Cell<usize>,
+}
+
+impl<'a, F> QueryRows<'a, F> {
+    pub(crate) fn new(rows: MappedRows<'a, F>, fetched: &'a Cell<usize>) -> Self {
+        QueryRows { rows, fetched }
+    }
+}
+
+impl<T, F> Iterator for QueryRows<'_, F>
+    where
+        F: FnMut(&Row<'_>) -> rusqlite::Result<T>,
+{
+    type Item = rusqlite::Result<T>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let row = self.rows.next();
+        if row.is_some() {
+            self.fetched.set(self.fetched.get() + 1);
+        }
+        row
+    }
 }
 
 /// Basic construct for performing Dynamic SQL queries.
 /// NOTE: This struct is not [Sync] because [Connection] contains a [RefCell] and thus is not [Sync].
 /// On the other hand, [Handlebars] is [Sync].
-/// This indicates that it need to be wrapped inside a [Mutex] when used in a multi-threaded context.
+/// This indicates that it need to be wrapped inside a [Mutex] when used in a multi-threaded context,
+/// or replaced by `PooledRepository` (feature `pool`) which hands out one connection per call.
 pub struct Repository<'reg> {
     pub conn: Connection,
-    handlebars: Handlebars<'reg>,
+    engine: Engine<'reg>,
 }
 
 impl<'reg> Repository<'reg> {
@@ -46,15 +348,64 @@ impl<'reg> Repository<'reg> {
             I: SqlTemplate + 'a,
     {
         let conn = Connection::open(file)?;
-        let mut handlebars = Handlebars::new();
-        for q in templates {
-            handlebars.register_template_string(q.name(), q.sql())?;
-        }
+        Ok(Repository::from_parts(conn, Engine::new(templates)?))
+    }
 
-        for (k, h) in sql_helpers() {
-            handlebars.register_helper(k, h);
-        }
-        Ok(Repository { conn, handlebars })
+    pub(crate) fn from_parts(conn: Connection, engine: Engine<'reg>) -> Self {
+        Repository { conn, engine }
+    }
+
+    /// Cache up to `capacity` rendered SQL strings and prepared statements. Rendered SQL is cached
+    /// per template and parameter "shape", i.e. which bind parameters are present plus the values
+    /// of render parameters, so templates must only test bind parameters for presence.
+    /// A `capacity` of `0` disables caching, which is the default.
+    pub fn with_cache(mut self, capacity: usize) -> Self {
+        self.engine.set_cache_capacity(capacity);
+        self.conn.set_prepared_statement_cache_capacity(capacity);
+        self
+    }
+
+    /// Set how rows that fail to be mapped are handled, [RowErrorPolicy::FailFast] by default.
+    pub fn with_row_error_policy(mut self, policy: RowErrorPolicy) -> Self {
+        self.engine.set_row_error_policy(policy);
+        self
+    }
+
+    /// Run `interceptor` around every statement, after the interceptors added before.
+    pub fn with_interceptor<I: Interceptor + 'static>(mut self, interceptor: I) -> Self {
+        self.engine.add_interceptor(Box::new(interceptor));
+        self
+    }
+
+    /// Hit/miss statistics of the rendered SQL cache, or [None] if caching is disabled.
+    pub fn cache_stats(&self) -> Option<CacheStats> {
+        self.engine.cache_stats()
+    }
+
+    /// Run `f` inside a transaction. The transaction is committed if `f` returns `Ok`, and rolled
+    /// back if `f` returns `Err` or panics. See [Transaction] for nesting transactions with savepoints.
+    pub fn transaction<F, R>(&mut self, f: F) -> Result<R>
+        where
+            F: FnOnce(&Transaction<'_>) -> Result<R>,
+    {
+        Transaction::run(&self.conn, &self.engine, f)
+    }
+
+    /// Render every template for every combination of the `[:param]` conditions it tests (or a
+    /// sample of them if there are too many) and prepare the resulting SQL against the schema of
+    /// this repository, e.g. at startup or in a test. All failures are reported with
+    /// [Error::TemplateValidationErrors]. This includes combinations that are invalid but never
+    /// used, e.g. an `UPDATE` without any column to set, which callers may want to filter out.
+    ///
+    /// The conditions of filter fields (`%>`) depend on the query type, so templates are only
+    /// validated with an empty `{{filters}}`. Wrong filter columns or operators are not detected.
+    pub fn validate(&self) -> Result<()> {
+        self.engine.validate(&self.conn)
+    }
+
+    /// Apply the pending `migrations`, see [Migrations::apply].
+    pub fn migrate(&mut self, migrations: &Migrations) -> Result<Vec<u32>> {
+        migrations.apply(&mut self.conn)
     }
 }
 
@@ -65,19 +416,25 @@ impl<'reg> DynamicSqlExecutor for Repository<'reg> {
             P: DynamicQueryParameters,
             F: FnMut(&Row<'_>) -> rusqlite::Result<T>,
     {
-        let q = self.handlebars.render(template.name(), &params.for_render())?;
-        log::debug!("{}", &q);
-        let mut stmt = self.conn.prepare(&q)?;
-        let result = stmt
-            .query_map(params.for_execution().as_slice(), f)?
-            .flat_map(|mapped_row| match mapped_row {
-                Ok(inst) => Some(inst),
-                Err(err) => {
-                    log::warn!("failed to map row, the error is: {}", err);
-                    None
-                }
-            });
-        Ok(Vec::from_iter(result))
+        self.engine.query(&self.conn, template, params, f)
+    }
+
+    fn query_derived<S, P, D, F, T, C, R>(
+        &self,
+        template: &S,
+        params: P,
+        derive: D,
+        f: F,
+        consume: C,
+    ) -> Result<R>
+        where
+            S: SqlTemplate,
+            P: DynamicQueryParameters,
+            D: FnOnce(String) -> String,
+            F: FnMut(&Row<'_>) -> rusqlite::Result<T>,
+            C: FnOnce(QueryRows<'_, F>) -> Result<R>,
+    {
+        self.engine.query_derived(&self.conn, template, params, derive, f, consume)
     }
 
     fn execute<S, P>(&self, template: &S, params: P) -> Result<usize>
@@ -85,26 +442,71 @@ impl<'reg> DynamicSqlExecutor for Repository<'reg> {
             S: SqlTemplate,
             P: DynamicQueryParameters,
     {
-        let q = self.handlebars.render(template.name(), &params.for_render())?;
-        log::debug!("{}", &q);
-        let mut stmt = self.conn.prepare(&q)?;
-        let result = stmt.execute(params.for_execution().as_slice())?;
-        Ok(result)
+        self.engine.execute(&self.conn, template, params)
+    }
+
+    fn execute_returning_rowid<S, P>(&self, template: &S, params: P) -> Result<i64>
+        where
+            S: SqlTemplate,
+            P: DynamicQueryParameters,
+    {
+        self.engine.execute_returning_rowid(&self.conn, template, params)
+    }
+
+    fn render<S, P>(&self, template: &S, params: P) -> Result<RenderedQuery>
+        where
+            S: SqlTemplate,
+            P: DynamicQueryParameters,
+    {
+        self.engine.render_query(template, params)
+    }
+
+    fn explain<S, P>(&self, template: &S, params: P) -> Result<QueryPlan>
+        where
+            S: SqlTemplate,
+            P: DynamicQueryParameters,
+    {
+        self.engine.explain(&self.conn, template, params)
+    }
+
+    fn execute_returning_one<S, P, F, T>(&self, template: &S, params: P, f: F) -> Result<T>
+        where
+            S: SqlTemplate,
+            P: DynamicQueryParameters,
+            F: FnMut(&Row<'_>) -> rusqlite::Result<T>,
+    {
+        Transaction::run_savepoint(&self.conn, &self.engine, |sp| sp.query_one(template, params, f))
+    }
+
+    fn execute_batch_chunked<S, P, I>(
+        &self,
+        template: &S,
+        params: I,
+        chunk_size: usize,
+    ) -> Result<Vec<usize>>
+        where
+            S: SqlTemplate,
+            P: DynamicQueryParameters,
+            I: IntoIterator<Item = P>,
+    {
+        Transaction::batch(&self.conn, &self.engine, 0, template, params, chunk_size)
     }
 }
 
 #[cfg(test)]
-mod dog {
+pub(crate) mod dog {
+    use std::iter::FromIterator;
     use std::path::Path;
 
-    use rusqlite::params;
+    use rusqlite::blob::ZeroBlob;
+    use rusqlite::{params, ToSql};
 
     use crate::new_query_type;
     use crate::dynamic_sql::{DynamicParam, ToSqlSegment};
 
     use super::*;
 
-    const DDL: &str = "CREATE TABLE IF NOT EXISTS dogs(\
+    pub const DDL: &str = "CREATE TABLE IF NOT EXISTS dogs(\
                 name TEXT PRIMARY KEY,\
                 color TEXT,\
                 weight REAL\
@@ -133,9 +535,15 @@ mod dog {
         {{#if [:q_color]}} AND color=:q_color{{/if}}\
         {{#if [:weight_upper]}} AND weight<=:weight_upper{{/if}}\
         {{#if [:weight_lower]}} AND weight>=:weight_lower{{/if}}\
+        {{#if [:q_names]}} AND {{#in [:q_names]}}name IN (:q_names){{/in}}{{/if}}\
         {{/where}}",
     );
 
+    pub const Q_DOGS_INSERT_RETURNING: (&str, &str) = (
+        "Q_DOGS_INSERT_RETURNING",
+        "INSERT INTO dogs(name, color, weight) VALUES(:name, :color, :weight) RETURNING *",
+    );
+
     pub const Q_DOGS_DELETE: (&str, &str) = ("Q_DOGS_DELETE", "DELETE FROM dogs WHERE name=?");
 
     pub const Q_DOGS_SELECT: (&str, &str) =
@@ -151,13 +559,56 @@ mod dog {
     new_query_type!(
         (DogQuery, 'q,
         -> q_name: &'q str, q_color: &'q str,
-            weight_upper: f32, weight_lower: f32,)
+            weight_upper: f32, weight_lower: f32,
+        *> q_names: &'q str,)
 
         (DogUpdate, 'q,
         -> color: &'q str, weight: f32,
         &> query: DogQuery<'q>,)
+
+        (DogInsert, 'q,
+        -> name: &'q str, color: &'q str, weight: f32,)
     );
 
+    /// Inserts a dog whose color is a zero-filled blob, which is bound without a value of its own.
+    pub struct BlobDogInsert(pub &'static str, pub ZeroBlob);
+
+    impl DynamicQueryParameters for BlobDogInsert {
+        fn for_render(&self) -> HashMap<&'static str, String> {
+            HashMap::new()
+        }
+
+        fn for_execution(&self) -> Vec<DynamicParam<'_>> {
+            vec![(":name", &self.0 as &dyn ToSql), (":color", &self.1 as &dyn ToSql)]
+        }
+    }
+
+    /// An in-memory [Repository] with the schema initialized and all dog templates registered.
+    pub(crate) fn memory_repository() -> Repository<'static> {
+        let repo = Repository::new(
+            ":memory:",
+            &[
+                Q_DOGS_INSERT,
+                Q_DOGS_INSERT_RETURNING,
+                Q_DOGS_DELETE,
+                Q_DOGS_SELECT,
+                Q_DOGS_UPDATE,
+                Q_DOGS_WHERE,
+            ],
+        )
+        .unwrap();
+        repo.conn.execute_batch(DDL).unwrap();
+        repo
+    }
+
+    pub(crate) fn dog_insert(name: &str) -> DogInsert<'_> {
+        DogInsert {
+            name: Some(name),
+            color: Some("white"),
+            weight: Some(20.5),
+        }
+    }
+
     pub struct DogStore<'reg>(Repository<'reg>);
 
     impl<'reg> DogStore<'reg> {
@@ -203,9 +654,15 @@ mod dog {
 
 #[cfg(test)]
 mod test {
+    use std::iter::FromIterator;
     use std::{env, fs};
 
+    use rusqlite::blob::ZeroBlob;
+    use rusqlite::types::Value;
+    use rusqlite::ToSql;
+
     use crate::new_query_type;
+    use crate::dynamic_sql::builder::RepositoryBuilder;
     use crate::dynamic_sql::{DynamicParam, ToSqlSegment};
 
     use super::dog::*;
@@ -213,7 +670,7 @@ mod test {
 
     #[test]
     fn test_update_query_template() {
-        let handlebars = get_template_engine();
+        let repo = memory_repository();
         for (update, q) in vec![
             (
                 DogUpdate {
@@ -233,16 +690,13 @@ mod test {
         ]
             .into_iter()
         {
-            assert_eq!(
-                q,
-                handlebars.render(Q_DOGS_UPDATE.name(), &update.for_render()).unwrap(),
-            );
+            assert_eq!(q, repo.render(&Q_DOGS_UPDATE, update).unwrap().sql);
         }
     }
 
     #[test]
     fn test_select_query_template() {
-        let handlebars = get_template_engine();
+        let repo = memory_repository();
         for (params, q) in vec![
             (
                 DogQuery {
@@ -265,6 +719,7 @@ mod test {
                     q_color: Some("white"),
                     weight_upper: Some(50.5),
                     weight_lower: Some(10.5),
+                    q_names: None,
                 },
                 "SELECT * FROM dogs WHERE name LIKE '%' || :q_name || '%' AND color=:q_color \
                 AND weight<=:weight_upper AND weight>=:weight_lower",
@@ -276,13 +731,81 @@ mod test {
                 "SELECT * FROM dogs",
             ),
         ] {
-            assert_eq!(
-                q,
-                handlebars.render(Q_DOGS_SELECT.name(), &params.for_render()).unwrap()
-            )
+            assert_eq!(q, repo.render(&Q_DOGS_SELECT, params).unwrap().sql)
         }
     }
 
+    #[test]
+    fn test_render() {
+        let repo = memory_repository();
+        let query = DogQuery {
+            q_name: Some("Je"),
+            weight_lower: Some(10.5),
+            ..Default::default()
+        };
+        let rendered = repo.render(&Q_DOGS_SELECT, query).unwrap();
+        assert_eq!(
+            "SELECT * FROM dogs WHERE name LIKE '%' || :q_name || '%' AND weight>=:weight_lower",
+            rendered.sql
+        );
+        assert_eq!(
+            vec![
+                (":q_name".to_string(), Value::Text("Je".to_string())),
+                (":weight_lower".to_string(), Value::Real(10.5)),
+            ],
+            rendered.params
+        );
+        assert_eq!(Some(&Value::Real(10.5)), rendered.param(":weight_lower"));
+
+        let rendered = repo.render(&Q_DOGS_INSERT, BlobDogInsert("Jeff", ZeroBlob(3))).unwrap();
+        assert_eq!(Some(&Value::Blob(vec![0; 3])), rendered.param(":color"));
+    }
+
+    #[test]
+    fn test_in_list() {
+        let repo = memory_repository();
+        for name in &["Jeff", "Bob's", "Tom, Jr."] {
+            repo.execute(&Q_DOGS_INSERT, dog_insert(name)).unwrap();
+        }
+        let query = |names: Vec<&'static str>| DogQuery {
+            q_names: Some(names),
+            ..Default::default()
+        };
+
+        let rendered = repo.render(&Q_DOGS_SELECT, query(vec!["Bob's", "Tom, Jr."])).unwrap();
+        assert_eq!("SELECT * FROM dogs WHERE name IN (:q_names_1, :q_names_2)", rendered.sql);
+        assert_eq!(Some(&Value::Text("Tom, Jr.".to_string())), rendered.param(":q_names_2"));
+
+        let names = |q| {
+            repo.query(&Q_DOGS_SELECT, q, |row| row.get::<_, String>("name"))
+                .unwrap()
+        };
+        assert_eq!(vec!["Bob's", "Tom, Jr."], names(query(vec!["Bob's", "Tom, Jr.", "x' OR 1=1 --"])));
+        assert!(names(query(vec![])).is_empty());
+        assert_eq!("SELECT * FROM dogs WHERE 0 = 1", repo.render(&Q_DOGS_SELECT, query(vec![])).unwrap().sql);
+
+        let update = DogUpdate {
+            color: Some("black"),
+            query: Some(query(vec!["Jeff"])),
+            ..Default::default()
+        };
+        assert_eq!(1, repo.execute(&Q_DOGS_UPDATE, update).unwrap());
+        assert_eq!(1, repo.count(&Q_DOGS_SELECT, query(vec!["Jeff", "Tom"])).unwrap());
+
+        new_query_type!(
+            (CollidingDogQuery, 'q,
+            -> q_names_1: &'q str,
+            &> query: DogQuery<'q>,)
+        );
+        let colliding = CollidingDogQuery {
+            q_names_1: Some("Tom"),
+            query: Some(query(vec!["Jeff"])),
+        };
+        let result = repo.query(&Q_DOGS_SELECT, &colliding, |row| row.get::<_, String>(0));
+        assert!(matches!(result, Err(crate::Error::ListParamCollision(ref p)) if p == ":q_names_1"));
+        assert!(repo.render(&Q_DOGS_SELECT, &colliding).is_err());
+    }
+
     #[test]
     fn test_movie_store() {
         let file = env::temp_dir().join("dog_store_test");
@@ -324,6 +847,156 @@ mod test {
         assert!(query_result.is_empty());
     }
 
+    #[test]
+    fn test_query_iter() {
+        let repo = memory_repository();
+        for name in &["Jeff", "Bob", "Tom"] {
+            repo.execute(&Q_DOGS_INSERT, dog_insert(name)).unwrap();
+        }
+        let name = |row: &Row<'_>| row.get::<_, String>("name");
+        let first_two = repo
+            .query_iter(&Q_DOGS_SELECT, DogQuery::default(), name, |rows| {
+                Ok(rows.take(2).collect::<rusqlite::Result<Vec<_>>>()?)
+            })
+            .unwrap();
+        assert_eq!(vec!["Jeff", "Bob"], first_two);
+
+        let mut names = vec![];
+        let count = repo
+            .query_for_each(&Q_DOGS_SELECT, DogQuery::default(), name, |n| {
+                names.push(n);
+                Ok(())
+            })
+            .unwrap();
+        assert_eq!(3, count);
+        assert_eq!(vec!["Jeff", "Bob", "Tom"], names);
+    }
+
+    #[test]
+    fn test_single_row_queries() {
+        let repo = memory_repository();
+        for name in &["Jeff", "Bob"] {
+            repo.execute(&Q_DOGS_INSERT, dog_insert(name)).unwrap();
+        }
+        let name = |row: &Row<'_>| row.get::<_, String>("name");
+        let query = |q_name| DogQuery {
+            q_name: Some(q_name),
+            ..Default::default()
+        };
+
+        assert_eq!("Jeff", repo.query_one(&Q_DOGS_SELECT, query("Je"), name).unwrap());
+        assert!(matches!(repo.query_one(&Q_DOGS_SELECT, query("Tom"), name), Err(Error::NoRows)));
+        assert!(matches!(
+            repo.query_one(&Q_DOGS_SELECT, DogQuery::default(), name),
+            Err(Error::TooManyRows)
+        ));
+        assert_eq!(None, repo.query_optional(&Q_DOGS_SELECT, query("Tom"), name).unwrap());
+        assert_eq!(
+            Some("Bob".to_string()),
+            repo.query_optional(&Q_DOGS_SELECT, query("Bo"), name).unwrap()
+        );
+
+        assert!(repo.exists(&Q_DOGS_SELECT, query("Bo")).unwrap());
+        assert!(!repo.exists(&Q_DOGS_SELECT, query("Tom")).unwrap());
+        assert_eq!(2, repo.count(&Q_DOGS_SELECT, DogQuery::default()).unwrap());
+        assert_eq!(1, repo.count(&Q_DOGS_SELECT, query("Je")).unwrap());
+    }
+
+    #[test]
+    fn test_execute_batch() {
+        let repo = memory_repository();
+        let dogs = ["Jeff", "Bob", "Tom"];
+        let counts = repo
+            .execute_batch(&Q_DOGS_INSERT, dogs.iter().map(|name| dog_insert(name)))
+            .unwrap();
+        assert_eq!(vec![1, 1, 1], counts);
+
+        let result = repo.execute_batch(&Q_DOGS_INSERT, vec![dog_insert("Max"), dog_insert("Bob")]);
+        assert!(matches!(result, Err(Error::BatchError { index: 1, .. })));
+        assert_eq!(3, repo.count(&Q_DOGS_SELECT, DogQuery::default()).unwrap());
+
+        let dogs = ["Max", "Rex", "Leo", "Jeff", "Ace"];
+        let result =
+            repo.execute_batch_chunked(&Q_DOGS_INSERT, dogs.iter().map(|name| dog_insert(name)), 2);
+        assert!(matches!(result, Err(Error::BatchError { index: 3, .. })));
+        // only the first chunk is committed
+        assert_eq!(5, repo.count(&Q_DOGS_SELECT, DogQuery::default()).unwrap());
+    }
+
+    #[test]
+    fn test_execute_returning() {
+        let repo = memory_repository();
+        assert_eq!(1, repo.execute_returning_rowid(&Q_DOGS_INSERT, dog_insert("Jeff")).unwrap());
+        assert_eq!(2, repo.execute_returning_rowid(&Q_DOGS_INSERT, dog_insert("Bob")).unwrap());
+
+        let dog = repo
+            .execute_returning_one(&Q_DOGS_INSERT_RETURNING, dog_insert("Tom"), |row| {
+                Ok(Dog {
+                    name: row.get("name")?,
+                    color: row.get("color")?,
+                    weight: row.get("weight")?,
+                })
+            })
+            .unwrap();
+        assert_eq!(
+            Dog {
+                name: "Tom".to_string(),
+                color: "white".to_string(),
+                weight: 20.5,
+            },
+            dog
+        );
+        assert_eq!(3, repo.count(&Q_DOGS_SELECT, DogQuery::default()).unwrap());
+    }
+
+    #[test]
+    fn test_execute_returning_one_rolls_back() {
+        const Q_DOGS_UPDATE_RETURNING: (&str, &str) = (
+            "Q_DOGS_UPDATE_RETURNING",
+            "UPDATE dogs SET weight=:weight{{> Q_DOGS_WHERE }} RETURNING name",
+        );
+        let mut repo = RepositoryBuilder::memory()
+            .templates(&[Q_DOGS_INSERT, Q_DOGS_SELECT, Q_DOGS_WHERE, Q_DOGS_UPDATE_RETURNING])
+            .init(DDL)
+            .build()
+            .unwrap();
+        repo.execute_batch(&Q_DOGS_INSERT, vec![dog_insert("Jeff"), dog_insert("Bob")])
+            .unwrap();
+        let update = |q_name| DogUpdate {
+            weight: Some(1.0),
+            query: Some(DogQuery {
+                q_name,
+                ..Default::default()
+            }),
+            ..Default::default()
+        };
+        let weights = |repo: &Repository| {
+            repo.query(&Q_DOGS_SELECT, DogQuery::default(), |row| row.get::<_, f32>("weight"))
+                .unwrap()
+        };
+
+        let name = |row: &Row<'_>| row.get::<_, String>(0);
+        let result = repo.execute_returning_one(&Q_DOGS_UPDATE_RETURNING, update(None), name);
+        assert!(matches!(result, Err(Error::TooManyRows)));
+        assert_eq!(vec![20.5, 20.5], weights(&repo));
+        let result = repo.transaction(|tx| {
+            let result = tx.execute_returning_one(&Q_DOGS_UPDATE_RETURNING, update(None), name);
+            assert!(matches!(result, Err(Error::TooManyRows)));
+            tx.execute_returning_one(&Q_DOGS_UPDATE_RETURNING, update(Some("Je")), name)
+        });
+        assert_eq!("Jeff", result.unwrap());
+        assert_eq!(vec![1.0, 20.5], weights(&repo));
+    }
+
+    #[test]
+    fn test_new_without_builder_checks() {
+        // unlike RepositoryBuilder, later templates replace earlier ones and missing partials render
+        // nothing
+        let templates = [("names", "SELECT 1"), ("names", "SELECT name FROM dogs{{> missing}}")];
+        let repo = Repository::new(":memory:", &templates).unwrap();
+        assert_eq!("SELECT name FROM dogs", repo.render(&templates[1], DogQuery::default()).unwrap().sql);
+    }
+
     #[test]
     fn test_new_query_type() {
         new_query_type!(
@@ -355,19 +1028,21 @@ mod test {
         assert_eq!(Some("aaa"), u.query.as_ref().map(|it| it.q_name).flatten());
     }
 
-    fn get_template_engine() -> Handlebars<'static> {
-        let mut handlebars = Handlebars::new();
-        for t in vec![Q_DOGS_INSERT, Q_DOGS_DELETE, Q_DOGS_SELECT, Q_DOGS_UPDATE] {
-            handlebars
-                .register_template_string(t.name(), t.sql())
-                .unwrap();
-        }
-        for t in vec![Q_DOGS_WHERE] {
-            handlebars.register_partial(t.name(), t.sql()).unwrap();
-        }
-        for (name, helper) in sql_helpers() {
-            handlebars.register_helper(name, helper);
-        }
-        handlebars
+    #[test]
+    fn test_nested_query_type_without_lifetime() {
+        new_query_type!(
+            (IdQuery, -> q_id: i64,)
+
+            (NamedIdQuery, 'q,
+            -> name: &'q str,
+            &> ids: IdQuery, names: DogQuery<'q>,)
+        );
+
+        let json = r#"{"name": "Jeff", "q_id": 1, "q_color": "white"}"#;
+        let q: NamedIdQuery<'_> = serde_json::from_str(json).unwrap();
+        assert_eq!(Some("Jeff"), q.name);
+        assert_eq!(Some(1), q.ids.as_ref().and_then(|ids| ids.q_id));
+        assert_eq!(Some("white"), q.names.as_ref().and_then(|names| names.q_color));
+        assert_eq!(3, q.for_execution().len());
     }
 }
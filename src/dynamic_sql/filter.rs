@@ -0,0 +1,276 @@
+use rusqlite::ToSql;
+
+use crate::dynamic_sql::query::{list_param_name, ToSqlSegment};
+
+/// The key of [DynamicQueryParameters::for_render](crate::dynamic_sql::DynamicQueryParameters::for_render)
+/// holding the conditions of the filter fields (`%>`) that are present, which are rendered by the
+/// `filters` helper.
+#[doc(hidden)]
+pub const FILTERS: &str = "%filters";
+
+/// A filter field (`%>`) of a query type that is present, see [new_query_type](crate::new_query_type).
+/// Its condition is only built when rendering.
+#[doc(hidden)]
+pub struct Filter<'a> {
+    pub param: &'static str,
+    column: &'static str,
+    condition: Condition<'a>,
+}
+
+enum Condition<'a> {
+    Compare(&'static str, &'a dyn ToSql),
+    Contains(&'a dyn ToSql),
+    Between(&'a dyn ToSql, &'a dyn ToSql),
+    In(Vec<&'a dyn ToSql>),
+    IsNull(bool),
+}
+
+#[doc(hidden)]
+pub enum FilterBind<'a> {
+    One(&'a dyn ToSql),
+    /// Bound as a list parameter, see [DynamicQueryParameters::for_lists](crate::dynamic_sql::DynamicQueryParameters::for_lists).
+    List(Vec<&'a dyn ToSql>),
+    Nothing,
+}
+
+impl<'a> Filter<'a> {
+    /// `column op :param` for the comparison operators and `LIKE`.
+    pub fn compare<T: ToSql>(column: &'static str, op: &'static str, param: &'static str, value: &'a T) -> Self {
+        Filter {
+            param,
+            column,
+            condition: Condition::Compare(op, value),
+        }
+    }
+
+    pub fn contains<T: ToSql>(column: &'static str, param: &'static str, value: &'a T) -> Self {
+        Filter {
+            param,
+            column,
+            condition: Condition::Contains(value),
+        }
+    }
+
+    pub fn between<T: ToSql>(column: &'static str, param: &'static str, value: &'a (T, T)) -> Self {
+        Filter {
+            param,
+            column,
+            condition: Condition::Between(&value.0, &value.1),
+        }
+    }
+
+    /// Like the `in` helper, an empty list is a condition that is never true.
+    pub fn in_list<T: ToSql>(column: &'static str, param: &'static str, values: &'a [T]) -> Self {
+        Filter {
+            param,
+            column,
+            condition: Condition::In(values.iter().map(|v| v as &dyn ToSql).collect()),
+        }
+    }
+
+    /// `column IS NULL` if `value` is `true`, `column IS NOT NULL` otherwise.
+    pub fn is_null(column: &'static str, param: &'static str, value: &bool) -> Self {
+        Filter {
+            param,
+            column,
+            condition: Condition::IsNull(*value),
+        }
+    }
+
+    /// The condition, e.g. `weight <= :weight_upper`.
+    pub fn sql(&self) -> String {
+        let (column, param) = (self.column, self.param);
+        match &self.condition {
+            Condition::Compare(op, _) => format!("{} {} {}", column, op, param),
+            Condition::Contains(_) => format!("{} LIKE '%' || {} || '%'", column, param),
+            Condition::Between(..) => format!(
+                "{} BETWEEN {} AND {}",
+                column,
+                list_param_name(param, 0),
+                list_param_name(param, 1)
+            ),
+            Condition::In(values) if values.is_empty() => "0 = 1".to_string(),
+            Condition::In(values) => {
+                let params = (0..values.len())
+                    .map(|i| list_param_name(param, i))
+                    .collect::<Vec<_>>();
+                format!("{} IN ({})", column, params.join(", "))
+            }
+            Condition::IsNull(null) => format!("{} IS {}NULL", column, if *null { "" } else { "NOT " }),
+        }
+    }
+
+    /// The value of the parameter for rendering.
+    pub fn shape(&self) -> String {
+        match &self.condition {
+            Condition::Compare(_, value) | Condition::Contains(value) => value.to_sql_segment().unwrap_or_default(),
+            Condition::Between(..) => "2".to_string(),
+            Condition::In(values) => values.len().to_string(),
+            Condition::IsNull(null) => null.to_string(),
+        }
+    }
+
+    pub fn bind(self) -> FilterBind<'a> {
+        match self.condition {
+            Condition::Compare(_, value) | Condition::Contains(value) => FilterBind::One(value),
+            Condition::Between(a, b) => FilterBind::List(vec![a, b]),
+            Condition::In(values) => FilterBind::List(values),
+            Condition::IsNull(_) => FilterBind::Nothing,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::iter::FromIterator;
+
+    use rusqlite::types::Value;
+    use rusqlite::ToSql;
+
+    use crate::dynamic_sql::executor::dog::*;
+    use crate::dynamic_sql::{
+        DynamicParam, DynamicQueryParameters, DynamicSqlExecutor, Repository, RepositoryBuilder, ToSqlSegment,
+    };
+    use crate::new_query_type;
+
+    const Q_FILTER_DOGS: (&str, &str) = ("Q_FILTER_DOGS", "SELECT name FROM dogs{{filters}} ORDER BY name");
+
+    const Q_FILTER_DOGS_CUSTOM: (&str, &str) = (
+        "Q_FILTER_DOGS_CUSTOM",
+        "SELECT name FROM dogs{{#filters}}\
+        {{#if [:initial]}} AND substr(name, 1, 1) = :initial{{/if}}\
+        {{/filters}} ORDER BY name",
+    );
+
+    new_query_type!(
+        (DogFilter, 'q,
+        %> name: &'q str = name eq, not_name: &'q str = name ne,
+            lighter: f32 = weight lt, at_most: f32 = weight le,
+            heavier: f32 = weight gt, at_least: f32 = weight ge,
+            name_like: &'q str = name like, name_contains: &'q str = name contains,
+            weight_range: (f32, f32) = weight between, names: Vec<&'q str> = name in,
+            colorless: bool = color is_null,)
+
+        (AliasDogFilter, 'q,
+        %> name: &'q str = "d.name" eq, names: Vec<&'q str> = "d.name" in,)
+
+        (CustomDogFilter, 'q,
+        -> initial: &'q str,
+        &> filter: DogFilter<'q>,)
+    );
+
+    const Q_FILTER_DOGS_ALIAS: (&str, &str) = ("Q_FILTER_DOGS_ALIAS", "SELECT d.name FROM dogs d{{filters}}");
+
+    fn repository() -> Repository<'static> {
+        RepositoryBuilder::memory()
+            .templates(&[Q_DOGS_INSERT, Q_FILTER_DOGS, Q_FILTER_DOGS_CUSTOM, Q_FILTER_DOGS_ALIAS])
+            .init(DDL)
+            .build()
+            .unwrap()
+    }
+
+    fn names(filter: DogFilter<'_>) -> Vec<String> {
+        let repo = repository();
+        for (name, color, weight) in &[("Bob", Some("yellow"), 10.0), ("Jeff", Some("white"), 20.5), ("Tom", None, 30.0)] {
+            let dog = DogInsert {
+                name: Some(name),
+                color: *color,
+                weight: Some(*weight),
+            };
+            repo.execute(&Q_DOGS_INSERT, dog).unwrap();
+        }
+        repo.query(&Q_FILTER_DOGS, filter, |row| row.get(0)).unwrap()
+    }
+
+    #[test]
+    fn test_filter_operators() {
+        let all = || DogFilter::default();
+        assert_eq!(vec!["Bob", "Jeff", "Tom"], names(all()));
+        assert_eq!(vec!["Jeff"], names(DogFilter { name: Some("Jeff"), ..all() }));
+        assert_eq!(vec!["Bob", "Tom"], names(DogFilter { not_name: Some("Jeff"), ..all() }));
+        assert_eq!(vec!["Bob"], names(DogFilter { lighter: Some(20.5), ..all() }));
+        assert_eq!(vec!["Bob", "Jeff"], names(DogFilter { at_most: Some(20.5), ..all() }));
+        assert_eq!(vec!["Tom"], names(DogFilter { heavier: Some(20.5), ..all() }));
+        assert_eq!(vec!["Jeff", "Tom"], names(DogFilter { at_least: Some(20.5), ..all() }));
+        assert_eq!(vec!["Jeff"], names(DogFilter { name_like: Some("J%"), ..all() }));
+        assert_eq!(vec!["Bob", "Tom"], names(DogFilter { name_contains: Some("o"), ..all() }));
+        assert_eq!(vec!["Jeff", "Tom"], names(DogFilter { weight_range: Some((15.0, 30.0)), ..all() }));
+        assert_eq!(vec!["Bob", "Tom"], names(DogFilter { names: Some(vec!["Tom", "Bob"]), ..all() }));
+        assert!(names(DogFilter { names: Some(vec![]), ..all() }).is_empty());
+        assert_eq!(vec!["Tom"], names(DogFilter { colorless: Some(true), ..all() }));
+        assert_eq!(vec!["Bob", "Jeff"], names(DogFilter { colorless: Some(false), ..all() }));
+        let filter = DogFilter {
+            name_contains: Some("o"),
+            at_least: Some(20.0),
+            ..all()
+        };
+        assert_eq!(vec!["Tom"], names(filter));
+    }
+
+    #[test]
+    fn test_filters_helper() {
+        let repo = repository();
+        let filter = DogFilter {
+            at_least: Some(20.0),
+            weight_range: Some((10.0, 30.0)),
+            colorless: Some(false),
+            ..Default::default()
+        };
+        let rendered = repo.render(&Q_FILTER_DOGS, &filter).unwrap();
+        assert_eq!(
+            "SELECT name FROM dogs WHERE weight >= :at_least AND weight BETWEEN :weight_range_1 \
+            AND :weight_range_2 AND color IS NOT NULL ORDER BY name",
+            rendered.sql
+        );
+        assert_eq!(
+            vec![
+                (":at_least".to_string(), Value::Real(20.0)),
+                (":weight_range_1".to_string(), Value::Real(10.0)),
+                (":weight_range_2".to_string(), Value::Real(30.0)),
+            ],
+            rendered.params
+        );
+        assert_eq!(
+            "SELECT name FROM dogs ORDER BY name",
+            repo.render(&Q_FILTER_DOGS, DogFilter::default()).unwrap().sql
+        );
+
+        let custom = |initial, filter| CustomDogFilter {
+            initial,
+            filter: Some(filter),
+        };
+        assert_eq!(
+            "SELECT name FROM dogs WHERE weight >= :at_least AND weight BETWEEN :weight_range_1 \
+            AND :weight_range_2 AND color IS NOT NULL AND substr(name, 1, 1) = :initial ORDER BY name",
+            repo.render(&Q_FILTER_DOGS_CUSTOM, custom(Some("J"), filter)).unwrap().sql
+        );
+        assert_eq!(
+            "SELECT name FROM dogs WHERE substr(name, 1, 1) = :initial ORDER BY name",
+            repo.render(&Q_FILTER_DOGS_CUSTOM, custom(Some("J"), DogFilter::default()))
+                .unwrap()
+                .sql
+        );
+        assert_eq!(
+            "SELECT name FROM dogs ORDER BY name",
+            repo.render(&Q_FILTER_DOGS_CUSTOM, custom(None, DogFilter::default()))
+                .unwrap()
+                .sql
+        );
+    }
+
+    #[test]
+    fn test_qualified_columns() {
+        let repo = repository();
+        repo.execute(&Q_DOGS_INSERT, dog_insert("Jeff")).unwrap();
+        let filter = AliasDogFilter {
+            name: Some("Jeff"),
+            names: Some(vec!["Jeff", "Tom"]),
+        };
+        assert_eq!(
+            "SELECT d.name FROM dogs d WHERE d.name = :name AND d.name IN (:names_1, :names_2)",
+            repo.render(&Q_FILTER_DOGS_ALIAS, &filter).unwrap().sql
+        );
+        let names: Vec<String> = repo.query(&Q_FILTER_DOGS_ALIAS, &filter, |row| row.get(0)).unwrap();
+        assert_eq!(vec!["Jeff"], names);
+    }
+}
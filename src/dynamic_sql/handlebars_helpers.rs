@@ -1,15 +1,23 @@
 use handlebars::{
-    Context, Handlebars, Helper, HelperDef, HelperResult, Output, RenderContext, RenderError,
-    Renderable,
+    Context, Handlebars, Helper, HelperDef, HelperResult, JsonValue as Json, Output, RenderContext,
+    RenderError, Renderable,
 };
-use itertools::Itertools;
 
+use crate::dynamic_sql::filter::FILTERS;
+use crate::dynamic_sql::query::list_param_name;
+
+/// The helpers that templates are rendered with: `set`, `where`, `trim`, `in` and `filters`.
+///
+/// Note that `in` takes a list parameter, e.g. `{{#in [:ids]}}id IN (:ids){{/in}}`, and binds its
+/// values. Its former form with a comma separated string, `{{#in "a,b,c"}}IN (:VALUES){{/in}}`,
+/// fails rendering.
 pub fn sql_helpers() -> Vec<(&'static str, Box<dyn HelperDef + Send + Sync>)> {
     return vec![
         ("set", Box::new(set_block)),
         ("where", Box::new(where_block)),
         ("trim", Box::new(trim_block)),
-        ("in", Box::new(in_block))
+        ("in", Box::new(in_block)),
+        ("filters", Box::new(filters_block)),
     ];
 }
 
@@ -53,18 +61,52 @@ fn trim_block_helper<'reg, 'rc>(
                 "delimiter is required for trimming helpers",
             ))
             .unwrap();
-        let mut content = content.trim();
-        if !content.is_empty() {
-            content = content.trim_start_matches(token);
-            content = content.trim_end_matches(token);
-            for s in &[" ", prefix, " ", content] {
-                out.write(s)?;
-            }
+        write_trimmed(out, &content, prefix, token)?;
+    }
+    Ok(())
+}
+
+/// Write `content` without the leading and trailing `token` after `prefix`, or nothing if it is empty.
+fn write_trimmed(out: &mut dyn Output, content: &str, prefix: &str, token: &str) -> HelperResult {
+    let mut content = content.trim();
+    if !content.is_empty() {
+        content = content.trim_start_matches(token);
+        content = content.trim_end_matches(token);
+        for s in &[" ", prefix, " ", content] {
+            out.write(s)?;
         }
     }
     Ok(())
 }
 
+/// `{{filters}}` renders the `WHERE` clause of the filter fields (`%>`, see
+/// [new_query_type](crate::new_query_type)) that are present. As a block, the conditions of the
+/// block are added like in `{{#where}}`.
+fn filters_block<'reg, 'rc>(
+    h: &Helper<'reg, 'rc>,
+    r: &'reg Handlebars<'reg>,
+    ctx: &'rc Context,
+    rc: &mut RenderContext<'reg, 'rc>,
+    out: &mut dyn Output,
+) -> HelperResult {
+    let filters = ctx.data().get(FILTERS).and_then(|v| v.as_str()).unwrap_or("");
+    let custom = match h.template() {
+        Some(t) => t.renders(r, ctx, rc)?,
+        None => String::new(),
+    };
+    write_trimmed(out, &format!("{} {}", filters, custom.trim()), "WHERE", "AND ")
+}
+
+/// `{{#in [:ids]}}id IN (:ids){{/in}}` expands the list parameter `:ids` (see
+/// [DynamicQueryParameters::for_lists](crate::dynamic_sql::DynamicQueryParameters::for_lists))
+/// into one bind parameter per value, `id IN (:ids_1, :ids_2)`. Values are never written into the
+/// SQL. If the list is empty, the whole block renders as a predicate that is never true, because
+/// not every database accepts `IN ()`.
+///
+/// This replaces the former `{{#in "a,b,c"}}name IN (:VALUES){{/in}}`, which wrote the values of
+/// a comma separated string into the SQL and is no longer supported: declare a list parameter
+/// (`*>` of [new_query_type](crate::new_query_type)) instead, which fails rendering with a message
+/// saying so.
 fn in_block<'reg, 'rc>(
     h: &Helper<'reg, 'rc>,
     r: &'reg Handlebars<'reg>,
@@ -72,24 +114,55 @@ fn in_block<'reg, 'rc>(
     rc: &mut RenderContext<'reg, 'rc>,
     out: &mut dyn Output,
 ) -> HelperResult {
-    let values = h.param(0).ok_or(RenderError::new("values must be provided for `IN` block"))?
-        .value()
-        .as_str()
-        .ok_or(RenderError::new("values must be provided as a valid string"))?
-        .split(',')
-        .unique()
-        .map(|it| format!("'{}'", it))
-        .collect::<Vec<String>>();
-    let replacement = values.join(",");
-    let mut inner_content = h.template().ok_or(RenderError::new("content cannot be empty for `IN` block"))?
-        .renders(r, ctx, rc)?;
-    for placeholder in vec![":VALUES", ":values"] {
-        inner_content = inner_content.replace(placeholder, &replacement);
+    let param = h.param(0).ok_or(RenderError::new("a list parameter must be provided for `IN` block"))?;
+    if param.relative_path().is_none() && param.value().is_string() {
+        return Err(RenderError::new(
+            "`IN` block no longer takes a list of values with `:VALUES`, use a list parameter such as [:ids]",
+        ));
+    }
+    let name = param
+        .relative_path()
+        .map(|p| p.trim_start_matches('[').trim_end_matches(']'))
+        .filter(|p| p.starts_with(':'))
+        .ok_or(RenderError::new("values of `IN` block must be a list parameter such as [:ids]"))?;
+    let len = match param.value() {
+        Json::String(s) => s.parse::<usize>().ok(),
+        Json::Number(n) => n.as_u64().map(|n| n as usize),
+        _ => None,
     }
-    out.write(&inner_content)?;
+    .ok_or_else(|| RenderError::new(format!("`{}` is not a list parameter with values", name)))?;
+    if len == 0 {
+        out.write("0 = 1")?;
+        return Ok(());
+    }
+    let replacement = (0..len)
+        .map(|i| list_param_name(name, i))
+        .collect::<Vec<_>>()
+        .join(", ");
+    let inner_content = h.template().ok_or(RenderError::new("content cannot be empty for `IN` block"))?
+        .renders(r, ctx, rc)?;
+    out.write(&replace_param(&inner_content, name, &replacement))?;
     Ok(())
 }
 
+/// Replace the bind parameter `name` in `sql`, but not parameters it is a prefix of.
+fn replace_param(sql: &str, name: &str, replacement: &str) -> String {
+    let mut result = String::with_capacity(sql.len());
+    let mut rest = sql;
+    while let Some(i) = rest.find(name) {
+        let after = &rest[i + name.len()..];
+        result.push_str(&rest[..i]);
+        if after.starts_with(|c: char| c.is_alphanumeric() || c == '_') {
+            result.push_str(name);
+        } else {
+            result.push_str(replacement);
+        }
+        rest = after;
+    }
+    result.push_str(rest);
+    result
+}
+
 #[cfg(test)]
 mod test {
     use std::collections::HashMap;
@@ -133,12 +206,20 @@ mod test {
         handlebars
             .register_helper("in", Box::new(in_block));
         handlebars
-            .register_template_string("foo", r#"{{#in "a,b,c"}}IN (:VALUES){{/in}}"#)
+            .register_template_string("foo", r#"{{#in [:ids]}}id IN (:ids) AND :ids_x{{/in}}"#)
             .unwrap();
-        let result = handlebars.render(
+        let render = |len: &str| handlebars.render(
             "foo",
-            &1,
-        ).unwrap();
-        assert_eq!("IN ('a','b','c')", result.as_str());
+            &HashMap::<&str, &str>::from_iter(vec![(":ids", len)]),
+        );
+        assert_eq!("id IN (:ids_1, :ids_2, :ids_3) AND :ids_x", render("3").unwrap());
+        assert_eq!("0 = 1", render("0").unwrap());
+        assert!(render("'a','b'").is_err());
+
+        handlebars
+            .register_template_string("bar", r#"{{#in "a,b,c"}}IN (:VALUES){{/in}}"#)
+            .unwrap();
+        let err = handlebars.render("bar", &1).unwrap_err();
+        assert!(err.desc.contains("no longer"));
     }
-}
\ No newline at end of file
+}
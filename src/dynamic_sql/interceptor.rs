@@ -0,0 +1,206 @@
+use std::sync::Arc;
+use std::time::Duration;
+
+use rusqlite::types::Value;
+
+use crate::error::{Error, Result};
+
+/// Whether a statement is run for its rows or for its effect.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CallKind {
+    Query,
+    Execute,
+}
+
+/// A statement that is about to run, as seen by an [Interceptor].
+#[derive(Debug, Clone, PartialEq)]
+pub struct Call {
+    pub kind: CallKind,
+    /// The name of the template the SQL was rendered from.
+    pub template: String,
+    pub sql: String,
+    /// Named parameters such as `:name` in the order they are bound.
+    pub params: Vec<(String, Value)>,
+}
+
+/// What happened to a [Call].
+#[derive(Debug)]
+pub struct Outcome<'a> {
+    /// Time spent preparing and running the statement and consuming its rows.
+    pub elapsed: Duration,
+    /// The number of rows returned by a query or affected by a statement. For queries whose rows
+    /// are consumed lazily, e.g. by [query_iter](crate::dynamic_sql::DynamicSqlExecutor::query_iter),
+    /// these are the rows that were fetched.
+    pub result: std::result::Result<Option<usize>, &'a Error>,
+}
+
+/// Cross-cutting behavior around every statement run by an executor, e.g. timing, auditing or
+/// tenant checks. Interceptors are registered with
+/// [RepositoryBuilder::interceptor](crate::dynamic_sql::RepositoryBuilder::interceptor) or
+/// [Repository::with_interceptor](crate::dynamic_sql::Repository::with_interceptor).
+///
+/// [Interceptor::before] is called in the order the interceptors were registered and
+/// [Interceptor::after] in reverse order, like nested middleware.
+pub trait Interceptor: Send + Sync {
+    /// Called after rendering and before the statement is prepared. `call` may be rewritten, in
+    /// which case the rewritten SQL and parameters are used. Returning `Err`, e.g.
+    /// [Error::Rejected], stops the call before it runs and without calling [Interceptor::after].
+    fn before(&self, call: &mut Call) -> Result<()> {
+        let _ = call;
+        Ok(())
+    }
+
+    /// Called once the statement has run, whether it succeeded or not.
+    fn after(&self, call: &Call, outcome: &Outcome<'_>) {
+        let _ = (call, outcome);
+    }
+}
+
+impl<T: Interceptor + ?Sized> Interceptor for Arc<T> {
+    fn before(&self, call: &mut Call) -> Result<()> {
+        (**self).before(call)
+    }
+
+    fn after(&self, call: &Call, outcome: &Outcome<'_>) {
+        (**self).after(call, outcome)
+    }
+}
+
+impl<T: Interceptor + ?Sized> Interceptor for Box<T> {
+    fn before(&self, call: &mut Call) -> Result<()> {
+        (**self).before(call)
+    }
+
+    fn after(&self, call: &Call, outcome: &Outcome<'_>) {
+        (**self).after(call, outcome)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::Mutex;
+
+    use rusqlite::blob::ZeroBlob;
+
+    use crate::dynamic_sql::executor::dog::*;
+    use crate::dynamic_sql::DynamicSqlExecutor;
+
+    use super::*;
+
+    /// The kind, template, row count and success of a call.
+    type Record = (CallKind, String, Option<usize>, bool);
+
+    /// Records every call and its row count.
+    #[derive(Default)]
+    struct Recorder(Mutex<Vec<Record>>);
+
+    impl Interceptor for Recorder {
+        fn after(&self, call: &Call, outcome: &Outcome<'_>) {
+            let (rows, ok) = match outcome.result {
+                Ok(rows) => (rows, true),
+                Err(_) => (None, false),
+            };
+            self.0
+                .lock()
+                .unwrap()
+                .push((call.kind, call.template.clone(), rows, ok));
+        }
+    }
+
+    /// Rejects deleting and restricts every select to white dogs.
+    struct Tenant;
+
+    impl Interceptor for Tenant {
+        fn before(&self, call: &mut Call) -> Result<()> {
+            if call.sql.starts_with("DELETE") {
+                return Err(Error::Rejected("deleting is not allowed".to_string()));
+            }
+            if call.kind == CallKind::Query && call.template == "Q_DOGS_SELECT" {
+                call.sql = format!("SELECT * FROM ({}) WHERE color = :tenant_color", call.sql);
+                call.params
+                    .push((":tenant_color".to_string(), Value::Text("white".to_string())));
+            }
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_interceptors() {
+        let recorder = Arc::new(Recorder::default());
+        let repo = memory_repository()
+            .with_interceptor(recorder.clone())
+            .with_interceptor(Tenant);
+        repo.execute(&Q_DOGS_INSERT, dog_insert("Jeff")).unwrap();
+        let bob = DogInsert {
+            color: Some("yellow"),
+            ..dog_insert("Bob")
+        };
+        repo.execute(&Q_DOGS_INSERT, bob).unwrap();
+        assert!(repo.execute(&Q_DOGS_INSERT, dog_insert("Jeff")).is_err());
+
+        let names = repo
+            .query(&Q_DOGS_SELECT, DogQuery::default(), |row| row.get::<_, String>("name"))
+            .unwrap();
+        assert_eq!(vec!["Jeff"], names);
+
+        let result = repo.execute(&Q_DOGS_DELETE, DogQuery::default());
+        assert!(matches!(result, Err(Error::Rejected(_))));
+
+        assert_eq!(
+            vec![
+                (CallKind::Execute, "Q_DOGS_INSERT".to_string(), Some(1), true),
+                (CallKind::Execute, "Q_DOGS_INSERT".to_string(), Some(1), true),
+                (CallKind::Execute, "Q_DOGS_INSERT".to_string(), None, false),
+                (CallKind::Query, "Q_DOGS_SELECT".to_string(), Some(1), true),
+            ],
+            *recorder.0.lock().unwrap()
+        );
+    }
+
+    #[test]
+    fn test_row_counts() {
+        let recorder = Arc::new(Recorder::default());
+        let repo = memory_repository().with_interceptor(recorder.clone());
+        for name in &["Bob", "Jeff", "Tom"] {
+            repo.execute(&Q_DOGS_INSERT, dog_insert(name)).unwrap();
+        }
+        let jeff = DogQuery {
+            q_name: Some("Jeff"),
+            ..Default::default()
+        };
+        let name = |row: &rusqlite::Row<'_>| row.get::<_, String>("name");
+        assert_eq!("Jeff", repo.query_one(&Q_DOGS_SELECT, &jeff, name).unwrap());
+        assert_eq!(3, repo.count(&Q_DOGS_SELECT, DogQuery::default()).unwrap());
+        let first = repo
+            .query_iter(&Q_DOGS_SELECT, DogQuery::default(), name, |mut rows| Ok(rows.next()))
+            .unwrap();
+        assert!(first.is_some());
+
+        let rows = recorder.0.lock().unwrap().iter().skip(3).map(|r| r.2).collect::<Vec<_>>();
+        // the rows that were fetched: the one of query_one, the count and the first of query_iter
+        assert_eq!(vec![Some(1), Some(1), Some(1)], rows);
+    }
+
+    /// Adds a comment to the SQL of every call, leaving the parameters as they are.
+    struct Comment;
+
+    impl Interceptor for Comment {
+        fn before(&self, call: &mut Call) -> Result<()> {
+            call.sql.push_str(" -- intercepted");
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_bound_values() {
+        let select = |repo: &crate::dynamic_sql::Repository<'_>| {
+            repo.conn
+                .query_row("SELECT color FROM dogs WHERE name = 'Jeff'", [], |row| row.get::<_, Value>(0))
+                .unwrap()
+        };
+        for repo in [memory_repository(), memory_repository().with_interceptor(Comment)] {
+            repo.execute(&Q_DOGS_INSERT, BlobDogInsert("Jeff", ZeroBlob(3))).unwrap();
+            assert_eq!(Value::Blob(vec![0; 3]), select(&repo));
+        }
+    }
+}
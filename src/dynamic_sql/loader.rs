@@ -0,0 +1,265 @@
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::error::{Error, Result};
+
+const EXTENSIONS: [&str; 2] = [".sql.hbs", ".sql"];
+
+/// SQL templates and partials loaded from `.sql` and `.sql.hbs` files, see
+/// [RepositoryBuilder::files](crate::dynamic_sql::RepositoryBuilder::files).
+///
+/// The name of a template is its path relative to the root directory, with `/` as separator and
+/// without the extension, e.g. `dogs/select.sql.hbs` becomes `dogs/select`. Files whose name starts
+/// with `_` are registered as partials without the underscore, so `dogs/_columns.sql` is included
+/// with `{{> dogs/columns}}`. Other files are ignored.
+#[derive(Debug, Clone, Default)]
+pub struct SqlFiles {
+    templates: Vec<(String, String)>,
+    partials: Vec<(String, String)>,
+    origins: HashMap<String, String>,
+}
+
+impl SqlFiles {
+    /// Load all template files under `dir` recursively. Symbolic links to files are followed, but
+    /// those to directories are not.
+    pub fn load_dir<P: AsRef<Path> + ?Sized>(dir: &P) -> Result<Self> {
+        let mut paths = vec![];
+        collect_files(dir.as_ref(), &mut paths)?;
+        paths.sort();
+        let mut files = SqlFiles::default();
+        for path in paths {
+            let relative = path
+                .strip_prefix(dir.as_ref())
+                .unwrap_or(&path)
+                .components()
+                .map(|c| c.as_os_str().to_string_lossy())
+                .collect::<Vec<_>>()
+                .join("/");
+            if template_name(&relative).is_none() {
+                continue;
+            }
+            let sql = fs::read_to_string(&path).map_err(|source| Error::TemplateLoadError {
+                path: path.clone(),
+                source,
+            })?;
+            files.add(&relative, sql)?;
+        }
+        Ok(files)
+    }
+
+    /// Use files embedded at compile time as `(path, content)` pairs, usually created with
+    /// [embed_sql_files](crate::embed_sql_files).
+    pub fn embedded(files: &[(&str, &str)]) -> Result<Self> {
+        let mut loaded = SqlFiles::default();
+        for (path, sql) in files {
+            loaded.add(path, sql.to_string())?;
+        }
+        Ok(loaded)
+    }
+
+    pub fn templates(&self) -> &[(String, String)] {
+        &self.templates
+    }
+
+    pub fn partials(&self) -> &[(String, String)] {
+        &self.partials
+    }
+
+    /// The path of the file that the template or partial `name` was loaded from.
+    pub(crate) fn origin(&self, name: &str) -> &str {
+        &self.origins[name]
+    }
+
+    fn add(&mut self, path: &str, sql: String) -> Result<()> {
+        let (name, partial) = match template_name(path) {
+            Some(name) => name,
+            None => return Ok(()),
+        };
+        if let Some(first) = self.origins.insert(name.clone(), path.to_string()) {
+            return Err(Error::DuplicateTemplate {
+                name,
+                first,
+                second: path.to_string(),
+            });
+        }
+        if partial {
+            self.partials.push((name, sql));
+        } else {
+            self.templates.push((name, sql));
+        }
+        Ok(())
+    }
+}
+
+fn collect_files(dir: &Path, paths: &mut Vec<PathBuf>) -> Result<()> {
+    let entries = fs::read_dir(dir).map_err(|source| Error::TemplateLoadError {
+        path: dir.to_path_buf(),
+        source,
+    })?;
+    for entry in entries {
+        let entry = entry.map_err(|source| Error::TemplateLoadError {
+            path: dir.to_path_buf(),
+            source,
+        })?;
+        let path = entry.path();
+        // unlike `path.is_dir()`, the file type does not follow symbolic links, which may loop
+        let file_type = entry.file_type().map_err(|source| Error::TemplateLoadError {
+            path: path.clone(),
+            source,
+        })?;
+        if file_type.is_dir() {
+            collect_files(&path, paths)?;
+        } else if file_type.is_file() || path.is_file() {
+            paths.push(path);
+        }
+    }
+    Ok(())
+}
+
+/// The template name of the file at `path` and whether it is a partial, or [None] if it is not a
+/// template file.
+fn template_name(path: &str) -> Option<(String, bool)> {
+    let path = path.trim_start_matches("./");
+    let stem = EXTENSIONS.iter().find_map(|ext| path.strip_suffix(ext))?;
+    let (dir, file) = match stem.rfind('/') {
+        Some(i) => stem.split_at(i + 1),
+        None => ("", stem),
+    };
+    match file.strip_prefix('_') {
+        Some("") => None,
+        Some(file) => Some((format!("{}{}", dir, file), true)),
+        None if file.is_empty() => None,
+        None => Some((stem.to_string(), false)),
+    }
+}
+
+/// Names of the partials that `sql` includes with `{{> name}}`, except for inline partials defined in
+/// `sql` itself and dynamic partials. A partial block `{{#> name}}...{{/name}}` renders its content
+/// when the partial is missing, so it does not require one.
+pub(crate) fn partial_references(sql: &str) -> Vec<&str> {
+    let mut references = vec![];
+    let mut inline = vec![];
+    let mut rest = sql;
+    while let Some(start) = rest.find("{{") {
+        rest = &rest[start + 2..];
+        let tag = rest.trim_start_matches('~');
+        let (tag, is_inline) = if let Some(tag) = tag.strip_prefix("#*inline") {
+            (tag, true)
+        } else if let Some(tag) = tag.strip_prefix('>') {
+            (tag, false)
+        } else {
+            continue;
+        };
+        let name = tag
+            .trim_start()
+            .split(|c: char| c.is_whitespace() || c == '}' || c == '~')
+            .next()
+            .unwrap_or("");
+        if is_inline {
+            inline.push(name.trim_matches('"'));
+        } else if !name.is_empty() && !name.starts_with('(') {
+            references.push(name.trim_matches('"'));
+        }
+    }
+    references.retain(|r| !inline.contains(r));
+    references
+}
+
+#[cfg(test)]
+mod test {
+    use crate::dynamic_sql::executor::dog::*;
+    use crate::dynamic_sql::{DynamicSqlExecutor, RepositoryBuilder};
+    use crate::embed_sql_files;
+
+    use super::*;
+
+    fn testdata() -> PathBuf {
+        Path::new(env!("CARGO_MANIFEST_DIR")).join("src/dynamic_sql/testdata")
+    }
+
+    #[test]
+    fn test_template_name() {
+        assert_eq!(Some(("dogs/select".to_string(), false)), template_name("dogs/select.sql.hbs"));
+        assert_eq!(Some(("dogs/columns".to_string(), true)), template_name("dogs/_columns.sql"));
+        assert_eq!(Some(("insert".to_string(), false)), template_name("./insert.sql"));
+        assert_eq!(None, template_name("dogs/README.md"));
+        assert_eq!(None, template_name("dogs/_.sql"));
+    }
+
+    #[test]
+    fn test_partial_references() {
+        let sql = "{{> a}} {{~> b/c ~}} {{#> d}}x{{/d}} {{> (lookup x)}} \
+            {{#*inline \"e\"}}y{{/inline}} {{> e}}";
+        assert_eq!(vec!["a", "b/c"], partial_references(sql));
+    }
+
+    #[test]
+    fn test_load_dir() {
+        let files = SqlFiles::load_dir(&testdata()).unwrap();
+        let mut names = files.templates().iter().map(|(n, _)| n.as_str()).collect::<Vec<_>>();
+        names.sort_unstable();
+        assert_eq!(vec!["dogs/insert", "dogs/select"], names);
+        assert_eq!("dogs/columns", files.partials()[0].0);
+
+        let repo = RepositoryBuilder::memory().files(&files).init(DDL).build().unwrap();
+        let insert = ("dogs/insert", "");
+        let select = ("dogs/select", "");
+        repo.execute(&insert, dog_insert("Jeff")).unwrap();
+        repo.execute(&insert, dog_insert("Bob")).unwrap();
+        let query = DogQuery {
+            q_name: Some("Je"),
+            ..Default::default()
+        };
+        let dogs = repo
+            .query(&select, query, |row| row.get::<_, String>("name"))
+            .unwrap();
+        assert_eq!(vec!["Jeff"], dogs);
+
+        let result = SqlFiles::load_dir(&testdata().join("missing"));
+        assert!(matches!(result, Err(Error::TemplateLoadError { .. })));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_load_dir_symlinks() {
+        use std::os::unix::fs::symlink;
+
+        let dir = std::env::temp_dir().join(format!("shunlib_loader_{}", std::process::id()));
+        fs::create_dir_all(dir.join("dogs")).unwrap();
+        symlink(&dir, dir.join("dogs/loop")).unwrap();
+        symlink(testdata().join("dogs/insert.sql"), dir.join("dogs/insert.sql")).unwrap();
+        let files = SqlFiles::load_dir(&dir);
+        fs::remove_dir_all(&dir).unwrap();
+        let files = files.unwrap();
+        assert_eq!(1, files.templates().len());
+        assert_eq!("dogs/insert", files.templates()[0].0);
+    }
+
+    #[test]
+    fn test_embedded() {
+        let embedded = embed_sql_files!("src/dynamic_sql/testdata", [
+            "dogs/_columns.sql",
+            "dogs/insert.sql",
+            "dogs/select.sql.hbs",
+        ]);
+        let files = SqlFiles::embedded(&embedded).unwrap();
+        assert_eq!(2, files.templates().len());
+        assert_eq!(1, files.partials().len());
+
+        let result = SqlFiles::embedded(&[("a/b.sql", "SELECT 1"), ("a/b.sql.hbs", "SELECT 2")]);
+        match result {
+            Err(Error::DuplicateTemplate { name, first, second }) => {
+                assert_eq!(("a/b", "a/b.sql", "a/b.sql.hbs"), (name.as_str(), first.as_str(), second.as_str()))
+            }
+            other => panic!("unexpected result: {:?}", other),
+        }
+
+        let files = SqlFiles::embedded(&[("select.sql", "SELECT {{> columns}} FROM dogs")]).unwrap();
+        let result = RepositoryBuilder::memory().files(&files).build();
+        assert!(matches!(
+            result,
+            Err(Error::MissingPartial { ref template, ref partial }) if template == "select" && partial == "columns"
+        ));
+    }
+}
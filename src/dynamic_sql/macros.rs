@@ -4,6 +4,7 @@
 macro_rules! build_dynamic_params {
     ( $( $key:expr, $value:expr, )* ) => {
         {
+            #[allow(unused_mut)]
             let mut v = Vec::<(&str, &dyn ToSql)>::new();
             $(
                     if $value.is_some() {
@@ -28,11 +29,43 @@ macro_rules! build_dynamic_params {
 /// The syntax is as below:
 /// `->`: parameters used in phase 2 as mentioned above.
 /// `=>`: parameters used in phase 1 as mentioned above
-/// `&>`: fields that reference other query types. Fields in referenced types are treated as if they
+/// `~>`: parameters used in phase 2 that can be explicitly set to `NULL`, e.g. for updates. The
+/// fields are of type [Patch](crate::dynamic_sql::Patch) and present unless they are
+/// [Patch::Absent](crate::dynamic_sql::Patch::Absent). Serde does not let them borrow from the
+/// input, so use owned types such as `String`.
+/// `*>`: parameters with a list of values used in phase 2, e.g. `ids: i64` for `id IN (:ids)`. The
+/// fields are of type `Option<Vec<T>>` and are expanded by the `in` helper, see
+/// [DynamicQueryParameters::for_lists](crate::dynamic_sql::DynamicQueryParameters::for_lists).
+/// `%>`: filter fields used in phase 2, declared with the column and the operator of their
+/// condition, e.g. `weight_upper: f32 = weight le` for `weight <= :weight_upper`. The operators are
+/// `eq`, `ne`, `lt`, `le`, `gt`, `ge`, `like`, `contains`, `between` (of a `(T, T)`), `in` (of a
+/// `Vec<T>`) and `is_null` (of a `bool`, `false` for `IS NOT NULL`). A column that is not an
+/// identifier, e.g. one qualified by a table alias, is a string literal: `name: &'q str = "d.name" eq`.
+/// The `filters` helper renders
+/// the `WHERE` clause of the filter fields that are present: `SELECT * FROM dogs{{filters}}`. Like
+/// `{{#where}}`, its block may add custom conditions:
+/// `{{#filters}}{{#if [:q_name]}} AND name LIKE :q_name{{/if}}{{/filters}}`.
+/// `&>`: fields that reference other query types, by a path with at most a lifetime, e.g.
+/// `query: FooQuery<'q>`. Fields in referenced types are treated as if they
 /// are defined as part of the referencing type. Please note that fields should be named differently
 /// if they happen to have the same name in referenced types and the referencing type. For example,
 /// if `FooUpdate` reference `FooQuery` and `name` appears in both, then one should named like `q_name`
-/// while the other is `name`.
+/// while the other is `name`. Otherwise the query type fails to compile with an error like
+/// `duplicate parameter :name`, as the names are checked by a constant. A referenced type that is
+/// not declared by this macro is only checked when rendering, which fails with
+/// [Error::DuplicateParams](crate::Error::DuplicateParams) if both have a value, unless it declares
+/// [DynamicQueryParameters::PARAMS](crate::dynamic_sql::DynamicQueryParameters::PARAMS).
+/// Alternatively, a prefix puts the parameters of a referenced type in a namespace: with
+/// `query: FooQuery<'q> as "query"`, `name` of `FooQuery` is `:query.name`, e.g.
+/// `{{#if [:query.name]}} AND name = :query.name{{/if}}`, so the same type can be referenced twice.
+/// A referenced type that is not declared by this macro has to declare its parameters in
+/// [DynamicQueryParameters::PARAMS](crate::dynamic_sql::DynamicQueryParameters::PARAMS) to be
+/// prefixed, rendering fails with [Error::UnprefixedParams](crate::Error::UnprefixedParams) otherwise.
+/// SQLite does not accept `.` in parameter names, so `:query.name` is bound as `$query::name`,
+/// which is also how it appears in [RenderedQuery](crate::dynamic_sql::RenderedQuery).
+///
+/// With the `derive` feature, [DynamicQuery](crate::dynamic_sql::DynamicQuery) implements the same
+/// for a struct declared as usual.
 #[macro_export]
 macro_rules! new_query_type {
     (
@@ -40,70 +73,131 @@ macro_rules! new_query_type {
             (
                 $s:ident, $( $l:lifetime, )?
                 $( -> $($pf:ident: $pt:ty,)* )?
+                $( ~> $($nf:ident: $nt:ty,)* )?
                 $( => $($cf:ident: $ct:ty,)* )?
-                $( &> $($r:ident: $rt:ty,)* )?
+                $( *> $($lf:ident: $lt:ty,)* )?
+                $( %> $($ff:ident: $ft:ty = $fc:tt $fo:tt,)* )?
+                $( &> $($r:ident: $($rt:ident)::+ $(<$rl:lifetime>)? $( as $rp:literal )?,)* )?
             )
         )+
     ) => {
         use serde::{Deserialize, Serialize};
         use $crate::build_dynamic_params;
+        #[allow(unused_imports)]
+        use $crate::dynamic_sql::Patch;
         use std::collections::HashMap;
 
         $(
-        #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
-        pub struct $s$(<$l>)? {
-            $( $( pub $pf: Option<$pt>, )* )?
-            $( $( pub $cf: Option<$ct>, )* )?
-            $(
+        $crate::new_query_type!(
+            @struct $s$(<$l>)? {
+                $( $( pub $pf: Option<$pt>, )* )?
                 $(
-                    #[serde(flatten)]
-                    pub $r: Option<$rt>,
-                )*
-            )?
-        }
+                    $(
+                        #[serde(default, skip_serializing_if = "Patch::is_absent")]
+                        pub $nf: Patch<$nt>,
+                    )*
+                )?
+                $( $( pub $cf: Option<$ct>, )* )?
+                $( $( pub $lf: Option<Vec<$lt>>, )* )?
+                $( $( pub $ff: Option<$ft>, )* )?
+            }
+            $( $( $r: $($rt)::+ $(<$rl>)?, )* )?
+        );
 
         impl$(<$l>)? Default for $s$(<$l>)? {
             fn default() -> Self {
                 $s {
                     $( $( $pf: None, )* )?
+                    $( $( $nf: Patch::Absent, )* )?
                     $( $( $cf: None, )* )?
+                    $( $( $lf: None, )* )?
+                    $( $( $ff: None, )* )?
                     $( $( $r: None, )* )?
                 }
             }
         }
 
         impl$(<$l>)? DynamicQueryParameters for $s$(<$l>)? {
+            const PARAMS: &'static $crate::dynamic_sql::ParamNames = &$crate::dynamic_sql::ParamNames {
+                names: &[
+                    $( $( concat!(":", stringify!($pf)), )* )?
+                    $( $( concat!(":", stringify!($nf)), )* )?
+                    $( $( concat!(":", stringify!($cf)), )* )?
+                    $( $( concat!(":", stringify!($lf)), )* )?
+                    $( $( concat!(":", stringify!($ff)), )* )?
+                ],
+                nested: &[
+                    $( $( ($crate::new_query_type!(@prefix $( $rp )?), <$($rt)::+ $(<$rl>)? as DynamicQueryParameters>::PARAMS), )* )?
+                ],
+            };
+
             fn for_render(&self) -> HashMap<&'static str, String> {
                 let v = build_dynamic_params!(
                     $( $( concat!(":", stringify!($pf)), self.$pf, )* )?
                     $( $( concat!(":", stringify!($cf)), self.$cf, )* )?
                 );
-                let v = HashMap::<&'static str, String>::from_iter(
+                let mut v = HashMap::<&'static str, String>::from_iter(
                     v.into_iter().map(|(k, v)| (k, v.to_sql_segment().unwrap_or("".to_string()))),
                 );
                 $(
                     $(
-                        let v = if let Some(ref $r) = self.$r {
-                            let mut v = v;
-                            v.extend($r.for_render());
-                            v
-                        } else {
-                            v
-                        };
+                        if !self.$nf.is_absent() {
+                            v.insert(
+                                concat!(":", stringify!($nf)),
+                                self.$nf.to_sql_segment().unwrap_or("".to_string()),
+                            );
+                        }
+                    )*
+                )?
+                $(
+                    $(
+                        if let Some(ref $lf) = self.$lf {
+                            v.insert(concat!(":", stringify!($lf)), $lf.len().to_string());
+                        }
+                    )*
+                )?
+                let filters = $crate::new_query_type!(@filters self $( $( $ff $fc $fo )* )?);
+                if !filters.is_empty() {
+                    let sql = filters.iter().map(|f| f.sql()).collect::<Vec<_>>().join(" AND ");
+                    v.insert($crate::dynamic_sql::FILTERS, sql);
+                }
+                v.extend(filters.iter().map(|f| (f.param, f.shape())));
+                $(
+                    $(
+                        if let Some(ref $r) = self.$r {
+                            let nested = $r.for_render();
+                            $( let nested = $crate::new_query_type!(@static_prefix $rp).render($crate::dynamic_sql::ParamNames::of($r), nested); )?
+                            $crate::dynamic_sql::extend_render(&mut v, nested);
+                        }
                     )*
                 )?
                 v
             }
 
             fn for_execution(&self) -> Vec<DynamicParam<'_>> {
-                 let v = build_dynamic_params!(
+                #[allow(unused_mut)]
+                let mut v = build_dynamic_params!(
                     $( $( concat!(":", stringify!($pf)), self.$pf, )* )?
                 );
+                $(
+                    $(
+                        if !self.$nf.is_absent() {
+                            v.push((concat!(":", stringify!($nf)), &self.$nf as &dyn rusqlite::ToSql));
+                        }
+                    )*
+                )?
+                for filter in $crate::new_query_type!(@filters self $( $( $ff $fc $fo )* )?) {
+                    if let (param, $crate::dynamic_sql::FilterBind::One(value)) = (filter.param, filter.bind()) {
+                        v.push((param, value));
+                    }
+                }
                 $(
                     $(
                         let v = if let Some(ref $r) = self.$r {
                             let mut v = v;
-                            v.append(&mut $r.for_execution());
+                            let nested = $r.for_execution();
+                            $( let nested = $crate::new_query_type!(@static_prefix $rp).params($crate::dynamic_sql::ParamNames::of($r), nested); )?
+                            v.extend(nested);
                             v
                         } else {
                             v
@@ -112,8 +206,292 @@ macro_rules! new_query_type {
                 )?
                 v
             }
+
+            fn for_lists(&self) -> Vec<$crate::dynamic_sql::DynamicListParam<'_>> {
+                #[allow(unused_mut)]
+                let mut v = Vec::<$crate::dynamic_sql::DynamicListParam<'_>>::new();
+                $(
+                    $(
+                        if let Some(ref $lf) = self.$lf {
+                            v.push((
+                                concat!(":", stringify!($lf)),
+                                $lf.iter().map(|it| it as &dyn rusqlite::ToSql).collect(),
+                            ));
+                        }
+                    )*
+                )?
+                for filter in $crate::new_query_type!(@filters self $( $( $ff $fc $fo )* )?) {
+                    if let (param, $crate::dynamic_sql::FilterBind::List(values)) = (filter.param, filter.bind()) {
+                        v.push((param, values));
+                    }
+                }
+                $(
+                    $(
+                        if let Some(ref $r) = self.$r {
+                            let nested = $r.for_lists();
+                            $( let nested = $crate::new_query_type!(@static_prefix $rp).params($crate::dynamic_sql::ParamNames::of($r), nested); )?
+                            v.extend(nested);
+                        }
+                    )*
+                )?
+                v
+            }
         }
 
+        const _: () = $crate::dynamic_sql::check_params(<$s as DynamicQueryParameters>::PARAMS);
+
         )+
+    };
+    (@prefix) => { None };
+    (@prefix $rp:literal) => { Some($rp) };
+    // The prefix of a nested query type, with its own `static` for every field.
+    (@static_prefix $rp:literal) => {
+        {
+            static PREFIX: $crate::dynamic_sql::Prefix = $crate::dynamic_sql::Prefix::new($rp);
+            &PREFIX
+        }
+    };
+    // The filter fields (`%>`) that are present.
+    (@filters $this:ident $( $ff:ident $fc:tt $fo:tt )*) => {
+        {
+            #[allow(unused_mut)]
+            let mut filters = Vec::<$crate::dynamic_sql::Filter<'_>>::new();
+            $(
+                if let Some(ref value) = $this.$ff {
+                    filters.push($crate::new_query_type!(
+                        @filter $fo, $crate::new_query_type!(@column $fc), concat!(":", stringify!($ff)), value
+                    ));
+                }
+            )*
+            filters
+        }
+    };
+    // The column of a filter field, an identifier or a string literal, e.g. `"d.weight"`.
+    (@column $fc:ident) => { stringify!($fc) };
+    (@column $fc:literal) => { $fc };
+    (@filter eq, $c:expr, $p:expr, $v:expr) => { $crate::dynamic_sql::Filter::compare($c, "=", $p, $v) };
+    (@filter ne, $c:expr, $p:expr, $v:expr) => { $crate::dynamic_sql::Filter::compare($c, "<>", $p, $v) };
+    (@filter lt, $c:expr, $p:expr, $v:expr) => { $crate::dynamic_sql::Filter::compare($c, "<", $p, $v) };
+    (@filter le, $c:expr, $p:expr, $v:expr) => { $crate::dynamic_sql::Filter::compare($c, "<=", $p, $v) };
+    (@filter gt, $c:expr, $p:expr, $v:expr) => { $crate::dynamic_sql::Filter::compare($c, ">", $p, $v) };
+    (@filter ge, $c:expr, $p:expr, $v:expr) => { $crate::dynamic_sql::Filter::compare($c, ">=", $p, $v) };
+    (@filter like, $c:expr, $p:expr, $v:expr) => { $crate::dynamic_sql::Filter::compare($c, "LIKE", $p, $v) };
+    (@filter contains, $c:expr, $p:expr, $v:expr) => { $crate::dynamic_sql::Filter::contains($c, $p, $v) };
+    (@filter between, $c:expr, $p:expr, $v:expr) => { $crate::dynamic_sql::Filter::between($c, $p, $v) };
+    (@filter in, $c:expr, $p:expr, $v:expr) => { $crate::dynamic_sql::Filter::in_list($c, $p, $v) };
+    (@filter is_null, $c:expr, $p:expr, $v:expr) => { $crate::dynamic_sql::Filter::is_null($c, $p, $v) };
+    // Nested types written with a lifetime borrow from the input like the referencing type, which
+    // serde only infers for `&str`, so the fields are added one by one.
+    (@struct $s:ident $(<$l:lifetime>)? { $($fields:tt)* }) => {
+        #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+        pub struct $s$(<$l>)? {
+            $($fields)*
+        }
+    };
+    (
+        @struct $s:ident $(<$l:lifetime>)? { $($fields:tt)* }
+        $r:ident: $($rt:ident)::+ <$rl:lifetime>, $($rest:tt)*
+    ) => {
+        $crate::new_query_type!(
+            @struct $s$(<$l>)? {
+                $($fields)*
+                #[serde(flatten, borrow)]
+                pub $r: Option<$($rt)::+<$rl>>,
+            }
+            $($rest)*
+        );
+    };
+    (
+        @struct $s:ident $(<$l:lifetime>)? { $($fields:tt)* }
+        $r:ident: $($rt:ident)::+, $($rest:tt)*
+    ) => {
+        $crate::new_query_type!(
+            @struct $s$(<$l>)? {
+                $($fields)*
+                #[serde(flatten)]
+                pub $r: Option<$($rt)::+>,
+            }
+            $($rest)*
+        );
+    };
+}
+
+/// Embed SQL template files at compile time for [SqlFiles::embedded](crate::dynamic_sql::SqlFiles::embedded).
+/// `$dir` is relative to the directory of the crate's `Cargo.toml` and the paths are relative to
+/// `$dir`, which also determines the names of the templates:
+/// `embed_sql_files!("sql", ["dogs/select.sql.hbs", "dogs/_columns.sql"])`
+#[macro_export]
+macro_rules! embed_sql_files {
+    ( $dir:literal, [ $( $path:literal ),* $(,)? ] ) => {
+        [
+            $(
+                ($path, include_str!(concat!(env!("CARGO_MANIFEST_DIR"), "/", $dir, "/", $path))),
+            )*
+        ]
+    };
+}
+
+#[cfg(all(test, feature = "derive"))]
+mod test {
+    use rusqlite::types::Value;
+
+    use crate::dynamic_sql::executor::dog::*;
+    use crate::dynamic_sql::{DynamicQuery, DynamicQueryParameters, DynamicSqlExecutor, Patch, RepositoryBuilder};
+
+    const Q_FILTER_DOGS: (&str, &str) = ("Q_FILTER_DOGS", "SELECT name FROM dogs{{filters}} LIMIT {{[:limit]}}");
+
+    #[derive(Debug, Default, DynamicQuery)]
+    struct DerivedDogQuery<'q> {
+        #[query(rename = "q_name")]
+        name: Option<&'q str>,
+        #[query(rename = "q_color")]
+        color: Option<&'q str>,
+        weight_upper: Option<f32>,
+        #[query(default = 0.0)]
+        weight_lower: Option<f32>,
+        #[query(list)]
+        q_names: Option<Vec<&'q str>>,
+        #[query(skip)]
+        #[allow(dead_code)]
+        note: String,
+    }
+
+    #[derive(Default, DynamicQuery)]
+    struct DerivedDogUpdate<'q> {
+        weight: Option<f32>,
+        #[query(patch)]
+        color: Patch<String>,
+        #[query(flatten)]
+        query: Option<DerivedDogQuery<'q>>,
+    }
+
+    #[derive(Default, DynamicQuery)]
+    struct DerivedDogPair<'q> {
+        #[query(flatten, prefix = "first")]
+        first: Option<DerivedDogQuery<'q>>,
+        #[query(flatten, prefix = "second")]
+        second: Option<DerivedDogFilter<'q>>,
+        #[query(rename = "q_name")]
+        name: Option<&'q str>,
+    }
+
+    #[derive(Default, DynamicQuery)]
+    struct DerivedDogFilter<'q> {
+        #[query(filter = "name contains")]
+        name: Option<&'q str>,
+        #[query(filter = "weight between")]
+        weight_range: Option<(f32, f32)>,
+        #[query(render, default = 10)]
+        limit: Option<u32>,
+    }
+
+    #[test]
+    fn test_derive_like_macro() {
+        let repo = memory_repository();
+        let query = DogQuery {
+            q_name: Some("J"),
+            weight_lower: Some(5.0),
+            q_names: Some(vec!["Jeff", "Tom"]),
+            ..Default::default()
+        };
+        let derived = DerivedDogQuery {
+            name: Some("J"),
+            weight_lower: Some(5.0),
+            q_names: Some(vec!["Jeff", "Tom"]),
+            note: "not a parameter".to_string(),
+            ..Default::default()
+        };
+        assert_eq!(query.for_render(), derived.for_render());
+        let rendered = repo.render(&Q_DOGS_SELECT, &query).unwrap();
+        let derived = repo.render(&Q_DOGS_SELECT, &derived).unwrap();
+        assert_eq!(rendered.sql, derived.sql);
+        assert_eq!(rendered.params, derived.params);
+
+        let rendered = repo.render(&Q_DOGS_SELECT, DerivedDogQuery::default()).unwrap();
+        assert_eq!("SELECT * FROM dogs WHERE weight>=:weight_lower", rendered.sql);
+        assert_eq!(Some(&Value::Real(0.0)), rendered.param(":weight_lower"));
+    }
+
+    #[test]
+    fn test_derive_update() {
+        let repo = memory_repository();
+        repo.execute(&Q_DOGS_INSERT, dog_insert("Jeff")).unwrap();
+        repo.execute(&Q_DOGS_INSERT, dog_insert("Tom")).unwrap();
+        let update = DerivedDogUpdate {
+            weight: Some(30.5),
+            color: Patch::Null,
+            query: Some(DerivedDogQuery {
+                name: Some("Je"),
+                ..Default::default()
+            }),
+        };
+        assert_eq!(1, repo.execute(&Q_DOGS_UPDATE, update).unwrap());
+        let dogs: Vec<(String, Option<String>, f32)> = repo
+            .conn
+            .prepare("SELECT * FROM dogs ORDER BY name")
+            .unwrap()
+            .query_map([], |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)))
+            .unwrap()
+            .collect::<rusqlite::Result<_>>()
+            .unwrap();
+        assert_eq!(None, dogs[0].1);
+        assert_eq!(30.5, dogs[0].2);
+        assert_eq!(Some("white".to_string()), dogs[1].1);
+        assert!(DerivedDogUpdate::default().for_execution().is_empty());
+    }
+
+    #[test]
+    fn test_derive_filters() {
+        let repo = RepositoryBuilder::memory()
+            .templates(&[Q_DOGS_INSERT, Q_FILTER_DOGS])
+            .init(DDL)
+            .build()
+            .unwrap();
+        let filter = DerivedDogFilter {
+            name: Some("e"),
+            weight_range: Some((10.0, 30.0)),
+            limit: None,
+        };
+        let rendered = repo.render(&Q_FILTER_DOGS, &filter).unwrap();
+        assert_eq!(
+            "SELECT name FROM dogs WHERE name LIKE '%' || :name || '%' AND weight BETWEEN :weight_range_1 \
+            AND :weight_range_2 LIMIT 10",
+            rendered.sql
+        );
+        assert_eq!(
+            vec![
+                (":name".to_string(), Value::Text("e".to_string())),
+                (":weight_range_1".to_string(), Value::Real(10.0)),
+                (":weight_range_2".to_string(), Value::Real(30.0)),
+            ],
+            rendered.params
+        );
+        for name in &["Bob", "Jeff", "Tom"] {
+            repo.execute(&Q_DOGS_INSERT, dog_insert(name)).unwrap();
+        }
+        let names: Vec<String> = repo.query(&Q_FILTER_DOGS, &filter, |row| row.get(0)).unwrap();
+        assert_eq!(vec!["Jeff"], names);
+    }
+
+    #[test]
+    fn test_derive_prefix() {
+        let pair = DerivedDogPair {
+            first: Some(DerivedDogQuery {
+                name: Some("Bob"),
+                ..Default::default()
+            }),
+            second: Some(DerivedDogFilter {
+                name: Some("e"),
+                ..Default::default()
+            }),
+            name: Some("Jeff"),
+        };
+        let render = pair.for_render();
+        assert_eq!("Bob", render[":first.q_name"]);
+        assert_eq!("Jeff", render[":q_name"]);
+        assert_eq!("10", render[":second.limit"]);
+        assert_eq!("name LIKE '%' || :second.name || '%'", render[crate::dynamic_sql::FILTERS]);
+        let names = pair.for_execution().into_iter().map(|(k, _)| k).collect::<Vec<_>>();
+        assert_eq!(vec![":first.q_name", ":first.weight_lower", ":second.name", ":q_name"], names);
     }
 }
@@ -0,0 +1,326 @@
+use std::collections::HashMap;
+
+use rusqlite::{params, Connection, OptionalExtension, TransactionBehavior};
+
+use crate::error::{Error, Result};
+
+type MigrationFn = Box<dyn Fn(&Connection) -> Result<()> + Send + Sync>;
+
+enum Step {
+    Sql(String),
+    Rust(MigrationFn),
+}
+
+/// A single schema change identified by its version. Versions must be positive and increasing.
+pub struct Migration {
+    version: u32,
+    name: String,
+    step: Step,
+}
+
+impl Migration {
+    /// A migration that runs `sql`, which may contain several statements.
+    pub fn sql<N: Into<String>, S: Into<String>>(version: u32, name: N, sql: S) -> Self {
+        Migration {
+            version,
+            name: name.into(),
+            step: Step::Sql(sql.into()),
+        }
+    }
+
+    /// A migration that runs `f`, e.g. to migrate data that cannot be expressed in plain SQL.
+    /// As the closure itself cannot be checksummed, only its name takes part in the checksum.
+    pub fn rust<N, F>(version: u32, name: N, f: F) -> Self
+        where
+            N: Into<String>,
+            F: Fn(&Connection) -> Result<()> + Send + Sync + 'static,
+    {
+        Migration {
+            version,
+            name: name.into(),
+            step: Step::Rust(Box::new(f)),
+        }
+    }
+
+    pub fn version(&self) -> u32 {
+        self.version
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// A stable (FNV-1a) checksum of the name and the SQL of the migration.
+    pub fn checksum(&self) -> String {
+        let mut hash: u64 = 0xcbf2_9ce4_8422_2325;
+        let sql = match self.step {
+            Step::Sql(ref sql) => sql.as_str(),
+            Step::Rust(_) => "",
+        };
+        for b in self.name.bytes().chain(std::iter::once(0)).chain(sql.bytes()) {
+            hash ^= b as u64;
+            hash = hash.wrapping_mul(0x0100_0000_01b3);
+        }
+        format!("{:016x}", hash)
+    }
+
+    fn run(&self, conn: &Connection) -> Result<()> {
+        match self.step {
+            Step::Sql(ref sql) => Ok(conn.execute_batch(sql)?),
+            Step::Rust(ref f) => f(conn),
+        }
+    }
+}
+
+/// Where applied migrations are recorded.
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Tracking {
+    /// A table with the version, name and checksum of every applied migration.
+    History(String),
+    /// `PRAGMA user_version`, which only records the latest version, so checksums are not verified.
+    UserVersion,
+}
+
+/// An ordered set of [Migration]s. Pending migrations are applied with [Migrations::apply], all in
+/// one transaction, so that either all of them or none are applied.
+pub struct Migrations {
+    migrations: Vec<Migration>,
+    tracking: Tracking,
+}
+
+impl Migrations {
+    /// Applied migrations are recorded in the `_migrations` table by default.
+    pub fn new(migrations: Vec<Migration>) -> Self {
+        Migrations {
+            migrations,
+            tracking: Tracking::History("_migrations".to_string()),
+        }
+    }
+
+    /// Record applied migrations in the table `name` instead of `_migrations`.
+    pub fn history_table<N: Into<String>>(mut self, name: N) -> Self {
+        self.tracking = Tracking::History(name.into());
+        self
+    }
+
+    /// Record the latest applied version in `PRAGMA user_version` instead of a history table.
+    /// Changes to applied migrations cannot be detected in this mode.
+    pub fn user_version(mut self) -> Self {
+        self.tracking = Tracking::UserVersion;
+        self
+    }
+
+    /// Apply all pending migrations in one transaction and return their versions. Fails without
+    /// applying anything if an applied migration is unknown or its checksum has changed, or if a
+    /// pending migration is older than the latest applied one.
+    pub fn apply(&self, conn: &mut Connection) -> Result<Vec<u32>> {
+        self.validate()?;
+        let tx = conn.transaction_with_behavior(TransactionBehavior::Immediate)?;
+        let pending = match self.tracking {
+            Tracking::History(ref table) => {
+                let table = quote(table);
+                tx.execute_batch(&format!(
+                    "CREATE TABLE IF NOT EXISTS {}(\
+                        version INTEGER PRIMARY KEY,\
+                        name TEXT NOT NULL,\
+                        checksum TEXT NOT NULL,\
+                        applied_at TEXT NOT NULL DEFAULT CURRENT_TIMESTAMP\
+                    )",
+                    table
+                ))?;
+                let applied = self.verify(&tx, &table)?;
+                let pending = self
+                    .migrations
+                    .iter()
+                    .filter(|m| !applied.contains_key(&m.version))
+                    .collect::<Vec<_>>();
+                let latest = applied.keys().max().copied().unwrap_or(0);
+                if let Some(m) = pending.iter().find(|m| m.version < latest) {
+                    return Err(Error::InvalidMigrations(format!(
+                        "pending migration {} `{}` is older than applied migration {}",
+                        m.version, m.name, latest
+                    )));
+                }
+                for m in &pending {
+                    run(m, &tx)?;
+                    tx.execute(
+                        &format!("INSERT INTO {}(version, name, checksum) VALUES(?, ?, ?)", table),
+                        params![m.version, m.name, m.checksum()],
+                    )?;
+                }
+                pending
+            }
+            Tracking::UserVersion => {
+                let current: u32 = tx.query_row("PRAGMA user_version", [], |row| row.get(0))?;
+                let pending = self
+                    .migrations
+                    .iter()
+                    .filter(|m| m.version > current)
+                    .collect::<Vec<_>>();
+                for m in &pending {
+                    run(m, &tx)?;
+                }
+                if let Some(last) = pending.last() {
+                    tx.execute_batch(&format!("PRAGMA user_version = {}", last.version))?;
+                }
+                pending
+            }
+        };
+        tx.commit()?;
+        Ok(pending.iter().map(|m| m.version).collect())
+    }
+
+    fn validate(&self) -> Result<()> {
+        let mut last = 0;
+        for m in &self.migrations {
+            if m.version <= last {
+                return Err(Error::InvalidMigrations(format!(
+                    "version {} of migration `{}` must be greater than {}",
+                    m.version, m.name, last
+                )));
+            }
+            last = m.version;
+        }
+        Ok(())
+    }
+
+    /// Check the applied migrations recorded in `table` against the known ones.
+    fn verify(&self, conn: &Connection, table: &str) -> Result<HashMap<u32, String>> {
+        let mut stmt = conn.prepare(&format!("SELECT version, checksum FROM {}", table))?;
+        let applied = stmt
+            .query_map([], |row| Ok((row.get(0)?, row.get(1)?)))?
+            .collect::<rusqlite::Result<HashMap<u32, String>>>()?;
+        for (version, checksum) in &applied {
+            match self.migrations.iter().find(|m| m.version == *version) {
+                None => {
+                    let name = conn
+                        .query_row(
+                            &format!("SELECT name FROM {} WHERE version = ?", table),
+                            [version],
+                            |row| row.get::<_, String>(0),
+                        )
+                        .optional()?
+                        .unwrap_or_default();
+                    return Err(Error::InvalidMigrations(format!(
+                        "applied migration {} `{}` is unknown",
+                        version, name
+                    )));
+                }
+                Some(m) if &m.checksum() != checksum => {
+                    return Err(Error::MigrationChecksumMismatch {
+                        version: m.version,
+                        name: m.name.clone(),
+                    })
+                }
+                _ => {}
+            }
+        }
+        Ok(applied)
+    }
+}
+
+fn run(m: &Migration, conn: &Connection) -> Result<()> {
+    log::debug!("applying migration {} `{}`", m.version, m.name);
+    m.run(conn).map_err(|e| Error::MigrationFailed {
+        version: m.version,
+        source: Box::new(e),
+    })
+}
+
+fn quote(identifier: &str) -> String {
+    format!("\"{}\"", identifier.replace('"', "\"\""))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const DOGS: &str = "CREATE TABLE dogs(name TEXT PRIMARY KEY, color TEXT)";
+
+    fn migrations() -> Vec<Migration> {
+        vec![
+            Migration::sql(1, "create dogs", DOGS),
+            Migration::rust(2, "add default dog", |conn| {
+                conn.execute("INSERT INTO dogs(name) VALUES('Jeff')", [])?;
+                Ok(())
+            }),
+        ]
+    }
+
+    fn dogs(conn: &Connection) -> i64 {
+        conn.query_row("SELECT COUNT(*) FROM dogs", [], |row| row.get(0))
+            .unwrap()
+    }
+
+    #[test]
+    fn test_apply_pending() {
+        let mut conn = Connection::open_in_memory().unwrap();
+        assert_eq!(vec![1, 2], Migrations::new(migrations()).apply(&mut conn).unwrap());
+        assert!(Migrations::new(migrations()).apply(&mut conn).unwrap().is_empty());
+
+        let mut next = migrations();
+        next.push(Migration::sql(3, "index color", "CREATE INDEX dogs_color ON dogs(color)"));
+        assert_eq!(vec![3], Migrations::new(next).apply(&mut conn).unwrap());
+        assert_eq!(1, dogs(&conn));
+    }
+
+    #[test]
+    fn test_checksum_mismatch() {
+        let mut conn = Connection::open_in_memory().unwrap();
+        Migrations::new(migrations()).apply(&mut conn).unwrap();
+
+        let mut changed = migrations();
+        changed[0] = Migration::sql(1, "create dogs", "CREATE TABLE dogs(name TEXT)");
+        changed.push(Migration::sql(3, "index color", "CREATE INDEX dogs_color ON dogs(color)"));
+        let result = Migrations::new(changed).apply(&mut conn);
+        assert!(matches!(result, Err(Error::MigrationChecksumMismatch { version: 1, .. })));
+
+        let result = Migrations::new(migrations().into_iter().take(1).collect()).apply(&mut conn);
+        assert!(matches!(result, Err(Error::InvalidMigrations(_))));
+    }
+
+    #[test]
+    fn test_pending_older_than_applied() {
+        let mut conn = Connection::open_in_memory().unwrap();
+        let mut skipped = migrations();
+        skipped[1] = Migration::sql(3, "index color", "CREATE INDEX dogs_color ON dogs(color)");
+        Migrations::new(skipped).apply(&mut conn).unwrap();
+
+        let mut all = migrations();
+        all.push(Migration::sql(3, "index color", "CREATE INDEX dogs_color ON dogs(color)"));
+        let result = Migrations::new(all).apply(&mut conn);
+        assert!(matches!(result, Err(Error::InvalidMigrations(_))));
+        assert_eq!(0, dogs(&conn));
+    }
+
+    #[test]
+    fn test_rollback_on_failure() {
+        let mut conn = Connection::open_in_memory().unwrap();
+        let mut failing = migrations();
+        failing.push(Migration::sql(3, "broken", "CREATE INDEX broken ON nothing(x)"));
+        let result = Migrations::new(failing).apply(&mut conn);
+        assert!(matches!(result, Err(Error::MigrationFailed { version: 3, .. })));
+        // the table of dogs is not created either
+        assert!(conn.prepare("SELECT * FROM dogs").is_err());
+    }
+
+    #[test]
+    fn test_user_version() {
+        let mut conn = Connection::open_in_memory().unwrap();
+        let applied = Migrations::new(migrations()).user_version().apply(&mut conn).unwrap();
+        assert_eq!(vec![1, 2], applied);
+        let version: u32 = conn
+            .query_row("PRAGMA user_version", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(2, version);
+        assert!(Migrations::new(migrations())
+            .user_version()
+            .apply(&mut conn)
+            .unwrap()
+            .is_empty());
+
+        let unordered = vec![Migration::sql(2, "b", DOGS), Migration::sql(1, "a", DOGS)];
+        let result = Migrations::new(unordered).user_version().apply(&mut conn);
+        assert!(matches!(result, Err(Error::InvalidMigrations(_))));
+    }
+}
@@ -0,0 +1,459 @@
+use std::cell::{Cell, RefCell};
+
+use rusqlite::types::Value;
+use rusqlite::{Connection, Row, ToSql};
+
+use crate::dynamic_sql::engine::Engine;
+use crate::dynamic_sql::executor::{DynamicSqlExecutor, QueryRows};
+use crate::dynamic_sql::interceptor::{Call, CallKind};
+use crate::dynamic_sql::plan::QueryPlan;
+use crate::dynamic_sql::policy::RowErrorPolicy;
+use crate::dynamic_sql::query::{to_value, DynamicQueryParameters, RenderedQuery};
+use crate::dynamic_sql::template::SqlTemplate;
+use crate::error::{Error, Result};
+
+/// Rows returned by a scripted query of a [MockExecutor].
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct MockRows {
+    columns: Vec<String>,
+    rows: Vec<Vec<Value>>,
+}
+
+impl MockRows {
+    pub fn new(columns: &[&str]) -> Self {
+        MockRows {
+            columns: columns.iter().map(|c| c.to_string()).collect(),
+            rows: vec![],
+        }
+    }
+
+    /// Add a row with a value for every column, e.g. `.row(params!["Jeff", "white", 20.5])`.
+    ///
+    /// Panics if the number of values differs from the number of columns.
+    pub fn row(mut self, values: &[&dyn ToSql]) -> Self {
+        assert_eq!(
+            self.columns.len(),
+            values.len(),
+            "a mock row needs a value for every column of {:?}",
+            self.columns
+        );
+        let values = values
+            .iter()
+            .map(|v| to_value(*v).expect("mock row values must be convertible to SQLite values"))
+            .collect();
+        self.rows.push(values);
+        self
+    }
+
+    /// A `SELECT` returning these rows, so that they can be mapped like real rows.
+    fn sql(&self) -> String {
+        if self.rows.is_empty() {
+            let columns = if self.columns.is_empty() {
+                "NULL".to_string()
+            } else {
+                self.columns
+                    .iter()
+                    .map(|c| format!("NULL AS {}", identifier(c)))
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            };
+            return format!("SELECT {} WHERE 0", columns);
+        }
+        let columns = self
+            .columns
+            .iter()
+            .enumerate()
+            .map(|(i, c)| format!("column{} AS {}", i + 1, identifier(c)))
+            .collect::<Vec<_>>();
+        let rows = self
+            .rows
+            .iter()
+            .map(|row| format!("({})", row.iter().map(literal).collect::<Vec<_>>().join(", ")))
+            .collect::<Vec<_>>();
+        format!("SELECT {} FROM (VALUES {})", columns.join(", "), rows.join(", "))
+    }
+}
+
+fn identifier(name: &str) -> String {
+    format!("\"{}\"", name.replace('"', "\"\""))
+}
+
+fn literal(value: &Value) -> String {
+    match value {
+        Value::Null => "NULL".to_string(),
+        Value::Integer(i) => i.to_string(),
+        Value::Real(f) if f.is_nan() => "NULL".to_string(),
+        // SQLite reads out-of-range literals as infinity
+        Value::Real(f) if f.is_infinite() => (if *f > 0.0 { "9e999" } else { "-9e999" }).to_string(),
+        Value::Real(f) => format!("{:?}", f),
+        Value::Text(s) => format!("'{}'", s.replace('\'', "''")),
+        Value::Blob(b) => format!("X'{}'", b.iter().map(|b| format!("{:02X}", b)).collect::<String>()),
+    }
+}
+
+/// What a scripted call of a [MockExecutor] returns.
+#[derive(Debug, Clone, PartialEq)]
+pub enum MockResponse {
+    /// The rows of a query. An `execute` affects as many rows.
+    Rows(MockRows),
+    /// The number of rows affected by an `execute`.
+    Affected(usize),
+    /// The rowid returned by `execute_returning_rowid`.
+    RowId(i64),
+}
+
+/// Which calls a scripted [MockResponse] is returned for.
+#[derive(Debug, Clone)]
+enum Matcher {
+    Template(String),
+    Sql(String),
+}
+
+impl Matcher {
+    fn matches(&self, call: &Call) -> bool {
+        match self {
+            Matcher::Template(name) => call.template == *name,
+            Matcher::Sql(pattern) => call.sql.contains(pattern.as_str()),
+        }
+    }
+}
+
+/// A [DynamicSqlExecutor] for unit-testing code that is generic over the executor, without a
+/// database. Templates are rendered with the real [sql_helpers](crate::dynamic_sql::sql_helpers),
+/// every call is recorded, and the result is scripted with [MockExecutor::on_template] or
+/// [MockExecutor::on_sql].
+///
+/// Queries that are not scripted return no rows, and statements that are not scripted affect no
+/// rows and return the rowid `0`. Queries derived from a template, e.g. by
+/// [count](DynamicSqlExecutor::count) or [query_page](DynamicSqlExecutor::query_page), run
+/// against the scripted rows of the template.
+///
+/// [explain](DynamicSqlExecutor::explain) runs against an empty in-memory database, so it fails
+/// for any SQL that reads a table.
+pub struct MockExecutor<'reg> {
+    engine: Engine<'reg>,
+    /// Only used to turn scripted rows into [Row]s.
+    conn: Connection,
+    responses: Vec<(Matcher, MockResponse)>,
+    calls: RefCell<Vec<Call>>,
+}
+
+impl<'reg> MockExecutor<'reg> {
+    pub fn new<'a, T, I>(templates: &'a T) -> Result<Self>
+        where
+            &'a T: IntoIterator<Item = &'a I>,
+            I: SqlTemplate + 'a,
+    {
+        Ok(MockExecutor {
+            engine: Engine::new(templates)?,
+            conn: Connection::open_in_memory()?,
+            responses: vec![],
+            calls: RefCell::new(vec![]),
+        })
+    }
+
+    /// Return `response` for calls of the template `name`. When several responses match a call,
+    /// the one scripted first is returned.
+    pub fn on_template<N: Into<String>>(mut self, name: N, response: MockResponse) -> Self {
+        self.responses.push((Matcher::Template(name.into()), response));
+        self
+    }
+
+    /// Return `response` for calls whose SQL contains `pattern`.
+    pub fn on_sql<N: Into<String>>(mut self, pattern: N, response: MockResponse) -> Self {
+        self.responses.push((Matcher::Sql(pattern.into()), response));
+        self
+    }
+
+    /// All calls so far, in the order they were made.
+    pub fn calls(&self) -> Vec<Call> {
+        self.calls.borrow().clone()
+    }
+
+    /// The calls of the template `name` so far.
+    pub fn calls_to(&self, name: &str) -> Vec<Call> {
+        self.calls
+            .borrow()
+            .iter()
+            .filter(|c| c.template == name)
+            .cloned()
+            .collect()
+    }
+
+    /// Forget the calls so far.
+    pub fn clear_calls(&self) {
+        self.calls.borrow_mut().clear();
+    }
+
+    /// Panic unless the template `name` has been called, and return its last call.
+    pub fn assert_called(&self, name: &str) -> Call {
+        match self.calls_to(name).pop() {
+            Some(call) => call,
+            None => panic!("`{}` was not called, calls: {:#?}", name, self.calls()),
+        }
+    }
+
+    /// Panic unless the template `name` has been called exactly `times` times.
+    pub fn assert_called_times(&self, name: &str, times: usize) {
+        let calls = self.calls_to(name);
+        assert_eq!(
+            times,
+            calls.len(),
+            "`{}` was called {} times instead of {}, calls: {:#?}",
+            name,
+            calls.len(),
+            times,
+            calls
+        );
+    }
+
+    /// Panic if the template `name` has been called.
+    pub fn assert_not_called(&self, name: &str) {
+        let calls = self.calls_to(name);
+        assert!(calls.is_empty(), "`{}` was called, calls: {:#?}", name, calls);
+    }
+
+    /// Render `template`, transform the SQL with `derive` and record the call.
+    fn record<S, P, D>(&self, kind: CallKind, template: &S, params: &P, derive: D) -> Result<Call>
+        where
+            S: SqlTemplate,
+            P: DynamicQueryParameters,
+            D: FnOnce(String) -> String,
+    {
+        let RenderedQuery { sql, params } = RenderedQuery::new(derive(self.engine.render(template, params)?), params)?;
+        let call = Call {
+            kind,
+            template: template.name().to_string(),
+            sql,
+            params,
+        };
+        self.calls.borrow_mut().push(call.clone());
+        Ok(call)
+    }
+
+    fn response(&self, call: &Call) -> Option<&MockResponse> {
+        self.responses
+            .iter()
+            .find(|(m, _)| m.matches(call))
+            .map(|(_, r)| r)
+    }
+
+    fn affected(&self, call: &Call) -> usize {
+        match self.response(call) {
+            Some(MockResponse::Rows(rows)) => rows.rows.len(),
+            Some(MockResponse::Affected(n)) => *n,
+            _ => 0,
+        }
+    }
+}
+
+impl<'reg> DynamicSqlExecutor for MockExecutor<'reg> {
+    fn query<S, P, F, T>(&self, template: &S, params: P, f: F) -> Result<Vec<T>>
+        where
+            S: SqlTemplate,
+            P: DynamicQueryParameters,
+            F: FnMut(&Row<'_>) -> rusqlite::Result<T>,
+    {
+        let collected = self.query_iter(template, params, RowErrorPolicy::mapper(f), |rows| {
+            RowErrorPolicy::default().collect(rows)
+        })?;
+        if collected.errors.is_empty() {
+            Ok(collected.rows)
+        } else {
+            Err(Error::RowMappingErrors(collected.errors.into()))
+        }
+    }
+
+    fn query_derived<S, P, D, F, T, C, R>(
+        &self,
+        template: &S,
+        params: P,
+        derive: D,
+        f: F,
+        consume: C,
+    ) -> Result<R>
+        where
+            S: SqlTemplate,
+            P: DynamicQueryParameters,
+            D: FnOnce(String) -> String,
+            F: FnMut(&Row<'_>) -> rusqlite::Result<T>,
+            C: FnOnce(QueryRows<'_, F>) -> Result<R>,
+    {
+        let mut rendered = None;
+        let call = self.record(CallKind::Query, template, &params, |q| {
+            rendered = Some(q.clone());
+            derive(q)
+        })?;
+        let rows = match self.response(&call) {
+            Some(MockResponse::Rows(rows)) => rows.sql(),
+            _ => MockRows::default().sql(),
+        };
+        // run what is derived from the template, e.g. a count, against the scripted rows instead
+        let sql = match rendered {
+            Some(q) if !q.is_empty() && call.sql.contains(&q) => call.sql.replacen(&q, &rows, 1),
+            _ => rows,
+        };
+        let mut stmt = self.conn.prepare(&sql)?;
+        let bound = call
+            .params
+            .iter()
+            .filter(|(k, _)| matches!(stmt.parameter_index(k), Ok(Some(_))))
+            .map(|(k, v)| (k.as_str(), v as &dyn ToSql))
+            .collect::<Vec<_>>();
+        let fetched = Cell::new(0);
+        let result = consume(QueryRows::new(stmt.query_map(bound.as_slice(), f)?, &fetched));
+        result
+    }
+
+    fn execute<S, P>(&self, template: &S, params: P) -> Result<usize>
+        where
+            S: SqlTemplate,
+            P: DynamicQueryParameters,
+    {
+        let call = self.record(CallKind::Execute, template, &params, |q| q)?;
+        Ok(self.affected(&call))
+    }
+
+    fn execute_returning_rowid<S, P>(&self, template: &S, params: P) -> Result<i64>
+        where
+            S: SqlTemplate,
+            P: DynamicQueryParameters,
+    {
+        let call = self.record(CallKind::Execute, template, &params, |q| q)?;
+        match self.response(&call) {
+            Some(MockResponse::RowId(id)) => Ok(*id),
+            _ => Ok(0),
+        }
+    }
+
+    fn render<S, P>(&self, template: &S, params: P) -> Result<RenderedQuery>
+        where
+            S: SqlTemplate,
+            P: DynamicQueryParameters,
+    {
+        self.engine.render_query(template, params)
+    }
+
+    fn explain<S, P>(&self, template: &S, params: P) -> Result<QueryPlan>
+        where
+            S: SqlTemplate,
+            P: DynamicQueryParameters,
+    {
+        self.engine.explain(&self.conn, template, params)
+    }
+
+    fn execute_batch_chunked<S, P, I>(
+        &self,
+        template: &S,
+        params: I,
+        _chunk_size: usize,
+    ) -> Result<Vec<usize>>
+        where
+            S: SqlTemplate,
+            P: DynamicQueryParameters,
+            I: IntoIterator<Item = P>,
+    {
+        params
+            .into_iter()
+            .enumerate()
+            .map(|(index, params)| {
+                self.execute(template, params).map_err(|e| Error::BatchError {
+                    index,
+                    source: Box::new(e),
+                })
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use rusqlite::params;
+
+    use crate::dynamic_sql::executor::dog::*;
+    use crate::dynamic_sql::PageRequest;
+
+    use super::*;
+
+    /// Service code that only knows the executor trait.
+    fn rename_heavy_dogs<E: DynamicSqlExecutor>(executor: &E, color: &str) -> Result<Vec<String>> {
+        let query = DogQuery {
+            weight_lower: Some(30.0),
+            ..Default::default()
+        };
+        let dogs = executor.query(&Q_DOGS_SELECT, &query, |row| row.get::<_, String>("name"))?;
+        let update = DogUpdate {
+            color: Some(color),
+            query: Some(query),
+            ..Default::default()
+        };
+        executor.execute(&Q_DOGS_UPDATE, update)?;
+        Ok(dogs)
+    }
+
+    fn dogs() -> MockRows {
+        MockRows::new(&["name", "color", "weight"])
+            .row(params!["Jeff", "white", 35.5])
+            .row(params!["Bob's", Option::<String>::None, 40])
+    }
+
+    fn mock() -> MockExecutor<'static> {
+        MockExecutor::new(&[Q_DOGS_SELECT, Q_DOGS_UPDATE, Q_DOGS_WHERE, Q_DOGS_INSERT]).unwrap()
+    }
+
+    #[test]
+    fn test_mock_executor() {
+        let mock = mock()
+            .on_template("Q_DOGS_SELECT", MockResponse::Rows(dogs()))
+            .on_sql("UPDATE dogs", MockResponse::Affected(2));
+        let names = rename_heavy_dogs(&mock, "black").unwrap();
+        assert_eq!(vec!["Jeff", "Bob's"], names);
+
+        let update = mock.assert_called("Q_DOGS_UPDATE");
+        assert_eq!(CallKind::Execute, update.kind);
+        assert_eq!("UPDATE dogs SET color=:color WHERE weight>=:weight_lower", update.sql);
+        assert_eq!(
+            vec![
+                (":color".to_string(), Value::Text("black".to_string())),
+                (":weight_lower".to_string(), Value::Real(30.0)),
+            ],
+            update.params
+        );
+        mock.assert_called_times("Q_DOGS_SELECT", 1);
+        mock.assert_not_called("Q_DOGS_INSERT");
+        assert_eq!(2, mock.calls().len());
+
+        mock.clear_calls();
+        assert_eq!(2, mock.count(&Q_DOGS_SELECT, DogQuery::default()).unwrap());
+        assert!(mock.exists(&Q_DOGS_SELECT, DogQuery::default()).unwrap());
+        let page = mock
+            .query_page(&Q_DOGS_SELECT, DogQuery::default(), &PageRequest::offset(0, 1), |row| {
+                row.get::<_, f64>("weight")
+            })
+            .unwrap();
+        assert_eq!(vec![35.5], page.items);
+        assert!(page.next.is_some());
+        assert_eq!("SELECT COUNT(*) FROM (SELECT * FROM dogs)", mock.calls()[0].sql);
+    }
+
+    #[test]
+    fn test_mock_executor_unscripted() {
+        let mock = mock().on_template("Q_DOGS_INSERT", MockResponse::RowId(7));
+        let names = rename_heavy_dogs(&mock, "black").unwrap();
+        assert!(names.is_empty());
+        assert_eq!(0, mock.count(&Q_DOGS_SELECT, DogQuery::default()).unwrap());
+        assert_eq!(7, mock.execute_returning_rowid(&Q_DOGS_INSERT, dog_insert("Jeff")).unwrap());
+        assert_eq!(
+            vec![0, 0],
+            mock.execute_batch(&Q_DOGS_INSERT, vec![dog_insert("Bob"), dog_insert("Tom")])
+                .unwrap()
+        );
+        mock.assert_called_times("Q_DOGS_INSERT", 3);
+    }
+
+    #[test]
+    #[should_panic(expected = "`Q_DOGS_INSERT` was not called")]
+    fn test_mock_executor_assert_called() {
+        mock().assert_called("Q_DOGS_INSERT");
+    }
+}
@@ -1,11 +1,61 @@
 #![cfg(feature="dynamic_sql")]
-pub use executor::{DynamicSqlExecutor, Repository};
+pub use builder::RepositoryBuilder;
+pub use cache::CacheStats;
+pub use de::from_row;
+#[cfg(feature = "derive")]
+pub use shunlib_derive::DynamicQuery;
+pub use executor::{DynamicSqlExecutor, QueryRows, Repository};
+#[doc(hidden)]
+pub use filter::{Filter, FilterBind, FILTERS};
 pub use handlebars_helpers::sql_helpers;
+pub use interceptor::{Call, CallKind, Interceptor, Outcome};
+pub use loader::SqlFiles;
+pub use migration::{Migration, Migrations};
+pub use mock::{MockExecutor, MockResponse, MockRows};
+#[doc(hidden)]
+pub use nested::{check_params, extend_render, Prefix, DUPLICATES};
+pub use nested::ParamNames;
+pub use patch::Patch;
+pub use page::{Cursor, Page, PageRequest, SortColumn};
+pub use plan::{PlanStep, QueryPlan};
+pub use policy::{CollectedRows, RowError, RowErrorPolicy};
 pub use template::SqlTemplate;
-pub use query::{DynamicParam, ToSqlSegment, DynamicQueryParameters};
+pub use query::{DynamicListParam, DynamicParam, ToSqlSegment, DynamicQueryParameters, RenderedQuery};
+/// Re-exported for the code generated by [DynamicQuery], so that it does not depend on `rusqlite`.
+#[cfg(feature = "derive")]
+pub use rusqlite::ToSql;
+pub use transaction::Transaction;
+pub use validate::ValidationFailure;
+#[cfg(feature = "async")]
+pub use async_executor::{AsyncDynamicSqlExecutor, AsyncRepository};
+#[cfg(feature = "pool")]
+pub use pool::{PoolOptions, PooledRepository};
 
+#[cfg(feature = "async")]
+mod async_executor;
+mod builder;
+mod cache;
+#[cfg(doctest)]
+mod compile_fail;
+mod de;
+mod engine;
 mod executor;
+mod filter;
 mod handlebars_helpers;
+mod interceptor;
+mod loader;
 mod macros;
+mod migration;
+mod mock;
+mod nested;
+mod page;
+mod patch;
+mod plan;
+mod policy;
+#[cfg(feature = "pool")]
+mod pool;
 mod template;
+mod trace;
 mod query;
+mod transaction;
+mod validate;
@@ -0,0 +1,582 @@
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::sync::OnceLock;
+
+use crate::dynamic_sql::filter::FILTERS;
+use crate::dynamic_sql::query::DynamicQueryParameters;
+
+/// The key of [DynamicQueryParameters::for_render](crate::dynamic_sql::DynamicQueryParameters::for_render)
+/// holding the parameters that a nested query type (`&>`) has in common with the referencing type,
+/// which fail rendering with [Error::DuplicateParams](crate::Error::DuplicateParams). Only nested
+/// types that do not declare [DynamicQueryParameters::PARAMS](crate::dynamic_sql::DynamicQueryParameters::PARAMS)
+/// get this far, the others fail to compile.
+#[doc(hidden)]
+pub const DUPLICATES: &str = "%duplicates";
+
+/// The key of [DynamicQueryParameters::for_render](crate::dynamic_sql::DynamicQueryParameters::for_render)
+/// holding the parameters of query types nested with a prefix that are not in their
+/// [DynamicQueryParameters::PARAMS](crate::dynamic_sql::DynamicQueryParameters::PARAMS), so that
+/// they cannot be prefixed, which fail rendering with [Error::UnprefixedParams](crate::Error::UnprefixedParams).
+pub(crate) const UNPREFIXED: &str = "%unprefixed";
+
+/// The maximum number of prefixes a parameter name can have when it is checked by [check_params].
+const MAX_DEPTH: usize = 8;
+
+/// The parameter names of a query type, see
+/// [DynamicQueryParameters::PARAMS](crate::dynamic_sql::DynamicQueryParameters::PARAMS).
+#[derive(Debug)]
+pub struct ParamNames {
+    /// The parameters of the fields of the type itself, e.g. `:name`.
+    pub names: &'static [&'static str],
+    /// The parameter names of the nested query types and their prefixes.
+    pub nested: &'static [(Option<&'static str>, &'static ParamNames)],
+}
+
+impl ParamNames {
+    pub const EMPTY: ParamNames = ParamNames { names: &[], nested: &[] };
+
+    /// All parameter names, with the names of nested query types prefixed, e.g. `:query.name`.
+    pub fn all(&self) -> Vec<String> {
+        let mut names = self.names.iter().map(|name| name.to_string()).collect::<Vec<_>>();
+        for (prefix, nested) in self.nested {
+            names.extend(nested.all().into_iter().map(|name| match prefix {
+                Some(prefix) => format!(":{}.{}", prefix, name.trim_start_matches(':')),
+                None => name,
+            }));
+        }
+        names
+    }
+
+    /// The parameter names of the type of `query`, for the macro, which cannot always name it.
+    #[doc(hidden)]
+    pub fn of<P: DynamicQueryParameters>(_query: &P) -> &'static ParamNames {
+        P::PARAMS
+    }
+}
+
+/// A parameter name of [ParamNames] in a form that can be compared in constant expressions.
+#[derive(Clone, Copy)]
+struct Leaf {
+    prefixes: [&'static str; MAX_DEPTH],
+    depth: usize,
+    name: &'static str,
+}
+
+const fn leaf_count(params: &ParamNames) -> usize {
+    let mut count = params.names.len();
+    let mut i = 0;
+    while i < params.nested.len() {
+        count += leaf_count(params.nested[i].1);
+        i += 1;
+    }
+    count
+}
+
+/// The parameter at `index` of [ParamNames::all].
+const fn leaf_at(params: &ParamNames, mut index: usize, mut leaf: Leaf) -> Leaf {
+    if index < params.names.len() {
+        leaf.name = params.names[index];
+        return leaf;
+    }
+    index -= params.names.len();
+    let mut i = 0;
+    while i < params.nested.len() {
+        let (prefix, nested) = params.nested[i];
+        let count = leaf_count(nested);
+        if index < count {
+            if let Some(prefix) = prefix {
+                assert!(leaf.depth < MAX_DEPTH, "too many nested prefixes");
+                leaf.prefixes[leaf.depth] = prefix;
+                leaf.depth += 1;
+            }
+            return leaf_at(nested, index, leaf);
+        }
+        index -= count;
+        i += 1;
+    }
+    panic!("no such parameter")
+}
+
+const fn str_eq(a: &str, b: &str) -> bool {
+    let (a, b) = (a.as_bytes(), b.as_bytes());
+    if a.len() != b.len() {
+        return false;
+    }
+    let mut i = 0;
+    while i < a.len() {
+        if a[i] != b[i] {
+            return false;
+        }
+        i += 1;
+    }
+    true
+}
+
+const fn leaf_eq(a: &Leaf, b: &Leaf) -> bool {
+    if a.depth != b.depth || !str_eq(a.name, b.name) {
+        return false;
+    }
+    let mut i = 0;
+    while i < a.depth {
+        if !str_eq(a.prefixes[i], b.prefixes[i]) {
+            return false;
+        }
+        i += 1;
+    }
+    true
+}
+
+/// Fail with the name of `leaf`, e.g. `duplicate parameter :query.name`.
+const fn duplicate(leaf: &Leaf) -> ! {
+    let mut message = [0u8; 256];
+    let mut len = 0;
+    let mut part = 0;
+    // the parts are "duplicate parameter :", the prefixes followed by "." and the name without ":"
+    while part < leaf.depth + 2 {
+        let bytes = if part == 0 {
+            "duplicate parameter :".as_bytes()
+        } else if part <= leaf.depth {
+            leaf.prefixes[part - 1].as_bytes()
+        } else {
+            leaf.name.as_bytes()
+        };
+        let mut i = if part == leaf.depth + 1 && !bytes.is_empty() && bytes[0] == b':' { 1 } else { 0 };
+        while i < bytes.len() && len < message.len() {
+            message[len] = bytes[i];
+            len += 1;
+            i += 1;
+        }
+        if part > 0 && part <= leaf.depth && len < message.len() {
+            message[len] = b'.';
+            len += 1;
+        }
+        part += 1;
+    }
+    match std::str::from_utf8(message.split_at(len).0) {
+        Ok(message) => panic!("{}", message),
+        Err(_) => panic!("duplicate parameter"),
+    }
+}
+
+/// Fail if two parameters of `params` have the same name, which [new_query_type](crate::new_query_type)
+/// and the derive evaluate at compile time.
+#[doc(hidden)]
+pub const fn check_params(params: &ParamNames) {
+    let count = leaf_count(params);
+    let empty = Leaf {
+        prefixes: [""; MAX_DEPTH],
+        depth: 0,
+        name: "",
+    };
+    let mut i = 0;
+    while i < count {
+        let a = leaf_at(params, i, empty);
+        let mut j = i + 1;
+        while j < count {
+            if leaf_eq(&a, &leaf_at(params, j, empty)) {
+                duplicate(&a);
+            }
+            j += 1;
+        }
+        i += 1;
+    }
+}
+
+/// Add the render context of a nested query type to `v`, joining the conditions of filter fields
+/// and recording the parameters that are already in `v`.
+#[doc(hidden)]
+pub fn extend_render(v: &mut HashMap<&'static str, String>, mut nested: HashMap<&'static str, String>) {
+    let mut duplicates = nested
+        .keys()
+        .filter(|k| !k.starts_with('%') && v.contains_key(*k))
+        .map(|k| k.to_string())
+        .collect::<Vec<_>>();
+    duplicates.sort();
+    duplicates.extend(nested.remove(DUPLICATES));
+    if !duplicates.is_empty() {
+        nested.insert(DUPLICATES, duplicates.join(", "));
+    }
+    for (key, sep) in [(FILTERS, " AND "), (DUPLICATES, ", "), (UNPREFIXED, ", ")] {
+        if let Some(value) = nested.remove(key) {
+            v.entry(key)
+                .and_modify(|s| {
+                    s.push_str(sep);
+                    s.push_str(&value);
+                })
+                .or_insert(value);
+        }
+    }
+    v.extend(nested);
+}
+
+/// The parameter `name` of a nested query type with the prefix `prefix`, e.g. `:query.q_name` for
+/// `:q_name`.
+fn prefix_name(prefix: &str, name: &str) -> String {
+    format!(":{}.{}", prefix, name.trim_start_matches(':'))
+}
+
+/// The prefix of a query type nested with `&> query: FooQuery as "query"`. Parameter names are
+/// `'static`, so the macro keeps one in a `static` for every such field, holding the prefixed
+/// [ParamNames::all] of the nested type once they are needed.
+#[doc(hidden)]
+pub struct Prefix {
+    prefix: &'static str,
+    names: OnceLock<HashMap<String, String>>,
+}
+
+impl Prefix {
+    pub const fn new(prefix: &'static str) -> Self {
+        Prefix {
+            prefix,
+            names: OnceLock::new(),
+        }
+    }
+
+    /// The prefixed parameter `name` of the nested query type with the parameters `params`, or
+    /// [None] if `params` does not have it.
+    fn name(&'static self, params: &ParamNames, name: &str) -> Option<&'static str> {
+        let names = self.names.get_or_init(|| {
+            params
+                .all()
+                .into_iter()
+                .map(|name| {
+                    let prefixed = prefix_name(self.prefix, &name);
+                    (name, prefixed)
+                })
+                .collect()
+        });
+        names.get(name).map(String::as_str)
+    }
+
+    /// Prefix the render context of a nested query type, including the parameters in the
+    /// conditions of its filter fields. Parameters that `params` does not have are recorded, so that
+    /// rendering fails with [Error::UnprefixedParams](crate::Error::UnprefixedParams).
+    pub fn render(
+        &'static self,
+        params: &ParamNames,
+        nested: HashMap<&'static str, String>,
+    ) -> HashMap<&'static str, String> {
+        let mut unprefixed = vec![];
+        let mut prefixed = HashMap::with_capacity(nested.len());
+        for (k, v) in nested {
+            match k {
+                FILTERS => {
+                    prefixed.insert(k, replace_params(&v, |name| Some(prefix_name(self.prefix, name))).into_owned());
+                }
+                DUPLICATES | UNPREFIXED => {
+                    let v = v.split(", ").map(|name| prefix_name(self.prefix, name)).collect::<Vec<_>>();
+                    prefixed.insert(k, v.join(", "));
+                }
+                _ => match self.name(params, k) {
+                    Some(name) => {
+                        prefixed.insert(name, v);
+                    }
+                    None => unprefixed.push(prefix_name(self.prefix, k)),
+                },
+            }
+        }
+        if !unprefixed.is_empty() {
+            unprefixed.sort();
+            unprefixed.extend(prefixed.remove(UNPREFIXED));
+            prefixed.insert(UNPREFIXED, unprefixed.join(", "));
+        }
+        prefixed
+    }
+
+    /// Prefix the bind parameters of a nested query type. Parameters that `params` does not have
+    /// are left out, rendering fails for them.
+    pub fn params<V>(&'static self, params: &ParamNames, nested: Vec<(&'static str, V)>) -> Vec<(&'static str, V)> {
+        nested
+            .into_iter()
+            .filter_map(|(k, v)| Some((self.name(params, k)?, v)))
+            .collect()
+    }
+}
+
+/// SQLite does not accept `.` in parameter names, so a prefixed parameter such as `:query.q_name` is
+/// bound as `$query::q_name`, its syntax for namespaced parameters.
+pub(crate) fn sqlite_param_name(name: &str) -> Cow<'_, str> {
+    match name.strip_prefix(':') {
+        Some(rest) if rest.contains('.') => Cow::Owned(format!("${}", rest.replace('.', "::"))),
+        _ => Cow::Borrowed(name),
+    }
+}
+
+/// Rewrite the prefixed parameters in rendered SQL like [sqlite_param_name].
+pub(crate) fn sqlite_params(sql: &str) -> Cow<'_, str> {
+    // most statements have none, which saves scanning them for quotes and comments
+    if !has_prefixed_params(sql) {
+        return Cow::Borrowed(sql);
+    }
+    replace_params(sql, |name| match sqlite_param_name(name) {
+        Cow::Owned(name) => Some(name),
+        Cow::Borrowed(_) => None,
+    })
+}
+
+fn is_name(c: char) -> bool {
+    c.is_alphanumeric() || c == '_'
+}
+
+/// Whether `sql` has a sequence like `:name.`, which may be a prefixed parameter.
+fn has_prefixed_params(sql: &str) -> bool {
+    sql.match_indices(':').any(|(i, _)| {
+        let rest = &sql[i + 1..];
+        let len = rest.find(|c| !is_name(c)).unwrap_or(rest.len());
+        len > 0 && rest[len..].starts_with('.')
+    })
+}
+
+/// Replace the named parameters such as `:name` or `:query.name` in `sql` for which `f` returns a
+/// replacement, skipping string literals, quoted identifiers and comments.
+fn replace_params<F: FnMut(&str) -> Option<String>>(sql: &str, mut f: F) -> Cow<'_, str> {
+    let mut result = String::new();
+    let mut copied = 0;
+    // the character that ends the quote or comment, `*` for `*/`
+    let mut quote = None;
+    let mut chars = sql.char_indices().peekable();
+    while let Some((i, c)) = chars.next() {
+        match (quote, c) {
+            (Some('*'), '*') if sql[i + 1..].starts_with('/') => {
+                chars.next();
+                quote = None;
+            }
+            (Some('*'), _) => {}
+            (Some(q), _) if c == q => quote = None,
+            (Some(_), _) => {}
+            (None, '-') if sql[i + 1..].starts_with('-') => quote = Some('\n'),
+            (None, '/') if sql[i + 1..].starts_with('*') => {
+                chars.next();
+                quote = Some('*');
+            }
+            (None, '\'') | (None, '"') | (None, '`') => quote = Some(c),
+            (None, '[') => quote = Some(']'),
+            (None, ':') if sql[i + 1..].starts_with(is_name) => {
+                let mut end = i + 1;
+                loop {
+                    end += sql[end..].find(|c| !is_name(c)).unwrap_or(sql.len() - end);
+                    if sql[end..].starts_with('.') && sql[end + 1..].starts_with(is_name) {
+                        end += 1;
+                    } else {
+                        break;
+                    }
+                }
+                if let Some(replacement) = f(&sql[i..end]) {
+                    result.push_str(&sql[copied..i]);
+                    result.push_str(&replacement);
+                    copied = end;
+                }
+                while chars.peek().map(|(j, _)| *j < end).unwrap_or(false) {
+                    chars.next();
+                }
+            }
+            _ => {}
+        }
+    }
+    if copied == 0 {
+        return Cow::Borrowed(sql);
+    }
+    result.push_str(&sql[copied..]);
+    Cow::Owned(result)
+}
+
+#[cfg(test)]
+mod test {
+    use std::iter::FromIterator;
+
+    use rusqlite::types::Value;
+    use rusqlite::ToSql;
+
+    use crate::dynamic_sql::executor::dog::*;
+    use crate::dynamic_sql::{
+        DynamicParam, DynamicQueryParameters, DynamicSqlExecutor, RepositoryBuilder, ToSqlSegment,
+    };
+    use crate::error::Error;
+    use crate::new_query_type;
+
+    use super::*;
+
+    const Q_DOG_PAIR: (&str, &str) = (
+        "Q_DOG_PAIR",
+        "SELECT name FROM dogs WHERE \
+        {{#if [:first.q_name]}}name = :first.q_name OR {{/if}}\
+        {{#in [:second.q_names]}}name IN (:second.q_names){{/in}} ORDER BY name",
+    );
+
+    new_query_type!(
+        (DogPair, 'q,
+        &> first: DogQuery<'q> as "first", second: DogQuery<'q> as "second",)
+
+        (DuplicateDogQuery, 'q,
+        -> q_name: &'q str,
+        &> query: NameQuery<'q>,)
+
+        (PrefixedNameQuery, 'q,
+        &> query: NameQuery<'q> as "query",)
+    );
+
+    /// A query type that does not declare its [DynamicQueryParameters::PARAMS].
+    #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+    pub struct NameQuery<'q> {
+        name: Option<&'q str>,
+    }
+
+    impl<'q> DynamicQueryParameters for NameQuery<'q> {
+        fn for_render(&self) -> HashMap<&'static str, String> {
+            self.name.iter().map(|name| (":q_name", name.to_string())).collect()
+        }
+
+        fn for_execution(&self) -> Vec<DynamicParam<'_>> {
+            self.name.iter().map(|name| (":q_name", name as &dyn ToSql)).collect()
+        }
+    }
+
+    #[test]
+    fn test_param_names() {
+        let names = DogPair::PARAMS.all();
+        assert!(names.contains(&":first.q_name".to_string()));
+        assert!(names.contains(&":second.q_name".to_string()));
+        assert_eq!(2 * DogQuery::PARAMS.all().len(), names.len());
+        const NAME: ParamNames = ParamNames {
+            names: &[":name"],
+            nested: &[],
+        };
+        const DUPLICATE: ParamNames = ParamNames {
+            names: &[":name"],
+            nested: &[(Some("q"), &NAME), (None, &ParamNames::EMPTY), (Some("q"), &NAME)],
+        };
+        check_params(&ParamNames {
+            names: DUPLICATE.names,
+            nested: &DUPLICATE.nested[..2],
+        });
+        let result = std::panic::catch_unwind(|| check_params(&DUPLICATE));
+        let message = result.unwrap_err().downcast::<String>().unwrap();
+        assert_eq!("duplicate parameter :q.name", *message);
+    }
+
+    #[test]
+    fn test_prefix() {
+        static PREFIX: Prefix = Prefix::new("q");
+        const PARAMS: ParamNames = ParamNames {
+            names: &[":name", ":range"],
+            nested: &[(Some("inner"), &ParamNames { names: &[":name"], nested: &[] })],
+        };
+        assert_eq!(Some(":q.name"), PREFIX.name(&PARAMS, ":name"));
+        assert!(std::ptr::eq(PREFIX.name(&PARAMS, ":name").unwrap(), PREFIX.name(&PARAMS, ":name").unwrap()));
+        assert_eq!(Some(":q.inner.name"), PREFIX.name(&PARAMS, ":inner.name"));
+
+        let nested = HashMap::from_iter(vec![
+            (":name", "true".to_string()),
+            (FILTERS, "name = :name AND weight BETWEEN :range_1 AND :range_2".to_string()),
+        ]);
+        let prefixed = PREFIX.render(&PARAMS, nested);
+        assert_eq!("true", prefixed[":q.name"]);
+        assert_eq!(
+            "name = :q.name AND weight BETWEEN :q.range_1 AND :q.range_2",
+            prefixed[FILTERS]
+        );
+        assert_eq!(None, PREFIX.name(&PARAMS, ":color"));
+        let nested = HashMap::from_iter(vec![(":color", "true".to_string()), (":name", "true".to_string())]);
+        let prefixed = PREFIX.render(&PARAMS, nested);
+        assert_eq!(":q.color", prefixed[UNPREFIXED]);
+        assert!(!prefixed.contains_key(":color"));
+        let params = PREFIX.params(&PARAMS, vec![(":color", 1), (":name", 2)]);
+        assert_eq!(vec![(":q.name", 2)], params);
+    }
+
+    #[test]
+    fn test_extend_render_duplicates() {
+        let mut v = HashMap::from_iter(vec![(":name", "a".to_string()), (":color", "b".to_string())]);
+        extend_render(&mut v, HashMap::from_iter(vec![(":name", "c".to_string())]));
+        assert_eq!(":name", v[DUPLICATES]);
+        static PREFIX: Prefix = Prefix::new("q");
+        const PARAMS: ParamNames = ParamNames {
+            names: &[":name"],
+            nested: &[],
+        };
+        extend_render(
+            &mut v,
+            PREFIX.render(&PARAMS, HashMap::from_iter(vec![(":name", "c".to_string())])),
+        );
+        assert_eq!(":name", v[DUPLICATES]);
+        assert_eq!("c", v[":q.name"]);
+    }
+
+    #[test]
+    fn test_sqlite_params() {
+        assert_eq!("$query::q_name", sqlite_param_name(":query.q_name"));
+        assert_eq!(":q_name", sqlite_param_name(":q_name"));
+        let sql = "SELECT * FROM dogs d WHERE d.name = :query.name AND color = :color \
+            AND note = ':query.name' AND weight IN (:a.b.ids_1, :a.b.ids_2)";
+        assert_eq!(
+            "SELECT * FROM dogs d WHERE d.name = $query::name AND color = :color \
+            AND note = ':query.name' AND weight IN ($a::b::ids_1, $a::b::ids_2)",
+            sqlite_params(sql)
+        );
+        assert!(matches!(sqlite_params("SELECT :name, t.x FROM t"), Cow::Borrowed(_)));
+        assert!(matches!(sqlite_params("SELECT ':a.b' FROM t"), Cow::Borrowed(_)));
+        let sql = "SELECT * FROM dogs -- by :query.name\nWHERE /* :query.name */ name = :query.name /*/ :a.b */";
+        assert_eq!(
+            "SELECT * FROM dogs -- by :query.name\nWHERE /* :query.name */ name = $query::name /*/ :a.b */",
+            sqlite_params(sql)
+        );
+    }
+
+    #[test]
+    fn test_prefixed_query_types() {
+        let repo = RepositoryBuilder::memory()
+            .templates(&[Q_DOGS_INSERT, Q_DOG_PAIR])
+            .init(DDL)
+            .build()
+            .unwrap();
+        for name in &["Bob", "Jeff", "Tom"] {
+            repo.execute(&Q_DOGS_INSERT, dog_insert(name)).unwrap();
+        }
+        let pair = DogPair {
+            first: Some(DogQuery {
+                q_name: Some("Bob"),
+                ..Default::default()
+            }),
+            second: Some(DogQuery {
+                q_names: Some(vec!["Tom"]),
+                ..Default::default()
+            }),
+        };
+        let rendered = repo.render(&Q_DOG_PAIR, &pair).unwrap();
+        assert_eq!(
+            "SELECT name FROM dogs WHERE name = $first::q_name OR name IN ($second::q_names_1) ORDER BY name",
+            rendered.sql
+        );
+        assert_eq!(Some(&Value::Text("Bob".to_string())), rendered.param("$first::q_name"));
+        assert_eq!(Some(&Value::Text("Tom".to_string())), rendered.param("$second::q_names_1"));
+        let names: Vec<String> = repo.query(&Q_DOG_PAIR, &pair, |row| row.get(0)).unwrap();
+        assert_eq!(vec!["Bob", "Tom"], names);
+    }
+
+    #[test]
+    fn test_duplicate_params() {
+        let repo = memory_repository();
+        let duplicate = |q_name, name| DuplicateDogQuery {
+            q_name,
+            query: Some(NameQuery { name }),
+        };
+        let result = repo.query(&Q_DOGS_SELECT, duplicate(Some("Bob"), Some("Jeff")), |row| row.get::<_, String>(0));
+        assert!(matches!(result, Err(Error::DuplicateParams(ref p)) if p == ":q_name"));
+        assert!(repo.render(&Q_DOGS_SELECT, duplicate(Some("Bob"), None)).is_ok());
+        assert!(repo.render(&Q_DOGS_SELECT, duplicate(None, Some("Jeff"))).is_ok());
+    }
+
+    #[test]
+    fn test_unprefixed_params() {
+        let repo = memory_repository();
+        let query = PrefixedNameQuery {
+            query: Some(NameQuery { name: Some("Jeff") }),
+        };
+        let result = repo.query(&Q_DOGS_SELECT, &query, |row| row.get::<_, String>(0));
+        assert!(matches!(result, Err(Error::UnprefixedParams(ref p)) if p == ":query.q_name"));
+        let query = PrefixedNameQuery {
+            query: Some(NameQuery { name: None }),
+        };
+        assert!(repo.render(&Q_DOGS_SELECT, &query).is_ok());
+    }
+}
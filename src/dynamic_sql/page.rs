@@ -0,0 +1,379 @@
+use std::collections::HashMap;
+
+use rusqlite::types::Value;
+use rusqlite::{Row, ToSql};
+
+use crate::dynamic_sql::query::{DynamicListParam, DynamicParam, DynamicQueryParameters};
+use crate::error::{Error, Result};
+
+/// Names of the bind parameters for cursor values. Keys of [DynamicParam] are `'static`, which
+/// limits the number of sort columns for keyset pagination.
+const CURSOR_PARAMS: [&str; 8] = [
+    ":page_cursor_0",
+    ":page_cursor_1",
+    ":page_cursor_2",
+    ":page_cursor_3",
+    ":page_cursor_4",
+    ":page_cursor_5",
+    ":page_cursor_6",
+    ":page_cursor_7",
+];
+
+/// A column that keyset pagination sorts by. The combination of all sort columns must be unique
+/// and not `NULL` for every row, e.g. by ending with the primary key.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SortColumn {
+    column: String,
+    descending: bool,
+}
+
+impl SortColumn {
+    pub fn asc<T: Into<String>>(column: T) -> Self {
+        SortColumn {
+            column: column.into(),
+            descending: false,
+        }
+    }
+
+    pub fn desc<T: Into<String>>(column: T) -> Self {
+        SortColumn {
+            column: column.into(),
+            descending: true,
+        }
+    }
+
+    /// The column name quoted as an identifier, so that it can never inject SQL.
+    fn quoted(&self) -> String {
+        format!("\"{}\"", self.column.replace('"', "\"\""))
+    }
+}
+
+/// The values of the sort columns of the last row of a page. Keyset pagination continues after it.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Cursor(pub Vec<Value>);
+
+#[derive(Debug, Clone, PartialEq)]
+enum Position {
+    Offset(u64),
+    Keyset {
+        sort: Vec<SortColumn>,
+        after: Option<Cursor>,
+    },
+}
+
+/// Describes which page of a select template to fetch.
+#[derive(Debug, Clone, PartialEq)]
+pub struct PageRequest {
+    limit: u64,
+    position: Position,
+    with_total: bool,
+}
+
+impl PageRequest {
+    /// Skip `offset` rows and fetch up to `limit` rows. The template should have a stable
+    /// `ORDER BY` and must not have a `LIMIT` clause of its own.
+    pub fn offset(offset: u64, limit: u64) -> Self {
+        PageRequest {
+            limit,
+            position: Position::Offset(offset),
+            with_total: false,
+        }
+    }
+
+    /// Fetch up to `limit` rows ordered by `sort`, starting from the first row. Use
+    /// [PageRequest::after] or [Page::next] to continue with the following pages.
+    pub fn keyset(sort: Vec<SortColumn>, limit: u64) -> Self {
+        PageRequest {
+            limit,
+            position: Position::Keyset { sort, after: None },
+            with_total: false,
+        }
+    }
+
+    /// Continue a keyset pagination after `cursor`. Has no effect on offset pagination.
+    pub fn after(mut self, cursor: Cursor) -> Self {
+        if let Position::Keyset { ref mut after, .. } = self.position {
+            *after = Some(cursor);
+        }
+        self
+    }
+
+    /// Also count all rows of the template, see [DynamicSqlExecutor::count](crate::dynamic_sql::DynamicSqlExecutor::count).
+    pub fn with_total(mut self) -> Self {
+        self.with_total = true;
+        self
+    }
+
+    pub fn limit(&self) -> u64 {
+        self.limit
+    }
+
+    pub(crate) fn wants_total(&self) -> bool {
+        self.with_total
+    }
+
+    pub(crate) fn cursor(&self) -> Option<&Cursor> {
+        match self.position {
+            Position::Keyset { ref after, .. } => after.as_ref(),
+            Position::Offset(_) => None,
+        }
+    }
+
+    pub(crate) fn validate(&self) -> Result<()> {
+        if self.limit == 0 {
+            return Err(Error::InvalidPageRequest("limit must be positive"));
+        }
+        // SQLite takes an `i64`, and one more row than `limit` is fetched
+        if self.limit > i64::MAX as u64 - 1 {
+            return Err(Error::InvalidPageRequest("limit is too large"));
+        }
+        if let Position::Offset(offset) = self.position {
+            if offset > i64::MAX as u64 {
+                return Err(Error::InvalidPageRequest("offset is too large"));
+            }
+        }
+        if let Position::Keyset { ref sort, ref after } = self.position {
+            if sort.is_empty() {
+                return Err(Error::InvalidPageRequest("at least one sort column is required"));
+            }
+            if sort.len() > CURSOR_PARAMS.len() {
+                return Err(Error::InvalidPageRequest("too many sort columns"));
+            }
+            if after.as_ref().is_some_and(|c| c.0.len() != sort.len()) {
+                return Err(Error::InvalidPageRequest("cursor does not match the sort columns"));
+            }
+        }
+        Ok(())
+    }
+
+    /// Derive the SQL of the page from the SQL rendered from the template. One more row than
+    /// `limit` is fetched to find out whether there is a next page.
+    pub(crate) fn derive_sql(&self, q: String) -> String {
+        let mut q = subquery("*", &q);
+        match self.position {
+            Position::Offset(offset) => format!("{} LIMIT {} OFFSET {}", q, self.limit + 1, offset),
+            Position::Keyset { ref sort, ref after } => {
+                if after.is_some() {
+                    // (c0 > :v0) OR (c0 = :v0 AND c1 > :v1) OR ...
+                    let predicates = (0..sort.len())
+                        .map(|i| {
+                            let mut terms = sort[..i]
+                                .iter()
+                                .zip(CURSOR_PARAMS.iter())
+                                .map(|(c, p)| format!("{} = {}", c.quoted(), p))
+                                .collect::<Vec<_>>();
+                            let op = if sort[i].descending { "<" } else { ">" };
+                            terms.push(format!("{} {} {}", sort[i].quoted(), op, CURSOR_PARAMS[i]));
+                            format!("({})", terms.join(" AND "))
+                        })
+                        .collect::<Vec<_>>();
+                    q.push_str(&format!(" WHERE {}", predicates.join(" OR ")));
+                }
+                let order = sort
+                    .iter()
+                    .map(|c| format!("{} {}", c.quoted(), if c.descending { "DESC" } else { "ASC" }))
+                    .collect::<Vec<_>>();
+                format!("{} ORDER BY {} LIMIT {}", q, order.join(", "), self.limit + 1)
+            }
+        }
+    }
+
+    /// Read the values of the sort columns from `row`.
+    pub(crate) fn cursor_of(&self, row: &Row<'_>) -> rusqlite::Result<Option<Cursor>> {
+        match self.position {
+            Position::Offset(_) => Ok(None),
+            Position::Keyset { ref sort, .. } => {
+                let values = sort
+                    .iter()
+                    .map(|c| row.get_ref(c.column.as_str()).map(Value::from))
+                    .collect::<rusqlite::Result<Vec<_>>>()?;
+                Ok(Some(Cursor(values)))
+            }
+        }
+    }
+
+    /// The request for the page following the one ending with `last`.
+    pub(crate) fn next(&self, last: Option<Cursor>) -> PageRequest {
+        let mut next = self.clone();
+        match next.position {
+            Position::Offset(ref mut offset) => *offset = offset.saturating_add(self.limit),
+            Position::Keyset { ref mut after, .. } => *after = last,
+        }
+        next
+    }
+}
+
+/// Wrap the SQL rendered from a template in `SELECT <columns> FROM (...)`, without the trailing `;`
+/// that would end the statement inside the parentheses, and closing them on a new line after a
+/// trailing `-- comment`.
+pub(crate) fn subquery(columns: &str, q: &str) -> String {
+    let q = q.trim_end_matches(|c: char| c.is_whitespace() || c == ';');
+    if q.lines().last().is_some_and(|line| line.contains("--")) {
+        format!("SELECT {} FROM ({}\n)", columns, q)
+    } else {
+        format!("SELECT {} FROM ({})", columns, q)
+    }
+}
+
+/// A page of mapped rows.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Page<T> {
+    pub items: Vec<T>,
+    /// The request for the next page, or [None] if this is the last page.
+    pub next: Option<PageRequest>,
+    /// The number of rows of the whole template if [PageRequest::with_total] is set.
+    pub total: Option<i64>,
+}
+
+impl<T> Page<T> {
+    /// The cursor to continue a keyset pagination with.
+    pub fn next_cursor(&self) -> Option<&Cursor> {
+        self.next.as_ref().and_then(|r| r.cursor())
+    }
+}
+
+/// Query parameters of a template with the values of a cursor appended.
+pub(crate) struct PageParams<'c, P> {
+    pub(crate) params: P,
+    pub(crate) cursor: Option<&'c Cursor>,
+}
+
+impl<'c, P: DynamicQueryParameters> DynamicQueryParameters for PageParams<'c, P> {
+    fn for_render(&self) -> HashMap<&'static str, String> {
+        self.params.for_render()
+    }
+
+    fn for_execution(&self) -> Vec<DynamicParam<'_>> {
+        let mut v = self.params.for_execution();
+        if let Some(cursor) = self.cursor {
+            v.extend(
+                CURSOR_PARAMS
+                    .iter()
+                    .zip(cursor.0.iter())
+                    .map(|(k, v)| (*k, v as &dyn ToSql)),
+            );
+        }
+        v
+    }
+
+    fn for_lists(&self) -> Vec<DynamicListParam<'_>> {
+        self.params.for_lists()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::dynamic_sql::executor::dog::*;
+    use crate::dynamic_sql::DynamicSqlExecutor;
+
+    use super::*;
+
+    fn repository() -> crate::dynamic_sql::Repository<'static> {
+        let repo = memory_repository();
+        for (name, weight) in &[("a", 10.0), ("b", 30.0), ("c", 20.0), ("d", 30.0), ("e", 5.0)] {
+            let dog = DogInsert {
+                weight: Some(*weight),
+                ..dog_insert(name)
+            };
+            repo.execute(&Q_DOGS_INSERT, dog).unwrap();
+        }
+        repo
+    }
+
+    fn name(row: &Row<'_>) -> rusqlite::Result<String> {
+        row.get("name")
+    }
+
+    #[test]
+    fn test_offset_pages() {
+        let repo = repository();
+        let request = PageRequest::offset(0, 2).with_total();
+        let page = repo
+            .query_page(&Q_DOGS_SELECT, DogQuery::default(), &request, name)
+            .unwrap();
+        assert_eq!(vec!["a", "b"], page.items);
+        assert_eq!(Some(5), page.total);
+
+        let page = repo
+            .query_page(&Q_DOGS_SELECT, DogQuery::default(), page.next.as_ref().unwrap(), name)
+            .unwrap();
+        assert_eq!(vec!["c", "d"], page.items);
+        let page = repo
+            .query_page(&Q_DOGS_SELECT, DogQuery::default(), page.next.as_ref().unwrap(), name)
+            .unwrap();
+        assert_eq!(vec!["e"], page.items);
+        assert_eq!(None, page.next);
+        assert_eq!(Some(5), page.total);
+    }
+
+    #[test]
+    fn test_keyset_pages() {
+        let repo = repository();
+        let query = || DogQuery {
+            weight_lower: Some(6.0),
+            ..Default::default()
+        };
+        let sort = vec![SortColumn::desc("weight"), SortColumn::asc("name")];
+        let mut request = PageRequest::keyset(sort, 2);
+        let mut names = vec![];
+        loop {
+            let page = repo.query_page(&Q_DOGS_SELECT, query(), &request, name).unwrap();
+            names.push(page.items.clone());
+            match page.next {
+                Some(next) => request = next,
+                None => break,
+            }
+        }
+        assert_eq!(vec![vec!["b", "d"], vec!["c", "a"]], names);
+
+        let cursor = Cursor(vec![Value::Real(20.0), Value::Text("c".to_string())]);
+        let page = repo
+            .query_page(&Q_DOGS_SELECT, query(), &request.after(cursor), name)
+            .unwrap();
+        assert_eq!(vec!["a"], page.items);
+    }
+
+    #[test]
+    fn test_terminated_templates() {
+        const Q_TERMINATED: (&str, &str) = ("Q_TERMINATED", "SELECT * FROM dogs ORDER BY name;\n");
+        const Q_COMMENTED: (&str, &str) = ("Q_COMMENTED", "SELECT * FROM dogs ORDER BY name -- all dogs");
+        let repo = crate::dynamic_sql::Repository::new(":memory:", &[Q_DOGS_INSERT, Q_TERMINATED, Q_COMMENTED])
+            .unwrap();
+        repo.conn.execute_batch(DDL).unwrap();
+        for name in &["a", "b", "c"] {
+            repo.execute(&Q_DOGS_INSERT, dog_insert(name)).unwrap();
+        }
+        let sort = vec![SortColumn::asc("name")];
+        for template in &[Q_TERMINATED, Q_COMMENTED] {
+            for request in &[PageRequest::offset(1, 1).with_total(), PageRequest::keyset(sort.clone(), 2)] {
+                let page = repo.query_page(template, DogQuery::default(), request, name).unwrap();
+                assert!(page.next.is_some());
+            }
+        }
+        let page = repo
+            .query_page(&Q_COMMENTED, DogQuery::default(), &PageRequest::offset(1, 1), name)
+            .unwrap();
+        assert_eq!(vec!["b"], page.items);
+    }
+
+    #[test]
+    fn test_invalid_request() {
+        let repo = repository();
+        let request = PageRequest::keyset(vec![], 2);
+        let result = repo.query_page(&Q_DOGS_SELECT, DogQuery::default(), &request, name);
+        assert!(matches!(result, Err(Error::InvalidPageRequest(_))));
+
+        for request in &[
+            PageRequest::offset(0, u64::MAX),
+            PageRequest::offset(0, i64::MAX as u64),
+            PageRequest::offset(u64::MAX, 2),
+            PageRequest::keyset(vec![SortColumn::asc("name")], u64::MAX),
+        ] {
+            let result = repo.query_page(&Q_DOGS_SELECT, DogQuery::default(), request, name);
+            assert!(matches!(result, Err(Error::InvalidPageRequest(_))));
+        }
+        let request = PageRequest::offset(0, i64::MAX as u64 - 1);
+        let page = repo.query_page(&Q_DOGS_SELECT, DogQuery::default(), &request, name).unwrap();
+        assert_eq!(5, page.items.len());
+    }
+}
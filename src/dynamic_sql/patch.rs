@@ -0,0 +1,138 @@
+use rusqlite::types::{Null, ToSqlOutput};
+use rusqlite::ToSql;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+/// A field of an update that distinguishes "leave as is" from "set to `NULL`", which
+/// `Option` cannot. Declared with `~>` in [new_query_type](crate::new_query_type).
+///
+/// With serde, a missing field is [Patch::Absent] and `null` is [Patch::Null], like in a JSON
+/// merge patch (RFC 7396). The field must have `#[serde(default)]` for that, which
+/// [new_query_type](crate::new_query_type) adds.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub enum Patch<T> {
+    /// Not provided, the parameter is not present.
+    #[default]
+    Absent,
+    /// Explicitly cleared, the parameter is present and bound as `NULL`.
+    Null,
+    Value(T),
+}
+
+impl<T> Patch<T> {
+    pub fn is_absent(&self) -> bool {
+        matches!(self, Patch::Absent)
+    }
+
+    pub fn is_null(&self) -> bool {
+        matches!(self, Patch::Null)
+    }
+
+    /// The value if there is one.
+    pub fn value(&self) -> Option<&T> {
+        match self {
+            Patch::Value(v) => Some(v),
+            _ => None,
+        }
+    }
+
+    pub fn as_ref(&self) -> Patch<&T> {
+        match self {
+            Patch::Absent => Patch::Absent,
+            Patch::Null => Patch::Null,
+            Patch::Value(v) => Patch::Value(v),
+        }
+    }
+}
+
+impl<T: ToSql> ToSql for Patch<T> {
+    fn to_sql(&self) -> rusqlite::Result<ToSqlOutput<'_>> {
+        match self {
+            Patch::Value(v) => v.to_sql(),
+            _ => Null.to_sql(),
+        }
+    }
+}
+
+/// [Patch::Absent] is serialized like [Patch::Null], so it has to be skipped with
+/// `#[serde(skip_serializing_if = "Patch::is_absent")]` to round-trip.
+impl<T: Serialize> Serialize for Patch<T> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        match self {
+            Patch::Value(v) => serializer.serialize_some(v),
+            _ => serializer.serialize_none(),
+        }
+    }
+}
+
+impl<'de, T: Deserialize<'de>> Deserialize<'de> for Patch<T> {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        Ok(match Option::<T>::deserialize(deserializer)? {
+            Some(v) => Patch::Value(v),
+            None => Patch::Null,
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::iter::FromIterator;
+
+    use rusqlite::types::Value;
+    use rusqlite::ToSql;
+
+    use crate::new_query_type;
+    use crate::dynamic_sql::executor::dog::*;
+    use crate::dynamic_sql::{DynamicParam, DynamicQueryParameters, DynamicSqlExecutor, ToSqlSegment};
+
+    new_query_type!(
+        (DogPatch, 'q,
+        -> weight: f32,
+        ~> color: String,
+        &> query: DogQuery<'q>,)
+    );
+
+    #[test]
+    fn test_patch_params() {
+        let update = |color| DogPatch {
+            color,
+            ..Default::default()
+        };
+        assert!(update(Patch::Absent).for_render().is_empty());
+        assert!(update(Patch::Absent).for_execution().is_empty());
+        assert_eq!("true", update(Patch::Null).for_render()[":color"]);
+        assert_eq!("white", update(Patch::Value("white".to_string())).for_render()[":color"]);
+
+        let repo = memory_repository();
+        let rendered = repo.render(&Q_DOGS_UPDATE, update(Patch::Null)).unwrap();
+        assert_eq!("UPDATE dogs SET color=:color", rendered.sql);
+        assert_eq!(Some(&Value::Null), rendered.param(":color"));
+    }
+
+    #[test]
+    fn test_patch_update() {
+        let repo = memory_repository();
+        repo.execute(&Q_DOGS_INSERT, dog_insert("Jeff")).unwrap();
+        let color = || {
+            repo.conn
+                .query_row("SELECT color FROM dogs", [], |row| row.get::<_, Option<String>>(0))
+                .unwrap()
+        };
+
+        let patch: DogPatch = serde_json::from_str(r#"{"weight": 30.5}"#).unwrap();
+        assert_eq!(Patch::Absent, patch.color);
+        repo.execute(&Q_DOGS_UPDATE, patch).unwrap();
+        assert_eq!(Some("white".to_string()), color());
+
+        let patch: DogPatch = serde_json::from_str(r#"{"color": null, "q_name": "Je"}"#).unwrap();
+        assert_eq!(Patch::Null, patch.color);
+        assert_eq!(Some("Je"), patch.query.as_ref().and_then(|q| q.q_name));
+        repo.execute(&Q_DOGS_UPDATE, patch).unwrap();
+        assert_eq!(None, color());
+
+        let patch: DogPatch = serde_json::from_str(r#"{"color": "black"}"#).unwrap();
+        assert!(serde_json::to_string(&patch).unwrap().contains(r#""color":"black""#));
+        assert!(!serde_json::to_string(&DogPatch::default()).unwrap().contains("color"));
+        repo.execute(&Q_DOGS_UPDATE, patch).unwrap();
+        assert_eq!(Some("black".to_string()), color());
+    }
+}
@@ -0,0 +1,197 @@
+use std::collections::HashSet;
+use std::fmt::{self, Display, Formatter};
+
+/// The parsed output of `EXPLAIN QUERY PLAN`, see
+/// [DynamicSqlExecutor::explain](crate::dynamic_sql::DynamicSqlExecutor::explain).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct QueryPlan {
+    /// The top level steps, in the order SQLite reports them.
+    pub steps: Vec<PlanStep>,
+}
+
+/// A step of a [QueryPlan], e.g. `SEARCH dogs USING INDEX dogs_color (color=?)`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PlanStep {
+    pub id: i64,
+    pub detail: String,
+    pub children: Vec<PlanStep>,
+    /// Whether the table scanned by this step has any index. Always `false` for other steps.
+    pub(crate) scans_indexed_table: bool,
+}
+
+impl PlanStep {
+    /// The table if this step reads a whole table without using an index. SQLite 3.36 and later
+    /// report the alias instead if the query gives the table one.
+    pub fn full_scan(&self) -> Option<&str> {
+        full_scan(&self.detail)
+    }
+}
+
+/// The table that the step described by `detail` reads without using an index.
+pub(crate) fn full_scan(detail: &str) -> Option<&str> {
+    let rest = detail.strip_prefix("SCAN ")?;
+    if rest.contains(" USING ") {
+        return None;
+    }
+    // SQLite before 3.36 reports `SCAN TABLE dogs`
+    let rest = rest.strip_prefix("TABLE ").unwrap_or(rest);
+    rest.split_whitespace()
+        .next()
+        .filter(|table| !table.starts_with('('))
+}
+
+/// The table that `sql` gives the alias `alias`, as in `FROM dogs d` or `JOIN dogs AS d`.
+pub(crate) fn aliased_table<'a>(sql: &'a str, alias: &str) -> Option<&'a str> {
+    let words = sql
+        .split(|c: char| c.is_whitespace() || c == ',' || c == '(' || c == ')' || c == ';')
+        .filter(|w| !w.is_empty())
+        .map(|w| w.trim_matches(|c| c == '"' || c == '`' || c == '[' || c == ']'))
+        .collect::<Vec<_>>();
+    words.iter().enumerate().skip(1).find_map(|(i, word)| {
+        if !word.eq_ignore_ascii_case(alias) {
+            return None;
+        }
+        match words[i - 1] {
+            w if w.eq_ignore_ascii_case("AS") => i.checked_sub(2).map(|j| words[j]),
+            table => Some(table),
+        }
+    })
+}
+
+impl QueryPlan {
+    /// Build the tree from `(id, parent, detail)` rows. `indexed` are the names of the scanned
+    /// tables that have indexes, or their aliases if the plan reports those.
+    pub(crate) fn from_rows(rows: &[(i64, i64, String)], indexed: &HashSet<String>) -> Self {
+        fn children(parent: i64, rows: &[(i64, i64, String)], indexed: &HashSet<String>) -> Vec<PlanStep> {
+            rows.iter()
+                .filter(|(_, p, _)| *p == parent)
+                .map(|(id, _, detail)| PlanStep {
+                    id: *id,
+                    detail: detail.clone(),
+                    children: children(*id, rows, indexed),
+                    scans_indexed_table: full_scan(detail).is_some_and(|t| indexed.contains(t)),
+                })
+                .collect()
+        }
+        QueryPlan {
+            steps: children(0, rows, indexed),
+        }
+    }
+
+    /// All steps, depth first.
+    pub fn iter(&self) -> impl Iterator<Item = &PlanStep> {
+        fn flatten<'a>(steps: &'a [PlanStep], out: &mut Vec<&'a PlanStep>) {
+            for step in steps {
+                out.push(step);
+                flatten(&step.children, out);
+            }
+        }
+        let mut out = vec![];
+        flatten(&self.steps, &mut out);
+        out.into_iter()
+    }
+
+    /// Steps that scan a whole table although the table has indexes.
+    pub fn full_scans_of_indexed_tables(&self) -> Vec<&PlanStep> {
+        self.iter().filter(|s| s.scans_indexed_table).collect()
+    }
+
+    /// Panic if any step scans a whole table that has indexes, for use in tests.
+    pub fn assert_no_full_scan(&self) {
+        let scans = self.full_scans_of_indexed_tables();
+        if !scans.is_empty() {
+            let details = scans.iter().map(|s| s.detail.as_str()).collect::<Vec<_>>();
+            panic!("full scan of indexed tables: {}\n{}", details.join(", "), self);
+        }
+    }
+}
+
+impl Display for QueryPlan {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        fn write(f: &mut Formatter<'_>, steps: &[PlanStep], depth: usize) -> fmt::Result {
+            for step in steps {
+                writeln!(f, "{:indent$}{}", "", step.detail, indent = depth * 2)?;
+                write(f, &step.children, depth + 1)?;
+            }
+            Ok(())
+        }
+        write(f, &self.steps, 0)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::dynamic_sql::executor::dog::*;
+    use crate::dynamic_sql::{DynamicSqlExecutor, RepositoryBuilder};
+
+    use super::*;
+
+    #[test]
+    fn test_plan_tree() {
+        let rows = vec![
+            (2, 0, "SCAN dogs".to_string()),
+            (5, 0, "SCAN (subquery-1)".to_string()),
+            (7, 5, "SEARCH owners USING INDEX owners_name (name=?)".to_string()),
+            (9, 0, "SCAN TABLE cats".to_string()),
+        ];
+        let indexed = vec!["dogs".to_string()].into_iter().collect();
+        let plan = QueryPlan::from_rows(&rows, &indexed);
+        assert_eq!(3, plan.steps.len());
+        assert_eq!(7, plan.steps[1].children[0].id);
+        assert_eq!(
+            vec![Some("dogs"), None, None, Some("cats")],
+            plan.iter().map(|s| s.full_scan()).collect::<Vec<_>>()
+        );
+        assert_eq!(vec![2], plan.full_scans_of_indexed_tables().iter().map(|s| s.id).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn test_aliased_table() {
+        let sql = "SELECT d.name FROM dogs d JOIN owners AS o ON o.dog = d.name, \"cats\" c";
+        assert_eq!(Some("dogs"), aliased_table(sql, "d"));
+        assert_eq!(Some("owners"), aliased_table(sql, "O"));
+        assert_eq!(Some("cats"), aliased_table(sql, "c"));
+        assert_eq!(None, aliased_table(sql, "x"));
+        assert_eq!(None, full_scan("SCAN TABLE dogs AS d USING INDEX dogs_color"));
+        assert_eq!(Some("d"), full_scan("SCAN d"));
+    }
+
+    #[test]
+    fn test_explain() {
+        let repo = memory_repository();
+        let query = DogQuery {
+            q_color: Some("white"),
+            ..Default::default()
+        };
+        let plan = repo.explain(&Q_DOGS_SELECT, query).unwrap();
+        assert!(plan.iter().any(|s| s.detail.contains("dogs_color")), "{}", plan);
+        plan.assert_no_full_scan();
+    }
+
+    #[test]
+    #[should_panic(expected = "full scan of indexed tables")]
+    fn test_explain_full_scan() {
+        let repo = memory_repository();
+        let query = DogQuery {
+            q_name: Some("Je"),
+            ..Default::default()
+        };
+        repo.explain(&Q_DOGS_SELECT, query).unwrap().assert_no_full_scan();
+    }
+
+    #[test]
+    fn test_explain_alias() {
+        const Q_ALIAS: (&str, &str) = ("Q_ALIAS", "SELECT d.color FROM dogs AS d WHERE d.name LIKE :q_name");
+        let repo = RepositoryBuilder::memory()
+            .template(&Q_ALIAS)
+            .init(DDL)
+            .build()
+            .unwrap();
+        let query = DogQuery {
+            q_name: Some("Je"),
+            ..Default::default()
+        };
+        let plan = repo.explain(&Q_ALIAS, query).unwrap();
+        assert_eq!(1, plan.full_scans_of_indexed_tables().len(), "{}", plan);
+    }
+}
@@ -0,0 +1,160 @@
+use rusqlite::Row;
+
+use crate::error::{Error, Result};
+
+/// Decides what happens when a row cannot be mapped, i.e. the row mapper of a query returns `Err`.
+/// Errors of the database while stepping through the rows are returned whatever the policy.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum RowErrorPolicy {
+    /// Stop at the first failing row and return [Error::RowMappingError] with its index.
+    #[default]
+    FailFast,
+    /// Map all rows and collect the failures. [DynamicSqlExecutor::query](crate::dynamic_sql::DynamicSqlExecutor::query)
+    /// returns [Error::RowMappingErrors] if there is any, while
+    /// [DynamicSqlExecutor::query_with_policy](crate::dynamic_sql::DynamicSqlExecutor::query_with_policy)
+    /// returns them alongside the rows that are mapped successfully.
+    Collect,
+    /// Skip failing rows and only log a warning.
+    Skip,
+}
+
+/// A row that failed to be mapped.
+#[derive(Debug)]
+pub struct RowError {
+    /// The index of the row in the result, starting from `0`.
+    pub index: usize,
+    pub error: rusqlite::Error,
+}
+
+/// Rows mapped under a [RowErrorPolicy]. `errors` can only be non-empty for [RowErrorPolicy::Collect].
+#[derive(Debug)]
+pub struct CollectedRows<T> {
+    pub rows: Vec<T>,
+    pub errors: Vec<RowError>,
+}
+
+impl RowErrorPolicy {
+    /// Consume mapped `rows` according to this policy. The outer [Result] of a row is the result of
+    /// stepping to it, the inner one that of the row mapper, see [RowErrorPolicy::mapper].
+    pub(crate) fn collect<T, I>(self, rows: I) -> Result<CollectedRows<T>>
+        where
+            I: Iterator<Item = rusqlite::Result<rusqlite::Result<T>>>,
+    {
+        let mut collected = CollectedRows {
+            rows: vec![],
+            errors: vec![],
+        };
+        for (index, row) in rows.enumerate() {
+            match row? {
+                Ok(row) => collected.rows.push(row),
+                Err(error) => match self {
+                    RowErrorPolicy::FailFast => {
+                        return Err(Error::RowMappingError { index, source: error })
+                    }
+                    RowErrorPolicy::Collect => collected.errors.push(RowError { index, error }),
+                    RowErrorPolicy::Skip => {
+                        log::warn!("failed to map row {}, the error is: {}", index, error)
+                    }
+                },
+            }
+        }
+        Ok(collected)
+    }
+
+    /// Wrap the row mapper `f`, so that [RowErrorPolicy::collect] can tell its errors from those of
+    /// the database.
+    pub(crate) fn mapper<T, F>(mut f: F) -> impl FnMut(&Row<'_>) -> rusqlite::Result<rusqlite::Result<T>>
+        where
+            F: FnMut(&Row<'_>) -> rusqlite::Result<T>,
+    {
+        move |row| Ok(f(row))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::dynamic_sql::executor::dog::*;
+    use crate::dynamic_sql::{DynamicSqlExecutor, RepositoryBuilder};
+
+    use super::*;
+
+    /// Fails for dogs whose color is NULL.
+    fn map_color(row: &Row<'_>) -> rusqlite::Result<String> {
+        row.get("color")
+    }
+
+    fn repository() -> crate::dynamic_sql::Repository<'static> {
+        let repo = memory_repository();
+        for (name, color) in &[("Jeff", Some("white")), ("Bob", None), ("Tom", None)] {
+            let dog = DogInsert {
+                color: *color,
+                ..dog_insert(name)
+            };
+            repo.execute(&Q_DOGS_INSERT, dog).unwrap();
+        }
+        repo
+    }
+
+    #[test]
+    fn test_fail_fast() {
+        let repo = repository();
+        let result = repo.query(&Q_DOGS_SELECT, DogQuery::default(), map_color);
+        assert!(matches!(result, Err(Error::RowMappingError { index: 1, .. })));
+    }
+
+    #[test]
+    fn test_collect() {
+        let repo = repository().with_row_error_policy(RowErrorPolicy::Collect);
+        let result = repo.query(&Q_DOGS_SELECT, DogQuery::default(), map_color);
+        match result {
+            Err(Error::RowMappingErrors(errors)) => {
+                assert_eq!(vec![1, 2], errors.iter().map(|e| e.index).collect::<Vec<_>>())
+            }
+            _ => panic!("expected errors for rows 1 and 2"),
+        }
+
+        let collected = repo
+            .query_with_policy(&Q_DOGS_SELECT, DogQuery::default(), RowErrorPolicy::Collect, map_color)
+            .unwrap();
+        assert_eq!(vec!["white"], collected.rows);
+        assert_eq!(2, collected.errors.len());
+    }
+
+    #[test]
+    fn test_skip() {
+        let repo = repository().with_row_error_policy(RowErrorPolicy::Skip);
+        let colors = repo.query(&Q_DOGS_SELECT, DogQuery::default(), map_color).unwrap();
+        assert_eq!(vec!["white"], colors);
+    }
+
+    #[test]
+    fn test_step_errors() {
+        // `abs` of the smallest integer fails when stepping to the first dog without a color
+        const Q_DOGS_ABS: (&str, &str) = (
+            "Q_DOGS_ABS",
+            "SELECT name, CASE WHEN color IS NULL THEN abs(-9223372036854775807 - 1) END AS abs \
+            FROM dogs ORDER BY name DESC",
+        );
+        let repo = RepositoryBuilder::memory()
+            .templates(&[Q_DOGS_INSERT, Q_DOGS_ABS])
+            .init(DDL)
+            .build()
+            .unwrap();
+        for (name, color) in &[("Jeff", Some("white")), ("Bob", None)] {
+            let dog = DogInsert {
+                color: *color,
+                ..dog_insert(name)
+            };
+            repo.execute(&Q_DOGS_INSERT, dog).unwrap();
+        }
+        for policy in &[RowErrorPolicy::FailFast, RowErrorPolicy::Collect, RowErrorPolicy::Skip] {
+            let result = repo.query_with_policy(&Q_DOGS_ABS, DogQuery::default(), *policy, |row| {
+                row.get::<_, String>(0)
+            });
+            assert!(matches!(result, Err(Error::DatabaseError(_))), "{:?}", policy);
+        }
+        let repo = repo.with_row_error_policy(RowErrorPolicy::Skip);
+        let result = repo.query(&Q_DOGS_ABS, DogQuery::default(), |row| row.get::<_, String>(0));
+        assert!(matches!(result, Err(Error::DatabaseError(_))));
+    }
+}
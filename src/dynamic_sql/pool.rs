@@ -0,0 +1,338 @@
+use std::path::Path;
+use std::time::Duration;
+
+use r2d2::Pool;
+use r2d2_sqlite::SqliteConnectionManager;
+use rusqlite::{Connection, Row};
+
+use crate::dynamic_sql::cache::CacheStats;
+use crate::dynamic_sql::engine::Engine;
+use crate::dynamic_sql::executor::{DynamicSqlExecutor, QueryRows};
+use crate::dynamic_sql::interceptor::Interceptor;
+use crate::dynamic_sql::migration::Migrations;
+use crate::dynamic_sql::plan::QueryPlan;
+use crate::dynamic_sql::policy::RowErrorPolicy;
+use crate::dynamic_sql::query::{DynamicQueryParameters, RenderedQuery};
+use crate::dynamic_sql::template::SqlTemplate;
+use crate::dynamic_sql::transaction::Transaction;
+use crate::error::Result;
+
+type InitHook = Box<dyn Fn(&mut Connection) -> rusqlite::Result<()> + Send + Sync>;
+
+/// Options for creating a [PooledRepository].
+pub struct PoolOptions {
+    max_size: u32,
+    connection_timeout: Duration,
+    /// Only set on the connections if configured, so that rusqlite's default applies otherwise.
+    cache_capacity: Option<usize>,
+    row_error_policy: RowErrorPolicy,
+    init_hooks: Vec<InitHook>,
+    interceptors: Vec<Box<dyn Interceptor>>,
+    #[cfg(feature = "tracing")]
+    trace_values: bool,
+}
+
+impl Default for PoolOptions {
+    fn default() -> Self {
+        PoolOptions {
+            max_size: 10,
+            connection_timeout: Duration::from_secs(30),
+            cache_capacity: None,
+            row_error_policy: RowErrorPolicy::default(),
+            init_hooks: vec![],
+            interceptors: vec![],
+            #[cfg(feature = "tracing")]
+            trace_values: false,
+        }
+    }
+}
+
+impl PoolOptions {
+    /// The maximum number of connections managed by the pool.
+    pub fn max_size(mut self, max_size: u32) -> Self {
+        self.max_size = max_size;
+        self
+    }
+
+    /// How long to wait for a free connection before giving up with [Error::PoolError](crate::Error::PoolError).
+    pub fn connection_timeout(mut self, timeout: Duration) -> Self {
+        self.connection_timeout = timeout;
+        self
+    }
+
+    /// Cache rendered SQL and prepared statements, see [Repository::with_cache](crate::dynamic_sql::Repository::with_cache).
+    /// The rendered SQL cache is shared by all connections while each connection caches up to
+    /// `capacity` prepared statements.
+    pub fn cache_capacity(mut self, capacity: usize) -> Self {
+        self.cache_capacity = Some(capacity);
+        self
+    }
+
+    /// Set how rows that fail to be mapped are handled, see [RowErrorPolicy].
+    pub fn row_error_policy(mut self, policy: RowErrorPolicy) -> Self {
+        self.row_error_policy = policy;
+        self
+    }
+
+    /// Run `interceptor` around every statement, see [Repository::with_interceptor](crate::dynamic_sql::Repository::with_interceptor).
+    pub fn interceptor<I: Interceptor + 'static>(mut self, interceptor: I) -> Self {
+        self.interceptors.push(Box::new(interceptor));
+        self
+    }
+
+    /// See [RepositoryBuilder::trace_param_values](crate::dynamic_sql::RepositoryBuilder::trace_param_values).
+    #[cfg(feature = "tracing")]
+    pub fn trace_param_values(mut self, enabled: bool) -> Self {
+        self.trace_values = enabled;
+        self
+    }
+
+    /// Add a hook that is run on every newly opened connection, e.g. for setting pragmas.
+    /// Hooks are run in the order they are added.
+    pub fn on_connect<F>(mut self, hook: F) -> Self
+        where
+            F: Fn(&mut Connection) -> rusqlite::Result<()> + Send + Sync + 'static,
+    {
+        self.init_hooks.push(Box::new(hook));
+        self
+    }
+}
+
+/// A [DynamicSqlExecutor] backed by a pool of connections to the same database. Unlike
+/// [Repository](crate::dynamic_sql::Repository) it is [Sync], so it can be shared between threads
+/// (e.g. in an [Arc](std::sync::Arc)) without a [Mutex](std::sync::Mutex). Every call checks out
+/// a connection for its duration, while all connections share the same compiled templates.
+pub struct PooledRepository<'reg> {
+    pool: Pool<SqliteConnectionManager>,
+    engine: Engine<'reg>,
+}
+
+impl<'reg> PooledRepository<'reg> {
+    pub fn new<'a, P, T, I>(file: &P, templates: &'a T, options: PoolOptions) -> Result<Self>
+        where
+            P: AsRef<Path> + ?Sized,
+            &'a T: IntoIterator<Item = &'a I>,
+            I: SqlTemplate + 'a,
+    {
+        let mut engine = Engine::new(templates)?;
+        engine.set_cache_capacity(options.cache_capacity.unwrap_or(0));
+        engine.set_row_error_policy(options.row_error_policy);
+        #[cfg(feature = "tracing")]
+        engine.set_trace_values(options.trace_values);
+        for interceptor in options.interceptors {
+            engine.add_interceptor(interceptor);
+        }
+        let cache_capacity = options.cache_capacity;
+        let hooks = options.init_hooks;
+        let manager = SqliteConnectionManager::file(file).with_init(move |conn| {
+            if let Some(capacity) = cache_capacity {
+                conn.set_prepared_statement_cache_capacity(capacity);
+            }
+            for hook in &hooks {
+                hook(conn)?;
+            }
+            Ok(())
+        });
+        let pool = Pool::builder()
+            .max_size(options.max_size)
+            .connection_timeout(options.connection_timeout)
+            .build(manager)?;
+        Ok(PooledRepository { pool, engine })
+    }
+
+    /// Hit/miss statistics of the rendered SQL cache, or [None] if caching is disabled.
+    pub fn cache_stats(&self) -> Option<CacheStats> {
+        self.engine.cache_stats()
+    }
+
+    /// Check out a connection and run `f` inside a transaction on it.
+    /// See [Repository::transaction](crate::dynamic_sql::Repository::transaction).
+    pub fn transaction<F, R>(&self, f: F) -> Result<R>
+        where
+            F: FnOnce(&Transaction<'_>) -> Result<R>,
+    {
+        let conn = self.pool.get()?;
+        Transaction::run(&conn, &self.engine, f)
+    }
+
+    /// Check out a connection and validate all templates with it.
+    /// See [Repository::validate](crate::dynamic_sql::Repository::validate).
+    pub fn validate(&self) -> Result<()> {
+        let conn = self.pool.get()?;
+        self.engine.validate(&conn)
+    }
+
+    /// Check out a connection and apply the pending `migrations` with it, see [Migrations::apply].
+    pub fn migrate(&self, migrations: &Migrations) -> Result<Vec<u32>> {
+        let mut conn = self.pool.get()?;
+        migrations.apply(&mut conn)
+    }
+}
+
+impl<'reg> DynamicSqlExecutor for PooledRepository<'reg> {
+    fn query<S, P, F, T>(&self, template: &S, params: P, f: F) -> Result<Vec<T>>
+        where
+            S: SqlTemplate,
+            P: DynamicQueryParameters,
+            F: FnMut(&Row<'_>) -> rusqlite::Result<T>,
+    {
+        let conn = self.pool.get()?;
+        self.engine.query(&conn, template, params, f)
+    }
+
+    fn query_derived<S, P, D, F, T, C, R>(
+        &self,
+        template: &S,
+        params: P,
+        derive: D,
+        f: F,
+        consume: C,
+    ) -> Result<R>
+        where
+            S: SqlTemplate,
+            P: DynamicQueryParameters,
+            D: FnOnce(String) -> String,
+            F: FnMut(&Row<'_>) -> rusqlite::Result<T>,
+            C: FnOnce(QueryRows<'_, F>) -> Result<R>,
+    {
+        let conn = self.pool.get()?;
+        self.engine.query_derived(&conn, template, params, derive, f, consume)
+    }
+
+    fn execute<S, P>(&self, template: &S, params: P) -> Result<usize>
+        where
+            S: SqlTemplate,
+            P: DynamicQueryParameters,
+    {
+        let conn = self.pool.get()?;
+        self.engine.execute(&conn, template, params)
+    }
+
+    fn execute_returning_rowid<S, P>(&self, template: &S, params: P) -> Result<i64>
+        where
+            S: SqlTemplate,
+            P: DynamicQueryParameters,
+    {
+        let conn = self.pool.get()?;
+        self.engine.execute_returning_rowid(&conn, template, params)
+    }
+
+    fn render<S, P>(&self, template: &S, params: P) -> Result<RenderedQuery>
+        where
+            S: SqlTemplate,
+            P: DynamicQueryParameters,
+    {
+        self.engine.render_query(template, params)
+    }
+
+    fn explain<S, P>(&self, template: &S, params: P) -> Result<QueryPlan>
+        where
+            S: SqlTemplate,
+            P: DynamicQueryParameters,
+    {
+        let conn = self.pool.get()?;
+        self.engine.explain(&conn, template, params)
+    }
+
+    fn execute_returning_one<S, P, F, T>(&self, template: &S, params: P, f: F) -> Result<T>
+        where
+            S: SqlTemplate,
+            P: DynamicQueryParameters,
+            F: FnMut(&Row<'_>) -> rusqlite::Result<T>,
+    {
+        let conn = self.pool.get()?;
+        Transaction::run_savepoint(&conn, &self.engine, |sp| sp.query_one(template, params, f))
+    }
+
+    fn execute_batch_chunked<S, P, I>(
+        &self,
+        template: &S,
+        params: I,
+        chunk_size: usize,
+    ) -> Result<Vec<usize>>
+        where
+            S: SqlTemplate,
+            P: DynamicQueryParameters,
+            I: IntoIterator<Item = P>,
+    {
+        let conn = self.pool.get()?;
+        Transaction::batch(&conn, &self.engine, 0, template, params, chunk_size)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use std::{env, fs, thread};
+
+    use crate::dynamic_sql::executor::dog::*;
+    use crate::error::Error;
+
+    use super::*;
+
+    const TEMPLATES: [(&str, &str); 3] = [Q_DOGS_INSERT, Q_DOGS_SELECT, Q_DOGS_WHERE];
+
+    fn repository(name: &str, options: PoolOptions) -> PooledRepository<'static> {
+        let file = env::temp_dir().join(name);
+        if file.exists() {
+            fs::remove_file(&file).unwrap();
+        }
+        Connection::open(&file).unwrap().execute_batch(DDL).unwrap();
+        PooledRepository::new(&file, &TEMPLATES, options).unwrap()
+    }
+
+    #[test]
+    fn test_shared_between_threads() {
+        let repo = Arc::new(repository(
+            "pooled_dog_store_test",
+            PoolOptions::default()
+                .max_size(4)
+                .on_connect(|conn| conn.busy_timeout(Duration::from_secs(5))),
+        ));
+        let names = ["Jeff", "Bob", "Tom", "Max", "Rex", "Leo"];
+        let handles = names
+            .iter()
+            .copied()
+            .map(|name| {
+                let repo = repo.clone();
+                thread::spawn(move || repo.execute(&Q_DOGS_INSERT, dog_insert(name)).unwrap())
+            })
+            .collect::<Vec<_>>();
+        for h in handles {
+            assert_eq!(1, h.join().unwrap());
+        }
+        let dogs = repo
+            .query(&Q_DOGS_SELECT, DogQuery::default(), |row| row.get::<_, String>("name"))
+            .unwrap();
+        assert_eq!(names.len(), dogs.len());
+    }
+
+    #[test]
+    fn test_init_hooks_and_timeout() {
+        let connected = Arc::new(AtomicUsize::new(0));
+        let counter = connected.clone();
+        let repo = repository(
+            "pooled_dog_store_timeout_test",
+            PoolOptions::default()
+                .max_size(1)
+                .connection_timeout(Duration::from_millis(100))
+                .on_connect(move |_| {
+                    counter.fetch_add(1, Ordering::SeqCst);
+                    Ok(())
+                }),
+        );
+        assert_eq!(1, connected.load(Ordering::SeqCst));
+
+        let result = repo.transaction(|tx| {
+            tx.execute(&Q_DOGS_INSERT, dog_insert("Jeff"))?;
+            // the only connection is held by this transaction
+            repo.execute(&Q_DOGS_INSERT, dog_insert("Bob"))
+        });
+        assert!(matches!(result, Err(Error::PoolError(_))));
+        let dogs = repo
+            .query(&Q_DOGS_SELECT, DogQuery::default(), |row| row.get::<_, String>("name"))
+            .unwrap();
+        assert!(dogs.is_empty());
+    }
+}
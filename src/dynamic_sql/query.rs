@@ -1,10 +1,13 @@
-use std::collections::HashMap;
+use std::borrow::Cow;
+use std::collections::{HashMap, HashSet};
 
-use rusqlite::types::ToSqlOutput::{Borrowed, Owned};
+use rusqlite::types::ToSqlOutput::{Borrowed, Owned, ZeroBlob};
 use rusqlite::types::Value::{Integer, Real, Text};
+use rusqlite::types::Value;
 use rusqlite::{ToSql};
 
-use crate::error::Result;
+use crate::dynamic_sql::nested::{sqlite_param_name, ParamNames};
+use crate::error::{Error, Result};
 
 /// [DynamicParam] represents a key-value pair that is going to be used in a Dynamic SQL query.
 /// Typically the end user will not construct it directly but will use query object which can be
@@ -12,9 +15,14 @@ use crate::error::Result;
 ///
 /// The value can be of different types so it has to be boxed. The key is `'static` because we know
 /// at compile time the keys of query parameters. What we need to do at runtime is to determine which
-/// keys need to be present by checking their values.
+/// keys need to be present by checking their values. Keys prefixed for nested query types are built
+/// once for every nested field from [DynamicQueryParameters::PARAMS].
 pub type DynamicParam<'p> = (&'static str, &'p dyn ToSql);
 
+/// A bind parameter with a list of values, e.g. for `id IN (:ids)`, see
+/// [DynamicQueryParameters::for_lists].
+pub type DynamicListParam<'p> = (&'static str, Vec<&'p dyn ToSql>);
+
 /// Same as [Display], but need a custom trait so that it can be implemented for [ToSql].
 pub trait ToSqlSegment {
     fn to_sql_segment(&self) -> Result<String>;
@@ -39,6 +47,14 @@ impl<T: ToSql> ToSqlSegment for T {
 
 /// Defines behavior for a query type.
 pub trait DynamicQueryParameters {
+    /// The names of the parameters of this type and of the query types nested in it. Query types
+    /// declared by [new_query_type](crate::new_query_type) or derived with the `derive` feature
+    /// declare them, so that the parameters a nested type has in common with the referencing type
+    /// fail to compile. The parameters of a type that does not declare them are only checked when
+    /// rendering, see [Error::DuplicateParams](crate::Error::DuplicateParams), and it cannot be
+    /// nested with a prefix, which panics.
+    const PARAMS: &'static ParamNames = &ParamNames::EMPTY;
+
     /// Provides context for rendering SQL template. During this phase, for most parameters it is
     /// enough just to know whether values are provided or not. And if a parameter need to be substituted
     /// at this stage, the value need to be provided as [String].
@@ -52,4 +68,101 @@ pub trait DynamicQueryParameters {
     /// the query type is constructed. Their references are created in the implementation of this
     /// function.
     fn for_execution(&self) -> Vec<DynamicParam<'_>>;
+
+    /// Bind parameters with a list of values. A template uses them with the `in` helper, e.g.
+    /// `{{#in [:ids]}}id IN (:ids){{/in}}`, which expands `:ids` into one bind parameter per value,
+    /// `:ids_1, :ids_2, ...`. For rendering, [DynamicQueryParameters::for_render] provides the
+    /// number of values.
+    fn for_lists(&self) -> Vec<DynamicListParam<'_>> {
+        vec![]
+    }
+}
+
+/// Allows the same query parameters to be used for several queries, e.g. for counting and fetching rows.
+impl<T: DynamicQueryParameters + ?Sized> DynamicQueryParameters for &T {
+    const PARAMS: &'static ParamNames = T::PARAMS;
+
+    fn for_render(&self) -> HashMap<&'static str, String> {
+        (**self).for_render()
+    }
+
+    fn for_execution(&self) -> Vec<DynamicParam<'_>> {
+        (**self).for_execution()
+    }
+
+    fn for_lists(&self) -> Vec<DynamicListParam<'_>> {
+        (**self).for_lists()
+    }
+}
+
+/// The name of the bind parameter for the value at `index` of the list parameter `name`.
+pub(crate) fn list_param_name(name: &str, index: usize) -> String {
+    format!("{}_{}", name, index + 1)
+}
+
+/// All bind parameters of `params` in the order they are bound, with every list parameter
+/// expanded into one parameter per value and prefixed parameters named like in SQLite, see
+/// [sqlite_param_name]. A value of a list parameter, e.g. `:ids_1` of `:ids`, must not have the
+/// name of another parameter.
+pub(crate) fn bind_params<P: DynamicQueryParameters + ?Sized>(params: &P) -> Result<Vec<(Cow<'static, str>, &dyn ToSql)>> {
+    let mut bound = params
+        .for_execution()
+        .into_iter()
+        .map(|(k, v)| (sqlite_param_name(k), v))
+        .collect::<Vec<_>>();
+    let lists = params.for_lists();
+    if lists.is_empty() {
+        return Ok(bound);
+    }
+    let names = bound.iter().map(|(k, _)| k.to_string()).collect::<HashSet<_>>();
+    for (name, values) in lists {
+        for (i, v) in values.into_iter().enumerate() {
+            let name = sqlite_param_name(&list_param_name(name, i)).into_owned();
+            if names.contains(&name) {
+                return Err(Error::ListParamCollision(name));
+            }
+            bound.push((Cow::Owned(name), v));
+        }
+    }
+    Ok(bound)
+}
+
+/// The SQL rendered from a template and the values that would be bound to it, see
+/// [DynamicSqlExecutor::render](crate::dynamic_sql::DynamicSqlExecutor::render).
+#[derive(Debug, Clone, PartialEq)]
+pub struct RenderedQuery {
+    pub sql: String,
+    /// Named parameters such as `:name` in the order they are bound.
+    pub params: Vec<(String, Value)>,
+}
+
+impl RenderedQuery {
+    pub(crate) fn new<P: DynamicQueryParameters + ?Sized>(sql: String, params: &P) -> Result<Self> {
+        let params = bind_params(params)?
+            .into_iter()
+            .map(|(k, v)| Ok((k.into_owned(), to_value(v)?)))
+            .collect::<Result<Vec<_>>>()?;
+        Ok(RenderedQuery { sql, params })
+    }
+
+    /// The value bound to the parameter `name`, e.g. `:name`.
+    pub fn param(&self, name: &str) -> Option<&Value> {
+        self.params.iter().find(|(k, _)| k == name).map(|(_, v)| v)
+    }
+}
+
+/// The SQLite value that `v` is bound as.
+pub(crate) fn to_value(v: &dyn ToSql) -> Result<Value> {
+    let value = match v.to_sql()? {
+        Borrowed(v) => Value::from(v),
+        Owned(v) => v,
+        // SQLite treats a negative length like 0
+        ZeroBlob(len) => Value::Blob(vec![0; len.max(0) as usize]),
+        // e.g. arrays for the `rarray` table-valued function, which have no value of their own
+        output => {
+            let message = format!("{:?} cannot be represented as a value", output);
+            return Err(rusqlite::Error::ToSqlConversionFailure(message.into()).into());
+        }
+    };
+    Ok(value)
 }
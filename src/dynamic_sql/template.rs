@@ -13,3 +13,13 @@ impl SqlTemplate for (&str, &str) {
         self.1
     }
 }
+
+impl SqlTemplate for (String, String) {
+    fn name(&self) -> &str {
+        &self.0
+    }
+
+    fn sql(&self) -> &str {
+        &self.1
+    }
+}
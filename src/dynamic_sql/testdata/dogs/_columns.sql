@@ -0,0 +1 @@
+name, color, weight
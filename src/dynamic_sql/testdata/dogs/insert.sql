@@ -0,0 +1,2 @@
+INSERT INTO dogs(name, color, weight)
+VALUES(:name, :color, :weight)
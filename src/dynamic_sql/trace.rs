@@ -0,0 +1,243 @@
+use std::time::Duration;
+
+use rusqlite::types::Value;
+
+use crate::dynamic_sql::interceptor::CallKind;
+use crate::error::Error;
+
+/// The span of a single statement when the `tracing` feature is enabled; a no-op otherwise.
+///
+/// Spans are named `query` or `execute` and have the fields `template`, `params` (the names of
+/// the bound parameters), `values` (only if enabled with
+/// [RepositoryBuilder::trace_param_values](crate::dynamic_sql::RepositoryBuilder::trace_param_values)),
+/// `sql_len`, `render_us`, `prepare_us`, `step_us`, `rows` and `error`.
+pub(crate) struct Trace {
+    #[cfg(feature = "tracing")]
+    span: tracing::Span,
+}
+
+#[cfg(feature = "tracing")]
+pub(crate) type Entered<'a> = tracing::span::Entered<'a>;
+#[cfg(not(feature = "tracing"))]
+pub(crate) type Entered<'a> = std::marker::PhantomData<&'a ()>;
+
+#[cfg(feature = "tracing")]
+impl Trace {
+    pub(crate) fn new(kind: CallKind, template: &str) -> Self {
+        use tracing::field::Empty;
+        macro_rules! span {
+            ($name:literal) => {
+                tracing::debug_span!(
+                    target: "shunlib::dynamic_sql",
+                    $name,
+                    template,
+                    params = Empty,
+                    values = Empty,
+                    sql_len = Empty,
+                    render_us = Empty,
+                    prepare_us = Empty,
+                    step_us = Empty,
+                    rows = Empty,
+                    error = Empty,
+                )
+            };
+        }
+        let span = match kind {
+            CallKind::Query => span!("query"),
+            CallKind::Execute => span!("execute"),
+        };
+        Trace { span }
+    }
+
+    pub(crate) fn enter(&self) -> Entered<'_> {
+        self.span.enter()
+    }
+
+    /// Whether the span is recorded by a subscriber, so that the values of its fields are needed.
+    pub(crate) fn enabled(&self) -> bool {
+        !self.span.is_disabled()
+    }
+
+    pub(crate) fn rendered(&self, elapsed: Duration, sql: &str) {
+        self.span.record("render_us", elapsed.as_micros() as u64);
+        self.span.record("sql_len", sql.len() as u64);
+    }
+
+    pub(crate) fn params<'a, I: Iterator<Item = &'a str>>(&self, names: I) {
+        if self.enabled() {
+            self.span
+                .record("params", tracing::field::display(names.collect::<Vec<_>>().join(",")));
+        }
+    }
+
+    pub(crate) fn values(&self, values: &[(String, Value)]) {
+        if self.enabled() {
+            self.span.record("values", tracing::field::debug(values));
+        }
+    }
+
+    pub(crate) fn prepared(&self, elapsed: Duration) {
+        self.span.record("prepare_us", elapsed.as_micros() as u64);
+    }
+
+    pub(crate) fn stepped(&self, elapsed: Duration) {
+        self.span.record("step_us", elapsed.as_micros() as u64);
+    }
+
+    pub(crate) fn finished(&self, result: &std::result::Result<Option<usize>, &Error>) {
+        match result {
+            Ok(Some(rows)) => {
+                self.span.record("rows", *rows as u64);
+            }
+            Ok(None) => {}
+            Err(e) => self.failed(e),
+        }
+    }
+
+    pub(crate) fn failed(&self, error: &Error) {
+        self.span.record("error", tracing::field::display(error));
+        tracing::event!(target: "shunlib::dynamic_sql", tracing::Level::DEBUG, error = %error, "statement failed");
+    }
+}
+
+#[cfg(not(feature = "tracing"))]
+impl Trace {
+    #[inline]
+    pub(crate) fn new(_kind: CallKind, _template: &str) -> Self {
+        Trace {}
+    }
+
+    #[inline]
+    pub(crate) fn enter(&self) -> Entered<'_> {
+        std::marker::PhantomData
+    }
+
+    #[inline]
+    pub(crate) fn enabled(&self) -> bool {
+        false
+    }
+
+    #[inline]
+    pub(crate) fn rendered(&self, _elapsed: Duration, _sql: &str) {}
+
+    #[inline]
+    pub(crate) fn params<'a, I: Iterator<Item = &'a str>>(&self, _names: I) {}
+
+    #[inline]
+    pub(crate) fn values(&self, _values: &[(String, Value)]) {}
+
+    #[inline]
+    pub(crate) fn prepared(&self, _elapsed: Duration) {}
+
+    #[inline]
+    pub(crate) fn stepped(&self, _elapsed: Duration) {}
+
+    #[inline]
+    pub(crate) fn finished(&self, _result: &std::result::Result<Option<usize>, &Error>) {}
+
+    #[inline]
+    pub(crate) fn failed(&self, _error: &Error) {}
+}
+
+#[cfg(all(test, feature = "tracing"))]
+mod test {
+    use std::collections::HashMap;
+    use std::fmt::Debug;
+    use std::sync::{Arc, Mutex};
+
+    use tracing::field::{Field, Visit};
+    use tracing::span::{Attributes, Id, Record};
+    use tracing::{Event, Metadata, Subscriber};
+
+    use crate::dynamic_sql::executor::dog::*;
+    use crate::dynamic_sql::{DynamicSqlExecutor, RepositoryBuilder};
+
+    /// Fields recorded for every span, in the order the spans were created.
+    type Spans = Arc<Mutex<Vec<(&'static str, HashMap<String, String>)>>>;
+
+    struct Capture(Spans);
+
+    struct Fields<'a>(&'a mut HashMap<String, String>);
+
+    impl<'a> Visit for Fields<'a> {
+        fn record_debug(&mut self, field: &Field, value: &dyn Debug) {
+            self.0.insert(field.name().to_string(), format!("{:?}", value));
+        }
+    }
+
+    impl Subscriber for Capture {
+        fn enabled(&self, _: &Metadata<'_>) -> bool {
+            true
+        }
+
+        fn new_span(&self, span: &Attributes<'_>) -> Id {
+            let mut spans = self.0.lock().unwrap();
+            let mut fields = HashMap::new();
+            span.record(&mut Fields(&mut fields));
+            spans.push((span.metadata().name(), fields));
+            Id::from_u64(spans.len() as u64)
+        }
+
+        fn record(&self, span: &Id, values: &Record<'_>) {
+            let mut spans = self.0.lock().unwrap();
+            let (_, fields) = &mut spans[span.into_u64() as usize - 1];
+            values.record(&mut Fields(fields));
+        }
+
+        fn record_follows_from(&self, _: &Id, _: &Id) {}
+
+        fn event(&self, _: &Event<'_>) {}
+
+        fn enter(&self, _: &Id) {}
+
+        fn exit(&self, _: &Id) {}
+    }
+
+    #[test]
+    fn test_spans() {
+        let spans = Spans::default();
+        tracing::subscriber::with_default(Capture(spans.clone()), || {
+            let repo = RepositoryBuilder::memory()
+                .templates(&[Q_DOGS_INSERT, Q_DOGS_SELECT, Q_DOGS_WHERE])
+                .init(DDL)
+                .trace_param_values(true)
+                .build()
+                .unwrap();
+            repo.execute(&Q_DOGS_INSERT, dog_insert("Jeff")).unwrap();
+            assert!(repo.execute(&Q_DOGS_INSERT, dog_insert("Jeff")).is_err());
+            let query = DogQuery {
+                q_color: Some("white"),
+                ..Default::default()
+            };
+            repo.query(&Q_DOGS_SELECT, query, |row| row.get::<_, String>("name"))
+                .unwrap();
+        });
+
+        let spans = spans.lock().unwrap();
+        assert_eq!(
+            vec!["execute", "execute", "query"],
+            spans.iter().map(|(name, _)| *name).collect::<Vec<_>>()
+        );
+        let (_, insert) = &spans[0];
+        assert_eq!("\"Q_DOGS_INSERT\"", insert["template"]);
+        assert_eq!(":name,:color,:weight", insert["params"]);
+        assert!(insert["values"].contains("Text(\"Jeff\")"), "{:?}", insert);
+        assert_eq!("1", insert["rows"]);
+        for field in &["sql_len", "render_us", "prepare_us", "step_us"] {
+            assert!(insert.contains_key(*field), "{} is missing", field);
+        }
+        assert!(spans[1].1["error"].contains("database"), "{:?}", spans[1].1);
+        assert_eq!(":q_color", spans[2].1["params"]);
+        assert_eq!("1", spans[2].1["rows"]);
+    }
+
+    #[test]
+    fn test_disabled() {
+        use crate::dynamic_sql::CallKind;
+        // without a subscriber, names and values are neither collected nor converted
+        assert!(!super::Trace::new(CallKind::Execute, "Q_DOGS_INSERT").enabled());
+        tracing::subscriber::with_default(Capture(Spans::default()), || {
+            assert!(super::Trace::new(CallKind::Execute, "Q_DOGS_INSERT").enabled());
+        });
+    }
+}
@@ -0,0 +1,331 @@
+use rusqlite::{Connection, Row};
+
+use crate::dynamic_sql::engine::{BatchShapes, Engine};
+use crate::dynamic_sql::executor::{DynamicSqlExecutor, QueryRows};
+use crate::dynamic_sql::plan::QueryPlan;
+use crate::dynamic_sql::query::{DynamicQueryParameters, RenderedQuery};
+use crate::dynamic_sql::template::SqlTemplate;
+use crate::error::Result;
+
+/// [Transaction] is a [DynamicSqlExecutor] whose statements are run inside a database transaction.
+/// It is handed to the closure passed to [Repository::transaction](crate::dynamic_sql::Repository::transaction),
+/// and templates are rendered and bound exactly the same way as the repository does.
+///
+/// Transactions can be nested by calling [Transaction::savepoint], which wraps the closure in a
+/// `SAVEPOINT` so that it can be rolled back without affecting the enclosing transaction.
+pub struct Transaction<'c> {
+    conn: &'c Connection,
+    engine: &'c Engine<'c>,
+    depth: usize,
+}
+
+impl<'c> Transaction<'c> {
+    /// Start a top-level transaction on `conn` and run `f` inside it.
+    pub(crate) fn run<F, R>(conn: &'c Connection, engine: &'c Engine<'c>, f: F) -> Result<R>
+        where
+            F: FnOnce(&Transaction<'c>) -> Result<R>,
+    {
+        Transaction { conn, engine, depth: 0 }.scope(f)
+    }
+
+    /// Run `f` inside a savepoint on `conn`, which starts a transaction if there is none.
+    pub(crate) fn run_savepoint<F, R>(conn: &'c Connection, engine: &'c Engine<'c>, f: F) -> Result<R>
+        where
+            F: FnOnce(&Transaction<'c>) -> Result<R>,
+    {
+        Transaction { conn, engine, depth: 1 }.scope(f)
+    }
+
+    /// Run `f` inside a savepoint nested in this transaction. The savepoint is released if `f`
+    /// returns `Ok`, otherwise only the changes made by `f` are rolled back.
+    pub fn savepoint<F, R>(&self, f: F) -> Result<R>
+        where
+            F: FnOnce(&Transaction<'c>) -> Result<R>,
+    {
+        Transaction {
+            conn: self.conn,
+            engine: self.engine,
+            depth: self.depth + 1,
+        }
+        .scope(f)
+    }
+
+    /// Execute `template` for every item of `params`, committing every `chunk_size` items in a
+    /// transaction (or savepoint if `depth` is not `0`) of their own.
+    pub(crate) fn batch<S, P, I>(
+        conn: &'c Connection,
+        engine: &'c Engine<'c>,
+        depth: usize,
+        template: &S,
+        params: I,
+        chunk_size: usize,
+    ) -> Result<Vec<usize>>
+        where
+            S: SqlTemplate,
+            P: DynamicQueryParameters,
+            I: IntoIterator<Item = P>,
+    {
+        let mut items = params.into_iter().peekable();
+        let mut shapes = BatchShapes::default();
+        let mut counts = vec![];
+        while items.peek().is_some() {
+            Transaction { conn, engine, depth }.scope(|_| {
+                let chunk = items.by_ref().take(chunk_size.max(1));
+                engine.execute_batch(conn, template, chunk, &mut shapes, &mut counts)
+            })?;
+        }
+        Ok(counts)
+    }
+
+    /// The nesting level of this transaction, `0` for the top-level transaction.
+    pub fn depth(&self) -> usize {
+        self.depth
+    }
+
+    fn scope<F, R>(self, f: F) -> Result<R>
+        where
+            F: FnOnce(&Transaction<'c>) -> Result<R>,
+    {
+        self.conn.execute_batch(&self.begin_sql())?;
+        // The guard rolls back if `f` panics, because `finish` is never reached in that case.
+        let mut guard = Guard { tx: &self, finished: false };
+        // a failed commit, e.g. because the database is busy, leaves the transaction open
+        let result = f(&self).and_then(|v| {
+            self.conn.execute_batch(&self.commit_sql())?;
+            Ok(v)
+        });
+        guard.finished = true;
+        match result {
+            Ok(v) => Ok(v),
+            Err(err) => {
+                if let Err(rollback_err) = self.conn.execute_batch(&self.rollback_sql()) {
+                    log::warn!("failed to roll back transaction, the error is: {}", rollback_err);
+                }
+                Err(err)
+            }
+        }
+    }
+
+    fn begin_sql(&self) -> String {
+        match self.depth {
+            0 => "BEGIN DEFERRED".to_string(),
+            d => format!("SAVEPOINT sp_{}", d),
+        }
+    }
+
+    fn commit_sql(&self) -> String {
+        match self.depth {
+            0 => "COMMIT".to_string(),
+            d => format!("RELEASE sp_{}", d),
+        }
+    }
+
+    fn rollback_sql(&self) -> String {
+        match self.depth {
+            0 => "ROLLBACK".to_string(),
+            d => format!("ROLLBACK TO sp_{0}; RELEASE sp_{0}", d),
+        }
+    }
+}
+
+/// Rolls back the enclosing transaction when dropped before it is finished.
+struct Guard<'t, 'c> {
+    tx: &'t Transaction<'c>,
+    finished: bool,
+}
+
+impl<'t, 'c> Drop for Guard<'t, 'c> {
+    fn drop(&mut self) {
+        if !self.finished {
+            if let Err(err) = self.tx.conn.execute_batch(&self.tx.rollback_sql()) {
+                log::warn!("failed to roll back transaction, the error is: {}", err);
+            }
+        }
+    }
+}
+
+impl<'c> DynamicSqlExecutor for Transaction<'c> {
+    fn query<S, P, F, T>(&self, template: &S, params: P, f: F) -> Result<Vec<T>>
+        where
+            S: SqlTemplate,
+            P: DynamicQueryParameters,
+            F: FnMut(&Row<'_>) -> rusqlite::Result<T>,
+    {
+        self.engine.query(self.conn, template, params, f)
+    }
+
+    fn query_derived<S, P, D, F, T, C, R>(
+        &self,
+        template: &S,
+        params: P,
+        derive: D,
+        f: F,
+        consume: C,
+    ) -> Result<R>
+        where
+            S: SqlTemplate,
+            P: DynamicQueryParameters,
+            D: FnOnce(String) -> String,
+            F: FnMut(&Row<'_>) -> rusqlite::Result<T>,
+            C: FnOnce(QueryRows<'_, F>) -> Result<R>,
+    {
+        self.engine.query_derived(self.conn, template, params, derive, f, consume)
+    }
+
+    fn execute<S, P>(&self, template: &S, params: P) -> Result<usize>
+        where
+            S: SqlTemplate,
+            P: DynamicQueryParameters,
+    {
+        self.engine.execute(self.conn, template, params)
+    }
+
+    fn execute_returning_rowid<S, P>(&self, template: &S, params: P) -> Result<i64>
+        where
+            S: SqlTemplate,
+            P: DynamicQueryParameters,
+    {
+        self.engine.execute_returning_rowid(self.conn, template, params)
+    }
+
+    fn render<S, P>(&self, template: &S, params: P) -> Result<RenderedQuery>
+        where
+            S: SqlTemplate,
+            P: DynamicQueryParameters,
+    {
+        self.engine.render_query(template, params)
+    }
+
+    fn explain<S, P>(&self, template: &S, params: P) -> Result<QueryPlan>
+        where
+            S: SqlTemplate,
+            P: DynamicQueryParameters,
+    {
+        self.engine.explain(self.conn, template, params)
+    }
+
+    fn execute_returning_one<S, P, F, T>(&self, template: &S, params: P, f: F) -> Result<T>
+        where
+            S: SqlTemplate,
+            P: DynamicQueryParameters,
+            F: FnMut(&Row<'_>) -> rusqlite::Result<T>,
+    {
+        self.savepoint(|sp| sp.query_one(template, params, f))
+    }
+
+    /// Items are executed within savepoints of this transaction, so chunks are only committed
+    /// together with it.
+    fn execute_batch_chunked<S, P, I>(
+        &self,
+        template: &S,
+        params: I,
+        chunk_size: usize,
+    ) -> Result<Vec<usize>>
+        where
+            S: SqlTemplate,
+            P: DynamicQueryParameters,
+            I: IntoIterator<Item = P>,
+    {
+        Transaction::batch(self.conn, self.engine, self.depth + 1, template, params, chunk_size)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::panic::{catch_unwind, AssertUnwindSafe};
+
+    use crate::dynamic_sql::executor::dog::*;
+    use crate::dynamic_sql::RepositoryBuilder;
+    use crate::error::Error;
+
+    use super::*;
+
+    fn names(repo: &impl DynamicSqlExecutor) -> Vec<String> {
+        repo.query(&Q_DOGS_SELECT, DogQuery::default(), |row| row.get("name"))
+            .unwrap()
+    }
+
+    #[test]
+    fn test_commit_and_rollback() {
+        let mut repo = memory_repository();
+        repo.transaction(|tx| {
+            tx.execute(&Q_DOGS_INSERT, dog_insert("Jeff"))?;
+            tx.execute(&Q_DOGS_INSERT, dog_insert("Bob"))
+        })
+        .unwrap();
+        assert_eq!(vec!["Jeff", "Bob"], names(&repo));
+
+        let result = repo.transaction(|tx| {
+            tx.execute(&Q_DOGS_INSERT, dog_insert("Tom"))?;
+            // duplicate primary key
+            tx.execute(&Q_DOGS_INSERT, dog_insert("Jeff"))
+        });
+        assert!(matches!(result, Err(Error::DatabaseError(_))));
+        assert_eq!(vec!["Jeff", "Bob"], names(&repo));
+    }
+
+    #[test]
+    fn test_rollback_on_panic() {
+        let mut repo = memory_repository();
+        let result = catch_unwind(AssertUnwindSafe(|| {
+            repo.transaction(|tx| -> Result<()> {
+                tx.execute(&Q_DOGS_INSERT, dog_insert("Jeff"))?;
+                panic!("boom");
+            })
+        }));
+        assert!(result.is_err());
+        assert!(names(&repo).is_empty());
+    }
+
+    #[test]
+    fn test_rollback_on_failed_commit() {
+        let mut repo = RepositoryBuilder::memory()
+            .pragma("foreign_keys", true)
+            .init(
+                "CREATE TABLE owners(name TEXT PRIMARY KEY);\
+                CREATE TABLE pets(name TEXT, owner TEXT REFERENCES owners(name) DEFERRABLE INITIALLY DEFERRED);",
+            )
+            .build()
+            .unwrap();
+        let result = repo.transaction(|tx| {
+            // the foreign key is only checked by `COMMIT`
+            tx.conn.execute("INSERT INTO pets VALUES ('Jeff', 'Bob')", [])?;
+            Ok(())
+        });
+        assert!(matches!(result, Err(Error::DatabaseError(_))));
+        assert!(repo.conn.is_autocommit());
+        let pets: i64 = repo.conn.query_row("SELECT count(*) FROM pets", [], |row| row.get(0)).unwrap();
+        assert_eq!(0, pets);
+    }
+
+    #[test]
+    fn test_batch_in_transaction() {
+        let mut repo = memory_repository();
+        repo.transaction(|tx| {
+            let counts = tx.execute_batch(&Q_DOGS_INSERT, vec![dog_insert("Jeff"), dog_insert("Bob")])?;
+            assert_eq!(vec![1, 1], counts);
+            let result = tx.execute_batch(&Q_DOGS_INSERT, vec![dog_insert("Tom"), dog_insert("Bob")]);
+            assert!(matches!(result, Err(Error::BatchError { index: 1, .. })));
+            Ok(())
+        })
+        .unwrap();
+        assert_eq!(vec!["Jeff", "Bob"], names(&repo));
+    }
+
+    #[test]
+    fn test_savepoint() {
+        let mut repo = memory_repository();
+        repo.transaction(|tx| {
+            tx.execute(&Q_DOGS_INSERT, dog_insert("Jeff"))?;
+            let nested = tx.savepoint(|sp| {
+                assert_eq!(1, sp.depth());
+                sp.execute(&Q_DOGS_INSERT, dog_insert("Tom"))?;
+                sp.execute(&Q_DOGS_INSERT, dog_insert("Jeff"))
+            });
+            assert!(nested.is_err());
+            tx.savepoint(|sp| sp.savepoint(|sp| sp.execute(&Q_DOGS_INSERT, dog_insert("Bob"))))
+        })
+        .unwrap();
+        assert_eq!(vec!["Jeff", "Bob"], names(&repo));
+    }
+}
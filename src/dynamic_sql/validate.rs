@@ -0,0 +1,155 @@
+use std::collections::{BTreeSet, HashMap};
+use std::fmt::{self, Display, Formatter};
+
+use crate::dynamic_sql::loader::partial_references;
+use crate::error::Error;
+
+/// Templates with more `[:param]` conditions than this are validated with a sample of presence
+/// combinations instead of all of them.
+pub(crate) const MAX_CONDITIONS: usize = 8;
+
+/// A combination of present parameters for which a template could not be rendered or prepared,
+/// see [Repository::validate](crate::dynamic_sql::Repository::validate).
+#[derive(Debug)]
+pub struct ValidationFailure {
+    pub template: String,
+    /// The parameters that were present, all others were absent.
+    pub params: Vec<String>,
+    pub error: Error,
+}
+
+impl Display for ValidationFailure {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "template `{}` with [{}]: {}",
+            self.template,
+            self.params.join(", "),
+            self.error
+        )?;
+        let mut source = std::error::Error::source(&self.error);
+        while let Some(s) = source {
+            write!(f, ": {}", s)?;
+            source = s.source();
+        }
+        Ok(())
+    }
+}
+
+/// The parameters tested for presence with `[:param]` in `sql` and the partials it includes.
+pub(crate) fn conditions(sql: &str, partials: &HashMap<&str, &str>) -> BTreeSet<String> {
+    let mut found = BTreeSet::new();
+    let mut visited = vec![];
+    let mut pending = vec![sql];
+    while let Some(sql) = pending.pop() {
+        let mut rest = sql;
+        while let Some(start) = rest.find("[:") {
+            rest = &rest[start + 1..];
+            if let Some(end) = rest.find(']') {
+                found.insert(rest[..end].to_string());
+                rest = &rest[end..];
+            }
+        }
+        for name in partial_references(sql) {
+            if !visited.contains(&name) {
+                visited.push(name);
+                pending.extend(partials.get(name));
+            }
+        }
+    }
+    found
+}
+
+/// Presence combinations of `conditions` as bit masks: all of them if there are at most
+/// [MAX_CONDITIONS], otherwise none, all, each one alone and all but each one.
+pub(crate) fn combinations(conditions: usize) -> Vec<u64> {
+    if conditions <= MAX_CONDITIONS {
+        return (0..1u64 << conditions).collect();
+    }
+    let all = if conditions >= 64 { u64::MAX } else { (1u64 << conditions) - 1 };
+    let mut masks = vec![0, all];
+    for i in 0..conditions.min(64) {
+        masks.push(1 << i);
+        masks.push(all & !(1 << i));
+    }
+    masks
+}
+
+#[cfg(test)]
+mod test {
+    use crate::dynamic_sql::executor::dog::*;
+    use std::iter::FromIterator;
+
+    use rusqlite::ToSql;
+
+    use crate::dynamic_sql::{
+        DynamicParam, DynamicQueryParameters, DynamicSqlExecutor, RepositoryBuilder, ToSqlSegment,
+    };
+    use crate::new_query_type;
+
+    use super::*;
+
+    #[test]
+    fn test_conditions() {
+        let partials = vec![(Q_DOGS_WHERE.0, Q_DOGS_WHERE.1)].into_iter().collect();
+        let found = conditions(Q_DOGS_SELECT.1, &partials);
+        assert_eq!(
+            vec![":q_color", ":q_name", ":q_names", ":weight_lower", ":weight_upper"],
+            found.into_iter().collect::<Vec<_>>()
+        );
+        assert_eq!(16, combinations(4).len());
+        assert_eq!(2 + 2 * 10, combinations(10).len());
+    }
+
+    #[test]
+    fn test_validate() {
+        let repo = memory_repository();
+        match repo.validate() {
+            // an update without any column to set is invalid, but never used
+            Err(Error::TemplateValidationErrors(failures)) => {
+                assert_eq!(32, failures.len());
+                assert!(failures.iter().all(|f| f.template == "Q_DOGS_UPDATE"
+                    && !f.params.iter().any(|p| p == ":color" || p == ":weight")));
+            }
+            other => panic!("unexpected result: {:?}", other),
+        }
+
+        const Q_TYPO: (&str, &str) = (
+            "Q_TYPO",
+            "SELECT * FROM dogs{{#where}}\
+            {{#if [:q_name]}} AND name=:q_name{{/if}}\
+            {{#if [:q_color]}} AND colour=:q_color{{/if}}\
+            {{/where}}",
+        );
+        let repo = RepositoryBuilder::memory()
+            .templates(&[Q_DOGS_SELECT, Q_DOGS_WHERE, Q_TYPO])
+            .init(DDL)
+            .build()
+            .unwrap();
+        match repo.validate() {
+            Err(Error::TemplateValidationErrors(failures)) => {
+                assert_eq!(2, failures.len());
+                assert!(failures.iter().all(|f| f.template == "Q_TYPO"));
+                assert_eq!(vec![":q_color"], failures[0].params);
+                assert!(failures[0].to_string().contains("colour"), "{}", failures[0]);
+            }
+            other => panic!("unexpected result: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_validate_filters() {
+        new_query_type!((ColourFilter, 'q, %> colour: &'q str = colour eq,));
+
+        const Q_FILTERS: (&str, &str) = ("Q_FILTERS", "SELECT name FROM dogs{{filters}}");
+        let repo = RepositoryBuilder::memory()
+            .templates(&[Q_FILTERS])
+            .init(DDL)
+            .build()
+            .unwrap();
+        // the columns of filter fields are only known to the query type
+        repo.validate().unwrap();
+        let query = ColourFilter { colour: Some("white") };
+        assert!(repo.query(&Q_FILTERS, query, |row| row.get::<_, String>(0)).is_err());
+    }
+}
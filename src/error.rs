@@ -2,6 +2,7 @@ use thiserror::Error;
 
 pub type Result<T> = std::result::Result<T, Error>;
 
+/// Large payloads are boxed, so that [Result] stays small.
 #[derive(Error, Debug)]
 pub enum Error {
     #[cfg(feature = "dynamic_sql")]
@@ -12,11 +13,125 @@ pub enum Error {
     #[error("error converting from SQLite value")]
     FromSqlError(#[from] rusqlite::types::FromSqlError),
 
+    #[cfg(feature = "dynamic_sql")]
+    #[error("failed to map row {index}")]
+    RowMappingError {
+        index: usize,
+        #[source]
+        source: rusqlite::Error,
+    },
+
+    #[cfg(feature = "dynamic_sql")]
+    #[error("failed to map {} rows", .0.len())]
+    RowMappingErrors(Box<[crate::dynamic_sql::RowError]>),
+
+    #[cfg(feature = "dynamic_sql")]
+    #[error("query returned no rows")]
+    NoRows,
+
+    #[cfg(feature = "dynamic_sql")]
+    #[error("query returned more than one row")]
+    TooManyRows,
+
+    #[cfg(feature = "dynamic_sql")]
+    #[error("invalid page request: {0}")]
+    InvalidPageRequest(&'static str),
+
+    #[cfg(feature = "dynamic_sql")]
+    #[error("batch item {index} failed")]
+    BatchError {
+        index: usize,
+        #[source]
+        source: Box<Error>,
+    },
+
+    #[cfg(feature = "dynamic_sql")]
+    #[error("invalid repository configuration: {0}")]
+    InvalidConfiguration(String),
+
+    #[cfg(feature = "dynamic_sql")]
+    #[error("invalid migrations: {0}")]
+    InvalidMigrations(String),
+
+    #[cfg(feature = "dynamic_sql")]
+    #[error("checksum of applied migration {version} `{name}` has changed")]
+    MigrationChecksumMismatch { version: u32, name: String },
+
+    #[cfg(feature = "dynamic_sql")]
+    #[error("migration {version} failed")]
+    MigrationFailed {
+        version: u32,
+        #[source]
+        source: Box<Error>,
+    },
+
+    #[cfg(feature = "dynamic_sql")]
+    #[error("failed to load templates from {path}")]
+    TemplateLoadError {
+        path: std::path::PathBuf,
+        #[source]
+        source: std::io::Error,
+    },
+
+    #[cfg(feature = "dynamic_sql")]
+    #[error("duplicate template `{name}` in {first} and {second}")]
+    DuplicateTemplate {
+        name: String,
+        first: String,
+        second: String,
+    },
+
+    #[cfg(feature = "dynamic_sql")]
+    #[error("template `{template}` includes missing partial `{partial}`")]
+    MissingPartial { template: String, partial: String },
+
+    #[cfg(feature = "dynamic_sql")]
+    #[error("{} template combinations failed to validate", .0.len())]
+    TemplateValidationErrors(Box<[crate::dynamic_sql::ValidationFailure]>),
+
+    #[cfg(feature = "dynamic_sql")]
+    #[error("parameters {0} are defined by both a query type and a query type nested in it")]
+    DuplicateParams(String),
+
+    #[cfg(feature = "dynamic_sql")]
+    #[error("parameters {0} of query types nested with a prefix are not in their PARAMS")]
+    UnprefixedParams(String),
+
+    #[cfg(feature = "dynamic_sql")]
+    #[error("parameter {0} is both a parameter and a value of a list parameter")]
+    ListParamCollision(String),
+
+    #[cfg(feature = "dynamic_sql")]
+    #[error("rejected by interceptor: {0}")]
+    Rejected(String),
+
     #[cfg(feature = "dynamic_sql")]
     #[error("error while rendering template")]
-    TemplateRenderError(#[from] handlebars::RenderError),
+    TemplateRenderError(#[source] Box<handlebars::RenderError>),
 
     #[cfg(feature = "dynamic_sql")]
     #[error("error while registering template")]
-    TemplateError(#[from] handlebars::TemplateError),
+    TemplateError(#[source] Box<handlebars::TemplateError>),
+
+    #[cfg(feature = "pool")]
+    #[error("error while checking out a pooled connection")]
+    PoolError(#[from] r2d2::Error),
+
+    #[cfg(feature = "async")]
+    #[error("the SQL worker stopped before completing the request")]
+    WorkerStopped,
+}
+
+#[cfg(feature = "dynamic_sql")]
+impl From<handlebars::RenderError> for Error {
+    fn from(e: handlebars::RenderError) -> Self {
+        Error::TemplateRenderError(Box::new(e))
+    }
+}
+
+#[cfg(feature = "dynamic_sql")]
+impl From<handlebars::TemplateError> for Error {
+    fn from(e: handlebars::TemplateError) -> Self {
+        Error::TemplateError(Box::new(e))
+    }
 }
@@ -1,3 +1,6 @@
+// lets `::shunlib` in the code generated by `shunlib-derive` work in this crate as well
+extern crate self as shunlib;
+
 pub use error::{Error, Result};
 
 #[cfg(feature = "dynamic_sql")]